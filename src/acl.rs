@@ -0,0 +1,113 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// A block of addresses written as address/prefix, a bare address is a single host
+pub struct Cidr {
+    /// First address of the block
+    addr: IpAddr,
+    /// Number of leading bits that must match
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Parse a block such as 10.0.0.0/8, 2001:db8::/32 or 192.0.2.1
+    pub fn parse(text: &str) -> Option<Self> {
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (text.parse().ok()?, None),
+        };
+
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max);
+        if prefix > max {
+            return None;
+        }
+
+        Some(Cidr { addr, prefix })
+    }
+
+    /// The block of the given length the address lies in, the length capped to the address size
+    pub fn around(addr: IpAddr, prefix: u8) -> Self {
+        match addr {
+            IpAddr::V4(ip) => {
+                let prefix = prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                Cidr {
+                    addr: IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask)),
+                    prefix,
+                }
+            }
+            IpAddr::V6(ip) => {
+                let prefix = prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                Cidr {
+                    addr: IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask)),
+                    prefix,
+                }
+            }
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// If the address lies inside the block
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients reaching a dual stack socket show up as mapped IPv6 addresses
+        self.contains_exact(addr.to_canonical())
+    }
+
+    /// If the address lies inside the block, an IPv4-mapped address only inside IPv6 blocks
+    pub fn contains_exact(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// If the leading bits of both addresses are equal
+fn prefix_matches(net: &[u8], addr: &[u8], prefix: u8) -> bool {
+    let full_bytes = prefix as usize / 8;
+    let rest_bits = prefix % 8;
+
+    if net[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xff << (8 - rest_bits);
+    net[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+#[derive(Debug, Clone, Default)]
+/// Clients let through by their address or by the TSIG key they signed with
+pub struct Acl {
+    /// Address blocks allowed without a key
+    pub blocks: Vec<Cidr>,
+    /// Names of keys allowed from any address, lowercase
+    pub keys: Vec<String>,
+}
+
+impl Acl {
+    /// If the client may pass, key is the name of the TSIG key its message was verified with
+    pub fn allows(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        self.blocks.iter().any(|cidr| cidr.contains(addr))
+            || key.is_some_and(|key| self.keys.iter().any(|name| name == key))
+    }
+}
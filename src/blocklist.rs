@@ -0,0 +1,364 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        PoisonError, RwLock,
+    },
+    time::SystemTime,
+};
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{Errors::IOErr, Result},
+    header::ResponseCode::{Nxdomain, Refused},
+    question::{QueryType, Question},
+    record::{Record, RecordPreamble},
+    zone::labels,
+};
+
+const BLOCK_TTL: u32 = 300; // how long clients may cache the answer for a blocked name
+
+// hosts files map these to themselves, they are never blocked
+const IGNORED_HOSTS: [&str; 4] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How queries for a name on a blocklist are answered
+pub enum BlockAction {
+    /// The name does not exist
+    Nxdomain,
+    /// The name points at the unspecified address, 0.0.0.0 or ::
+    Null,
+    /// The server refuses to answer
+    Refused,
+}
+
+impl BlockAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nxdomain" => Some(Self::Nxdomain),
+            "null" => Some(Self::Null),
+            "refused" => Some(Self::Refused),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// A node of the suffix trie holding one label of a listed domain
+struct TrieNode {
+    /// Nodes one label further from the root, keyed by lowercase label
+    children: HashMap<String, TrieNode>,
+    /// List the domain ending at this node came from
+    list: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+/// Listed domains stored from the root down, so a domain matches every name below it too
+struct SuffixTrie {
+    root: TrieNode,
+}
+
+impl SuffixTrie {
+    /// Add a domain, the first list to name it keeps it
+    fn insert(&mut self, domain: &str, list: usize) {
+        let mut node = &mut self.root;
+        for label in labels(domain) {
+            node = node.children.entry(label).or_default();
+        }
+        node.list.get_or_insert(list);
+    }
+
+    /// List of the closest domain at or above the name
+    fn find(&self, name: &str) -> Option<usize> {
+        let mut node = &self.root;
+        let mut found = node.list;
+        for label in labels(name) {
+            match node.children.get(&label) {
+                Some(child) => node = child,
+                None => break,
+            }
+            found = node.list.or(found);
+        }
+
+        found
+    }
+}
+
+#[derive(Debug, Default)]
+/// Domains read from one list file
+struct ListEntries {
+    blocked: Vec<String>,
+    /// Exceptions written as `@@||domain^`
+    allowed: Vec<String>,
+}
+
+/// If the word looks like a domain name rather than a pattern
+fn is_domain(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_'))
+}
+
+/// Domain of an adblock rule of the form `domain^`, rules with paths or options are for browsers
+fn adblock_domain(rule: &str) -> Option<String> {
+    let domain = rule.strip_suffix('^')?;
+    is_domain(domain).then(|| String::from(domain))
+}
+
+/// Read a list in hosts format, one domain per line, or adblock `||domain^` syntax
+fn parse_list(text: &str) -> ListEntries {
+    let mut entries = ListEntries::default();
+
+    for line in text.lines() {
+        let line = line.trim();
+        // adblock lists use ! for comments and open with a [Adblock Plus] header
+        if line.starts_with(['!', '[']) {
+            continue;
+        }
+        if let Some(rule) = line.strip_prefix("@@||") {
+            entries.allowed.extend(adblock_domain(rule));
+            continue;
+        }
+        if let Some(rule) = line.strip_prefix("||") {
+            entries.blocked.extend(adblock_domain(rule));
+            continue;
+        }
+
+        let line = line.split('#').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [ip, hosts @ ..] if ip.parse::<IpAddr>().is_ok() => entries.blocked.extend(
+                hosts
+                    .iter()
+                    .filter(|host| {
+                        !IGNORED_HOSTS.contains(&host.to_ascii_lowercase().as_str())
+                            && host.parse::<IpAddr>().is_err()
+                    })
+                    .filter(|host| is_domain(host))
+                    .map(|host| String::from(*host)),
+            ),
+            [domain] if is_domain(domain) => entries.blocked.push(String::from(*domain)),
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+#[derive(Debug)]
+/// A blocklist file and how the names on it are answered
+pub struct BlockList {
+    pub path: PathBuf,
+    pub action: BlockAction,
+    /// Queries blocked by this list since the server started
+    pub blocked: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+/// Every list loaded into suffix tries
+struct Tries {
+    blocked: SuffixTrie,
+    allowed: SuffixTrie,
+    /// Modification time of each file when it was read, blocklists then allowlists
+    modified: Vec<Option<SystemTime>>,
+}
+
+#[derive(Debug, Default)]
+/// Blocks queries for names on the blocklists unless an allowlist names them
+pub struct Filter {
+    pub lists: Vec<BlockList>,
+    pub allowlists: Vec<PathBuf>,
+    tries: RwLock<Tries>,
+}
+
+/// Modification time of a file, None when it cannot be read
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl Filter {
+    /// Load the blocklists, each with its action, and the allowlists that override them
+    pub fn load(lists: &[(PathBuf, BlockAction)], allowlists: &[PathBuf]) -> Result<Self> {
+        let filter = Filter {
+            lists: lists
+                .iter()
+                .map(|(path, action)| BlockList {
+                    path: path.clone(),
+                    action: *action,
+                    blocked: AtomicU64::new(0),
+                })
+                .collect(),
+            allowlists: allowlists.to_vec(),
+            tries: RwLock::default(),
+        };
+        *filter.tries.write().unwrap_or_else(PoisonError::into_inner) = filter.build()?;
+
+        Ok(filter)
+    }
+
+    /// If there is anything to filter with
+    pub fn is_empty(&self) -> bool {
+        self.lists.is_empty() && self.allowlists.is_empty()
+    }
+
+    /// Read every list file into fresh tries
+    fn build(&self) -> Result<Tries> {
+        let mut tries = Tries::default();
+
+        for (pos, list) in self.lists.iter().enumerate() {
+            tries.modified.push(modified(&list.path));
+            let entries = parse_list(&fs::read_to_string(&list.path).map_err(IOErr)?);
+            for domain in &entries.blocked {
+                tries.blocked.insert(domain, pos);
+            }
+            for domain in &entries.allowed {
+                tries.allowed.insert(domain, pos);
+            }
+        }
+
+        // every entry of an allowlist allows, whichever syntax it is written in
+        for (pos, path) in self.allowlists.iter().enumerate() {
+            tries.modified.push(modified(path));
+            let entries = parse_list(&fs::read_to_string(path).map_err(IOErr)?);
+            for domain in entries.blocked.iter().chain(&entries.allowed) {
+                tries.allowed.insert(domain, pos);
+            }
+        }
+
+        Ok(tries)
+    }
+
+    /// Read the lists again if any file changed since it was loaded, keeping the old ones on error
+    pub fn reload(&self) -> Result<()> {
+        let current: Vec<Option<SystemTime>> = self
+            .lists
+            .iter()
+            .map(|list| &list.path)
+            .chain(&self.allowlists)
+            .map(|path| modified(path))
+            .collect();
+        if current
+            == self
+                .tries
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .modified
+        {
+            return Ok(());
+        }
+
+        let tries = self.build()?;
+        *self.tries.write().unwrap_or_else(PoisonError::into_inner) = tries;
+
+        Ok(())
+    }
+
+    /// How a query for the name is answered if it is blocked, counting it against its list
+    pub fn check(&self, name: &str) -> Option<BlockAction> {
+        let tries = self.tries.read().unwrap_or_else(PoisonError::into_inner);
+        if tries.allowed.find(name).is_some() {
+            return None;
+        }
+
+        let list = &self.lists[tries.blocked.find(name)?];
+        list.blocked.fetch_add(1, Ordering::Relaxed);
+        Some(list.action)
+    }
+}
+
+/// Answer a query for a blocked name the way its list asks for
+pub fn answer_blocked(action: BlockAction, que: &Question, res_packet: &mut DNSPacket) {
+    match action {
+        BlockAction::Nxdomain => res_packet.header.rcode = Nxdomain,
+        BlockAction::Refused => res_packet.header.rcode = Refused,
+        // other types get an empty answer, the name still exists
+        BlockAction::Null => match que.query_type {
+            QueryType::A => res_packet.answer_sec.push(Record::A {
+                preamble: RecordPreamble::new(&que.name, QueryType::A, BLOCK_TTL),
+                ip: Ipv4Addr::UNSPECIFIED,
+            }),
+            QueryType::Aaaa => res_packet.answer_sec.push(Record::Aaaa {
+                preamble: RecordPreamble::new(&que.name, QueryType::Aaaa, BLOCK_TTL),
+                ip: Ipv6Addr::UNSPECIFIED,
+            }),
+            _ => {}
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn lists_are_read_in_every_syntax() {
+        let text = "\
+[Adblock Plus 2.0]
+! comment
+127.0.0.1 localhost LOCALHOST
+0.0.0.0 ads.example.com tracker.example.net # trailing comment
+::1 ip6-localhost
+plain.example.org
+||adblock.example.com^
+||path.example.com/banner.gif
+||options.example.com^$third-party
+@@||good.example.com^
+";
+        let entries = parse_list(text);
+
+        assert_eq!(
+            entries.blocked,
+            [
+                "ads.example.com",
+                "tracker.example.net",
+                "ip6-localhost",
+                "plain.example.org",
+                "adblock.example.com",
+            ]
+        );
+        assert_eq!(entries.allowed, ["good.example.com"]);
+    }
+
+    #[test]
+    fn domain_matches_the_names_below_it() {
+        let mut trie = SuffixTrie::default();
+        trie.insert("example.com", 0);
+        trie.insert("deep.ads.example.net", 1);
+
+        assert_eq!(trie.find("example.com"), Some(0));
+        assert_eq!(trie.find("WWW.Example.com"), Some(0));
+        assert_eq!(trie.find("a.b.example.com"), Some(0));
+        assert_eq!(trie.find("badexample.com"), None);
+        assert_eq!(trie.find("com"), None);
+        assert_eq!(trie.find("x.deep.ads.example.net"), Some(1));
+        assert_eq!(trie.find("ads.example.net"), None);
+    }
+
+    #[test]
+    fn allowlist_overrides_blocklist() {
+        let dir = env::temp_dir().join(format!("blocklist-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (blocked, allowed) = (dir.join("blocked.txt"), dir.join("allowed.txt"));
+        fs::write(&blocked, "example.com\n").unwrap();
+        fs::write(&allowed, "www.example.com\n").unwrap();
+
+        let filter = Filter::load(&[(blocked, BlockAction::Null)], &[allowed]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(filter.check("ads.example.com"), Some(BlockAction::Null));
+        assert_eq!(filter.check("www.example.com"), None);
+        assert_eq!(filter.check("img.www.example.com"), None);
+        assert_eq!(filter.lists[0].blocked.load(Ordering::Relaxed), 1);
+    }
+}
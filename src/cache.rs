@@ -0,0 +1,647 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    acl::Cidr,
+    dns_packet::DNSPacket,
+    edns::ClientSubnet,
+    header::ResponseCode::{self, Noerror, Nxdomain},
+    question::QueryType,
+    record::Record,
+};
+
+pub const CACHE_SIZE: usize = 10_000; // responses kept unless configured otherwise
+const MAX_CACHE_TTL: u32 = 86_400; // nothing is kept longer than a day, whatever its TTL
+const PREFETCH_QUEUE_SIZE: usize = 100; // refreshes waiting at once, more are dropped
+
+#[derive(Debug, Clone)]
+/// Answers served past their TTL while upstreams fail to give fresh ones, RFC 8767
+pub struct ServeStale {
+    /// How long past its TTL a response is kept
+    pub window: Duration,
+    /// TTL of the records of a stale answer, so clients come back for a fresh one soon
+    pub ttl: u32,
+    /// How long a client waits for a fresh answer before it gets the stale one
+    pub client_timeout: Duration,
+    /// How long after a failed refresh the stale answer is given without trying again
+    pub failure_recheck: Duration,
+}
+
+impl Default for ServeStale {
+    fn default() -> Self {
+        // the values RFC 8767 section 5 suggests
+        ServeStale {
+            window: Duration::from_secs(86_400),
+            ttl: 30,
+            client_timeout: Duration::from_millis(1800),
+            failure_recheck: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// When popular answers are refreshed before they expire
+pub struct Prefetch {
+    /// Hits an answer needs before it is worth refreshing
+    pub min_hits: u64,
+    /// Share of its TTL, in percent, an answer has left when a hit triggers the refresh
+    pub threshold: u8,
+    /// Most refreshes per second, so they cannot crowd out lookups for clients
+    pub rate: u32,
+}
+
+impl Default for Prefetch {
+    fn default() -> Self {
+        Prefetch {
+            min_hits: 3,
+            threshold: 10,
+            rate: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// An answer to look up again before it expires
+pub struct PrefetchJob {
+    pub name: String,
+    pub query_type: QueryType,
+    /// Subnet of the client whose hit triggered the refresh
+    pub subnet: Option<ClientSubnet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What a lookup that found a stale answer is to do about a fresh one
+pub enum Refresh {
+    /// Look up a fresh answer, no other lookup is doing so
+    Start,
+    /// Another lookup is already after a fresh answer
+    InFlight,
+    /// The last refresh failed too recently to try again, RFC 8767 section 5
+    Recheck,
+}
+
+#[derive(Debug)]
+/// A response kept for reuse until its TTL runs out
+struct CacheEntry {
+    rcode: ResponseCode,
+    answer: Vec<Record>,
+    authority: Vec<Record>,
+    additional: Vec<Record>,
+    stored: Instant,
+    expires: Instant,
+    /// Clients the response was tailored to through EDNS Client Subnet, None for everyone
+    scope: Option<Cidr>,
+    /// Times the response was answered from the cache, half of them carried over when it is
+    /// replaced so an answer no longer asked for stops being refreshed
+    hits: u64,
+    /// A refresh is queued, so later hits do not queue another
+    prefetching: bool,
+    /// A lookup is after a fresh answer for the expired response, so no other starts one
+    refreshing: bool,
+    /// When the last refresh of the expired response failed
+    failed: Option<Instant>,
+}
+
+impl CacheEntry {
+    /// If the response may be given to the client, it was tailored to no one or to its subnet
+    fn is_for(&self, client: Option<IpAddr>) -> bool {
+        self.scope
+            .is_none_or(|scope| client.is_some_and(|client| scope.contains_exact(client)))
+    }
+
+    /// The kept response with the TTL of each record set by the given function
+    fn response(&self, ttl: impl Fn(u32) -> u32) -> DNSPacket {
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.rcode = self.rcode;
+        res_packet.answer_sec = self.answer.clone();
+        res_packet.authority_sec = self.authority.clone();
+        res_packet.additional_sec = self.additional.clone();
+        for rec in res_packet
+            .answer_sec
+            .iter_mut()
+            .chain(&mut res_packet.authority_sec)
+            .chain(&mut res_packet.additional_sec)
+        {
+            let preamble = rec.preamble_mut();
+            preamble.ttl = ttl(preamble.ttl);
+        }
+
+        res_packet
+    }
+}
+
+/// Seconds a response may be kept, None when it must not be
+///
+/// That is the smallest TTL of its records, for a negative answer capped by the SOA minimum,
+/// RFC 2308 section 5. Failures and answers without any records are not kept.
+fn response_ttl(res_packet: &DNSPacket) -> Option<u32> {
+    if !matches!(res_packet.header.rcode, Noerror | Nxdomain) {
+        return None;
+    }
+
+    let records = res_packet
+        .answer_sec
+        .iter()
+        .chain(&res_packet.authority_sec)
+        .chain(&res_packet.additional_sec);
+    let ttl = records
+        .filter(|rec| !matches!(rec, Record::Opt { .. }))
+        .map(|rec| match rec {
+            Record::Soa {
+                preamble, minimum, ..
+            } if res_packet.answer_sec.is_empty() => preamble.ttl.min(*minimum),
+            rec => rec.preamble().ttl,
+        })
+        .min()?;
+
+    Some(ttl.min(MAX_CACHE_TTL))
+}
+
+#[derive(Debug)]
+/// Responses from upstream by name and type, each kept once per client subnet it was tailored to
+pub struct Cache {
+    /// Most responses kept at once, 0 turns the cache off
+    pub capacity: usize,
+    /// Keep responses past their TTL to answer with when upstreams fail, if enabled
+    pub serve_stale: Option<ServeStale>,
+    /// Refresh popular responses before they expire, if enabled
+    pub prefetch: Option<Prefetch>,
+    entries: Mutex<HashMap<(String, u16), Vec<CacheEntry>>>,
+    /// While the cache is full of fresh responses, when the first of them expires, new ones are
+    /// turned away until then without looking through the cache again
+    full_until: Mutex<Option<Instant>>,
+    /// Refreshes queued by hits, the receiving end is worked off by a thread of its own
+    prefetch_queue: (SyncSender<PrefetchJob>, Mutex<Receiver<PrefetchJob>>),
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache::new(CACHE_SIZE, None, None)
+    }
+}
+
+impl Cache {
+    pub fn new(
+        capacity: usize,
+        serve_stale: Option<ServeStale>,
+        prefetch: Option<Prefetch>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(PREFETCH_QUEUE_SIZE);
+        Cache {
+            capacity,
+            serve_stale,
+            prefetch,
+            entries: Mutex::default(),
+            full_until: Mutex::default(),
+            prefetch_queue: (sender, Mutex::new(receiver)),
+        }
+    }
+
+    /// If the entry is still worth keeping, either fresh or within the stale window
+    fn keeps(&self, entry: &CacheEntry, now: Instant) -> bool {
+        let window = self
+            .serve_stale
+            .as_ref()
+            .map_or(Duration::ZERO, |serve_stale| serve_stale.window);
+        entry.expires + window > now
+    }
+
+    /// A response still fresh for the client of the subnet, TTLs counted down by the time it was
+    /// kept, and the clients it was tailored to
+    ///
+    /// A hit on a popular response close to its expiry queues a refresh.
+    pub fn get(
+        &self,
+        name: &str,
+        query_type: &QueryType,
+        subnet: Option<&ClientSubnet>,
+    ) -> Option<(DNSPacket, Option<Cidr>)> {
+        let key = (name.to_ascii_lowercase(), query_type.to_num());
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let client = subnet.map(|subnet| subnet.source.addr());
+
+        let entry = entries
+            .get_mut(&key)?
+            .iter_mut()
+            .find(|entry| entry.expires > now && entry.is_for(client))?;
+        entry.hits += 1;
+
+        if let Some(prefetch) = &self.prefetch {
+            let left = entry.expires.duration_since(now);
+            let ttl = entry.expires.duration_since(entry.stored);
+            if entry.hits >= prefetch.min_hits
+                && !entry.prefetching
+                && left.as_secs_f64() * 100.0 <= ttl.as_secs_f64() * prefetch.threshold as f64
+            {
+                // a full queue drops the refresh, a later hit may queue it again
+                let job = PrefetchJob {
+                    name: String::from(name),
+                    query_type: query_type.clone(),
+                    subnet: subnet.copied(),
+                };
+                entry.prefetching = self.prefetch_queue.0.try_send(job).is_ok();
+            }
+        }
+
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let res_packet = entry.response(|ttl| ttl.saturating_sub(elapsed));
+        Some((res_packet, entry.scope))
+    }
+
+    /// The next refresh queued by a hit, waiting until there is one
+    pub fn next_prefetch(&self) -> Option<PrefetchJob> {
+        let receiver = self
+            .prefetch_queue
+            .1
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        receiver.recv().ok()
+    }
+
+    /// Let a later hit queue the refresh of the response for the client again, the refresh
+    /// having failed to replace it
+    pub fn finish_prefetch(
+        &self,
+        name: &str,
+        query_type: &QueryType,
+        subnet: Option<&ClientSubnet>,
+    ) {
+        let key = (name.to_ascii_lowercase(), query_type.to_num());
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let client = subnet.map(|subnet| subnet.source.addr());
+
+        let kept = entries.get_mut(&key).into_iter().flatten();
+        for entry in kept.filter(|entry| entry.is_for(client)) {
+            entry.prefetching = false;
+        }
+    }
+
+    /// A response for the client that has expired but is still within the stale window, every
+    /// TTL set to the stale answer TTL, the clients it was tailored to and whether the caller is
+    /// to look up a fresh one
+    ///
+    /// Only one lookup at a time refreshes the response, it must report back through
+    /// `finish_refresh`.
+    pub fn get_stale(
+        &self,
+        name: &str,
+        query_type: &QueryType,
+        subnet: Option<&ClientSubnet>,
+    ) -> Option<(DNSPacket, Option<Cidr>, Refresh)> {
+        let serve_stale = self.serve_stale.as_ref()?;
+        let key = (name.to_ascii_lowercase(), query_type.to_num());
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let client = subnet.map(|subnet| subnet.source.addr());
+
+        let entry = entries
+            .get_mut(&key)?
+            .iter_mut()
+            .find(|entry| self.keeps(entry, now) && entry.is_for(client))?;
+
+        let refresh = if entry.refreshing {
+            Refresh::InFlight
+        } else if entry
+            .failed
+            .is_some_and(|failed| now.duration_since(failed) < serve_stale.failure_recheck)
+        {
+            Refresh::Recheck
+        } else {
+            entry.refreshing = true;
+            Refresh::Start
+        };
+
+        Some((entry.response(|_| serve_stale.ttl), entry.scope, refresh))
+    }
+
+    /// Let other lookups refresh the expired response for the client again, after a failed
+    /// refresh only once the failure recheck time has passed
+    ///
+    /// A successful refresh has usually replaced the response already.
+    pub fn finish_refresh(
+        &self,
+        name: &str,
+        query_type: &QueryType,
+        subnet: Option<&ClientSubnet>,
+        failed: bool,
+    ) {
+        let key = (name.to_ascii_lowercase(), query_type.to_num());
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let client = subnet.map(|subnet| subnet.source.addr());
+
+        let entry = entries.get_mut(&key).and_then(|kept| {
+            kept.iter_mut()
+                .find(|entry| entry.refreshing && entry.is_for(client))
+        });
+        if let Some(entry) = entry {
+            entry.refreshing = false;
+            entry.failed = failed.then(Instant::now);
+        }
+    }
+
+    /// Keep the response for the clients of the scope, or for everyone
+    pub fn insert(
+        &self,
+        name: &str,
+        query_type: &QueryType,
+        res_packet: &DNSPacket,
+        scope: Option<Cidr>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Some(ttl) = response_ttl(res_packet) else {
+            return;
+        };
+
+        let key = (name.to_ascii_lowercase(), query_type.to_num());
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut full_until = self
+            .full_until
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl as u64);
+        // a response already kept is replaced in place, it needs no room
+        if !entries.contains_key(&key) && entries.len() >= self.capacity {
+            if full_until.is_some_and(|until| until > now) {
+                return;
+            }
+            entries.retain(|_, kept| {
+                kept.retain(|entry| self.keeps(entry, now));
+                !kept.is_empty()
+            });
+            // a fresh response is worth more than one only kept for when upstreams fail
+            if entries.len() >= self.capacity {
+                entries.retain(|_, kept| {
+                    kept.retain(|entry| entry.expires > now);
+                    !kept.is_empty()
+                });
+            }
+            // still full of fresh responses, let this one go rather than evict them
+            if entries.len() >= self.capacity {
+                *full_until = entries.values().flatten().map(|entry| entry.expires).min();
+                return;
+            }
+            *full_until = None;
+        }
+        // a shorter lived replacement may expire first
+        if let Some(until) = full_until.as_mut() {
+            *until = expires.min(*until);
+        }
+
+        let kept = entries.entry(key).or_default();
+        let hits = kept
+            .iter()
+            .find(|entry| entry.scope == scope)
+            .map_or(0, |entry| entry.hits / 2);
+        kept.retain(|entry| entry.scope != scope && self.keeps(entry, now));
+        kept.push(CacheEntry {
+            rcode: res_packet.header.rcode,
+            answer: res_packet.answer_sec.clone(),
+            authority: res_packet.authority_sec.clone(),
+            additional: res_packet.additional_sec.clone(),
+            stored: now,
+            expires,
+            scope,
+            hits,
+            prefetching: false,
+            refreshing: false,
+            failed: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::zone_file::parse_record;
+
+    /// A response with one A record of the given TTL
+    fn response(name: &str, ttl: u32) -> DNSPacket {
+        let mut res_packet = DNSPacket::new();
+        res_packet.answer_sec =
+            vec![parse_record(&format!("{}. {} IN A 192.0.2.1", name, ttl), 3600).unwrap()];
+        res_packet
+    }
+
+    fn serve_stale_cache(capacity: usize) -> Cache {
+        Cache::new(capacity, Some(ServeStale::default()), None)
+    }
+
+    fn refresh(cache: &Cache, name: &str) -> Option<Refresh> {
+        cache
+            .get_stale(name, &QueryType::A, None)
+            .map(|(_, _, refresh)| refresh)
+    }
+
+    #[test]
+    fn one_lookup_at_a_time_refreshes() {
+        let cache = serve_stale_cache(10);
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 0),
+            None,
+        );
+
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::Start));
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::InFlight));
+
+        cache.finish_refresh("example.com", &QueryType::A, None, false);
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::Start));
+    }
+
+    #[test]
+    fn failed_refresh_is_not_retried_until_the_recheck() {
+        let mut cache = serve_stale_cache(10);
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 0),
+            None,
+        );
+
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::Start));
+        cache.finish_refresh("example.com", &QueryType::A, None, true);
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::Recheck));
+
+        cache.serve_stale.as_mut().unwrap().failure_recheck = Duration::ZERO;
+        assert_eq!(refresh(&cache, "example.com"), Some(Refresh::Start));
+    }
+
+    #[test]
+    fn failed_prefetch_is_queued_again() {
+        let prefetch = Prefetch {
+            min_hits: 1,
+            threshold: 100,
+            rate: 10,
+        };
+        let cache = Cache::new(10, None, Some(prefetch));
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 3600),
+            None,
+        );
+
+        cache.get("example.com", &QueryType::A, None);
+        cache.get("example.com", &QueryType::A, None);
+        assert!(cache.next_prefetch().is_some());
+        cache.finish_prefetch("example.com", &QueryType::A, None);
+
+        cache.get("example.com", &QueryType::A, None);
+        let job = cache.next_prefetch().unwrap();
+        assert_eq!(job.name, "example.com");
+    }
+
+    #[test]
+    fn hits_decay_as_the_response_is_replaced() {
+        let cache = Cache::new(10, None, Some(Prefetch::default()));
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 3600),
+            None,
+        );
+        let hits = |cache: &Cache| {
+            let entries = cache.entries.lock().unwrap();
+            entries[&(String::from("example.com"), QueryType::A.to_num())][0].hits
+        };
+
+        for _ in 0..8 {
+            cache.get("example.com", &QueryType::A, None);
+        }
+        assert_eq!(hits(&cache), 8);
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 3600),
+            None,
+        );
+        assert_eq!(hits(&cache), 4);
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 3600),
+            None,
+        );
+        assert_eq!(hits(&cache), 2);
+    }
+
+    #[test]
+    fn full_cache_evicts_stale_for_fresh() {
+        let cache = serve_stale_cache(2);
+        cache.insert("a.example", &QueryType::A, &response("a.example", 0), None);
+        cache.insert(
+            "b.example",
+            &QueryType::A,
+            &response("b.example", 3600),
+            None,
+        );
+
+        cache.insert(
+            "c.example",
+            &QueryType::A,
+            &response("c.example", 3600),
+            None,
+        );
+        assert!(cache.get("c.example", &QueryType::A, None).is_some());
+        assert!(cache.get_stale("a.example", &QueryType::A, None).is_none());
+
+        // fresh responses are kept over a new one
+        cache.insert(
+            "d.example",
+            &QueryType::A,
+            &response("d.example", 3600),
+            None,
+        );
+        assert!(cache.get("d.example", &QueryType::A, None).is_none());
+        assert!(cache.get("b.example", &QueryType::A, None).is_some());
+    }
+
+    #[test]
+    fn tailored_response_is_only_served_to_its_scope() {
+        let cache = Cache::default();
+        let subnet = |block: &str| ClientSubnet {
+            source: Cidr::parse(block).unwrap(),
+            scope_prefix: 0,
+        };
+        let (inside, outside) = (subnet("192.0.2.0/24"), subnet("198.51.100.0/24"));
+
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 3600),
+            Cidr::parse("192.0.2.0/24"),
+        );
+        assert!(cache
+            .get("example.com", &QueryType::A, Some(&inside))
+            .is_some());
+        assert!(cache
+            .get("example.com", &QueryType::A, Some(&outside))
+            .is_none());
+        assert!(cache.get("example.com", &QueryType::A, None).is_none());
+
+        // each scope keeps its own answer
+        cache.insert(
+            "example.com",
+            &QueryType::A,
+            &response("example.com", 60),
+            Cidr::parse("198.51.100.0/24"),
+        );
+        let ttl = |subnet: &ClientSubnet| {
+            let (res_packet, scope) = cache
+                .get("example.com", &QueryType::A, Some(subnet))
+                .unwrap();
+            (res_packet.answer_sec[0].preamble().ttl, scope)
+        };
+        assert_eq!(ttl(&inside), (3600, Cidr::parse("192.0.2.0/24")));
+        assert_eq!(ttl(&outside), (60, Cidr::parse("198.51.100.0/24")));
+    }
+
+    #[test]
+    fn full_cache_still_replaces_kept_responses() {
+        let cache = Cache::new(2, None, None);
+        for name in ["a.example", "b.example"] {
+            cache.insert(name, &QueryType::A, &response(name, 3600), None);
+        }
+        let ttl = |name: &str| {
+            let (res_packet, _) = cache.get(name, &QueryType::A, None)?;
+            Some(res_packet.answer_sec[0].preamble().ttl)
+        };
+
+        cache.insert(
+            "c.example",
+            &QueryType::A,
+            &response("c.example", 3600),
+            None,
+        );
+        assert_eq!(ttl("c.example"), None);
+
+        // a refresh of a kept response takes its place
+        cache.insert("a.example", &QueryType::A, &response("a.example", 1), None);
+        assert_eq!(ttl("a.example"), Some(1));
+
+        // once it expires there is room again
+        thread::sleep(Duration::from_millis(1100));
+        cache.insert(
+            "c.example",
+            &QueryType::A,
+            &response("c.example", 3600),
+            None,
+        );
+        assert_eq!(ttl("c.example"), Some(3600));
+        assert_eq!(ttl("a.example"), None);
+    }
+}
@@ -0,0 +1,932 @@
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use super::{
+    acl::{Acl, Cidr},
+    blocklist::BlockAction,
+    cache::{Prefetch, ServeStale},
+    cookie::{CookieConfig, COOKIE_SECRET_SIZE},
+    dns64::Dns64,
+    doh::HTTPS_PORT,
+    doq::QUIC_PORT,
+    edns::ClientSubnetConfig,
+    errors::{
+        Errors::{Config as ConfigErr, IOErr},
+        Result,
+    },
+    local::LOCAL_TTL,
+    record::Record,
+    tls::TLS_PORT,
+    zone_file::parse_record,
+};
+
+const DNS_PORT: u16 = 53; // port used when an address is given without one
+const BUILTIN_ACLS: [&str; 3] = ["any", "none", "localhost"];
+
+#[derive(Debug)]
+/// Where the data of a zone comes from
+pub enum ZoneData {
+    /// Master file holding the zone data
+    File(PathBuf),
+    /// Primary server the zone is transferred from, making this server a secondary, and the
+    /// TSIG key the transfers are signed with
+    Transfer(SocketAddr, Option<String>),
+}
+
+#[derive(Debug)]
+/// A zone this server answers authoritatively for
+pub struct ZoneConfig {
+    /// Domain name at the apex of the zone
+    pub origin: String,
+    /// Where the zone data comes from
+    pub data: ZoneData,
+    /// Clients allowed to transfer the zone with AXFR or IXFR
+    pub allow_transfer: Acl,
+    /// Clients allowed to change the zone with dynamic updates
+    pub allow_update: Acl,
+}
+
+#[derive(Debug)]
+/// Resolver lookups are forwarded to
+pub enum UpstreamConfig {
+    /// Plain DNS over UDP, signed with the named TSIG key if given
+    Udp(SocketAddr, Option<String>),
+    /// Plain DNS over TCP, signed with the named TSIG key if given
+    Tcp(SocketAddr, Option<String>),
+    /// DNS over TLS, the certificate checked against the name unless public key pins are given
+    Tls {
+        server: SocketAddr,
+        name: String,
+        /// SHA-256 digests of accepted SubjectPublicKeyInfo structures
+        pins: Vec<Vec<u8>>,
+    },
+    /// DNS over HTTPS, posting to the URL, whose host the certificate is checked against unless
+    /// public key pins are given
+    Https {
+        server: SocketAddr,
+        url: String,
+        /// SHA-256 digests of accepted SubjectPublicKeyInfo structures
+        pins: Vec<Vec<u8>>,
+    },
+    /// DNS over QUIC, the certificate checked against the name unless public key pins are given
+    Quic {
+        server: SocketAddr,
+        name: String,
+        /// SHA-256 digests of accepted SubjectPublicKeyInfo structures
+        pins: Vec<Vec<u8>>,
+    },
+}
+
+impl UpstreamConfig {
+    /// If responses cannot be forged on the way, the upstream is reached over an encrypted
+    /// transport or signs them with TSIG
+    fn is_authenticated(&self) -> bool {
+        match self {
+            Self::Udp(_, key) | Self::Tcp(_, key) => key.is_some(),
+            Self::Tls { .. } | Self::Https { .. } | Self::Quic { .. } => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Names under a domain sent to their own upstream
+pub struct ForwardConfig {
+    pub domain: String,
+    pub upstream: UpstreamConfig,
+    /// Ask the upstream to recurse, or follow its referrals ourselves
+    pub recursive: bool,
+}
+
+#[derive(Debug, Clone)]
+/// Response Rate Limiting of UDP responses, off unless a rate is given
+pub struct RateLimitConfig {
+    /// Answers per second to one block of clients for one name, 0 for no limit
+    pub responses_per_second: u32,
+    /// NXDOMAIN responses per second to one block of clients, the answer rate if not given
+    pub nxdomains_per_second: Option<u32>,
+    /// Other error responses per second to one block of clients, the answer rate if not given
+    pub errors_per_second: Option<u32>,
+    /// Every slip-th limited response is sent truncated rather than dropped, 0 to drop them all
+    pub slip: u32,
+    /// Seconds over which responses are averaged, a flood is forgiven after this long
+    pub window: u32,
+    /// Length of the IPv4 blocks clients are counted in
+    pub ipv4_prefix_length: u8,
+    /// Length of the IPv6 blocks clients are counted in
+    pub ipv6_prefix_length: u8,
+    /// Only log which clients would be limited
+    pub log_only: bool,
+    /// Clients never limited
+    pub exempt: Vec<Cidr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            responses_per_second: 0,
+            nxdomains_per_second: None,
+            errors_per_second: None,
+            slip: 2,
+            window: 15,
+            ipv4_prefix_length: 24,
+            ipv6_prefix_length: 56,
+            log_only: false,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Zones, local records and forwarding rules answered to one set of clients
+pub struct ViewConfig {
+    pub name: String,
+    /// Address blocks of the clients the view is chosen for
+    pub clients: Vec<Cidr>,
+    /// Zones loaded from master files or transferred from a primary
+    pub zones: Vec<ZoneConfig>,
+    /// Rules sending names under a domain to their own upstream
+    pub forwards: Vec<ForwardConfig>,
+    /// Static records answered instead of forwarding
+    pub local_records: Vec<Record>,
+    /// Static records that also answer for every name below their owner
+    pub local_subtrees: Vec<Record>,
+}
+
+impl ViewConfig {
+    /// Apply a directive that can be given inside a view, the line it was split from is kept for
+    /// record data
+    fn apply(&mut self, dir: &Path, words: &[&str], line: &str) -> std::result::Result<(), String> {
+        match words {
+            ["zone", origin, file] => self.zones.push(ZoneConfig {
+                origin: String::from(*origin),
+                data: ZoneData::File(dir.join(file)),
+                allow_transfer: Acl::default(),
+                allow_update: Acl::default(),
+            }),
+
+            ["forward", domain, server, rest @ ..] => {
+                let (recursive, rest) = match rest {
+                    [rest @ .., "iterative"] => (false, rest),
+                    _ => (true, rest),
+                };
+                // plain DNS can be signed instead, a trusted upstream is then told apart from
+                // anyone spoofing it
+                let (key, transport) = match rest {
+                    [transport @ .., "key", key] => (Some(String::from(*key)), transport),
+                    _ => (None, rest),
+                };
+                let upstream = match (transport, key) {
+                    ([] | ["udp"], key) => {
+                        UpstreamConfig::Udp(parse_socket_addr(server, DNS_PORT)?, key)
+                    }
+                    (["tcp"], key) => {
+                        UpstreamConfig::Tcp(parse_socket_addr(server, DNS_PORT)?, key)
+                    }
+                    (["tls", name, pins @ ..], None) => UpstreamConfig::Tls {
+                        server: parse_socket_addr(server, TLS_PORT)?,
+                        name: String::from(*name),
+                        pins: parse_pins(pins)?,
+                    },
+                    _ => return Err(format!("invalid transport for forward {}", domain)),
+                };
+                self.forwards.push(ForwardConfig {
+                    domain: String::from(*domain),
+                    upstream,
+                    recursive,
+                });
+            }
+
+            // records are written as in a master file, names are taken as absolute
+            ["local", ..] => {
+                self.local_records
+                    .push(parse_record(after_directive(line), LOCAL_TTL)?);
+            }
+
+            ["local-subtree", ..] => {
+                self.local_subtrees
+                    .push(parse_record(after_directive(line), LOCAL_TTL)?);
+            }
+
+            [directive, ..] => return Err(format!("{} cannot be given inside a view", directive)),
+            [] => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+/// Settings read from the configuration file
+///
+/// The file holds one directive per line, `#` starts a comment:
+///
+/// ```text
+/// randomize-case yes
+/// keyring keys.conf
+/// acl trusted 192.0.2.0/24 2001:db8::/32 key admin-key
+/// allow-query any
+/// allow-recursion localhost trusted
+/// zone example.com zones/example.com.zone
+/// allow-transfer example.com trusted 198.51.100.53 key transfer-key
+/// allow-update example.com 192.0.2.10 key update-key
+/// secondary example.net 192.0.2.53 transfer-key
+/// tls-certificate certs/server.pem
+/// tls-key certs/server.key
+/// doh yes
+/// doq yes
+/// upstream-https 9.9.9.9 https://dns.quad9.net/dns-query
+/// forward corp.example 10.0.0.53
+/// forward consul 127.0.0.1:8600 tcp iterative
+/// forward . 198.41.0.4 iterative
+/// forward internal.example 10.0.0.54 tls dns.internal.example
+/// forward lab.example 10.0.0.55 key forward-key
+/// blocklist lists/ads.txt null
+/// allowlist lists/allow.txt
+/// local api.dev.example.com A 10.0.0.5
+/// local-subtree staging.example.com 60 CNAME api.dev.example.com.
+/// local-ptr yes
+/// cache-size 50000
+/// serve-stale yes
+/// serve-stale-window 86400
+/// serve-stale-ttl 30
+/// serve-stale-client-timeout 1800
+/// serve-stale-recheck 30
+/// prefetch yes
+/// prefetch-min-hits 3
+/// prefetch-threshold 10
+/// prefetch-rate 10
+/// client-subnet yes
+/// client-subnet-prefix-length 24 48
+/// dns64 yes
+/// dns64-prefix 64:ff9b::/96
+/// dns64-exclude ::ffff:0:0/96 10.0.0.0/8
+/// cookies yes
+/// cookie-secret 5elz5aaypD9I59yEnje/zw==
+/// require-cookies-above 512
+/// rate-limit responses-per-second 10
+/// rate-limit nxdomains-per-second 5
+/// rate-limit exempt-clients 192.0.2.0/24
+/// view internal 10.0.0.0/8 192.168.0.0/16
+/// zone example.com zones/internal/example.com.zone
+/// allow-transfer example.com 10.0.0.2
+/// local intranet.example.com A 10.0.0.80
+/// forward corp.example 10.0.0.53
+/// end
+/// ```
+///
+/// Zones, local records and forwarding rules given between `view` and `end` are only answered to
+/// the clients listed, everything given outside of a view makes up the default view for all
+/// other clients. Views are tried in order.
+///
+/// An iterative rule starts at the server it names and follows the referrals from there, so
+/// resolving from the root takes a rule for `.` naming a root server, as above.
+pub struct Config {
+    /// Randomize the case of outgoing query names
+    pub randomize_case: bool,
+    /// Zones, local records and forwarding rules for clients outside of every view
+    pub default_view: ViewConfig,
+    /// Views chosen by client address, each with its own zones and rules
+    pub views: Vec<ViewConfig>,
+    /// File holding the TSIG keys
+    pub keyring: Option<PathBuf>,
+    /// Certificate chain presented by the DNS over TLS listener, which only runs when set
+    pub tls_certificate: Option<PathBuf>,
+    /// Private key of the certificate
+    pub tls_key: Option<PathBuf>,
+    /// Also serve DNS over HTTPS with the same certificate
+    pub doh: bool,
+    /// Also serve DNS over QUIC with the same certificate
+    pub doq: bool,
+    /// Resolver lookups are forwarded to instead of the default
+    pub upstream: Option<UpstreamConfig>,
+    /// CA certificates trusted for the upstream instead of the bundled roots
+    pub upstream_ca: Option<PathBuf>,
+    /// Lists of names to block and how queries for them are answered
+    pub blocklists: Vec<(PathBuf, BlockAction)>,
+    /// Lists of names never blocked
+    pub allowlists: Vec<PathBuf>,
+    /// Add a PTR record for each local A record
+    pub local_ptr: bool,
+    /// Limits on UDP responses to each block of clients
+    pub rate_limit: RateLimitConfig,
+    /// Synthesize AAAA records for IPv6-only clients
+    pub dns64: Option<Dns64>,
+    /// Most responses cached by each view, the default size if not given
+    pub cache_size: Option<usize>,
+    /// Answer from expired cache entries when upstreams fail
+    pub serve_stale: Option<ServeStale>,
+    /// Refresh popular cache entries before they expire
+    pub prefetch: Option<Prefetch>,
+    /// Answer from NSEC and NSEC3 records the upstream validated instead of asking it again,
+    /// every upstream has to be authenticated
+    pub aggressive_nsec: bool,
+    /// Send the subnet of the client upstream with EDNS Client Subnet
+    pub client_subnet: Option<ClientSubnetConfig>,
+    /// Send DNS cookies upstream and hand them out to clients
+    pub cookies: Option<CookieConfig>,
+    /// Named acls that later address lists can refer to
+    acls: HashMap<String, Acl>,
+    /// Clients that may query at all, everyone if not given
+    pub allow_query: Option<Acl>,
+    /// Clients that may have names outside of our zones resolved for them, everyone if not given
+    pub allow_recursion: Option<Acl>,
+    /// Directives go to the last view until its end
+    in_view: bool,
+}
+
+impl Config {
+    /// Read the configuration file, relative paths are taken from its directory
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(IOErr)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+
+        let mut config = Config::default();
+        for (num, line) in text.lines().enumerate() {
+            let line = strip_comment(line);
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            config
+                .apply(dir, &words, line)
+                .map_err(|msg| ConfigErr(format!("{}:{}: {}", path.display(), num + 1, msg)))?;
+        }
+
+        if config.in_view {
+            return Err(ConfigErr(format!("{}: view has no end", path.display())));
+        }
+        config
+            .check_aggressive_nsec()
+            .map_err(|msg| ConfigErr(format!("{}: {}", path.display(), msg)))?;
+
+        Ok(config)
+    }
+
+    /// Refuse aggressive NSEC unless the AD bit of every upstream can be trusted, a forged one
+    /// would let anyone on the path deny names for as long as the records last
+    fn check_aggressive_nsec(&self) -> std::result::Result<(), String> {
+        if !self.aggressive_nsec {
+            return Ok(());
+        }
+        if !self
+            .upstream
+            .as_ref()
+            .is_some_and(UpstreamConfig::is_authenticated)
+        {
+            return Err(String::from(
+                "aggressive-nsec needs an upstream reached over TLS, HTTPS or QUIC, or with a key",
+            ));
+        }
+
+        let mut forwards = self
+            .default_view
+            .forwards
+            .iter()
+            .chain(self.views.iter().flat_map(|view| &view.forwards));
+        match forwards.find(|forward| !forward.recursive || !forward.upstream.is_authenticated()) {
+            // referrals lead to name servers reached over plain DNS
+            Some(forward) if !forward.recursive => Err(format!(
+                "aggressive-nsec cannot be used with iterative forward {}",
+                forward.domain
+            )),
+            Some(forward) => Err(format!(
+                "aggressive-nsec needs forward {} over TLS or with a key",
+                forward.domain
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply a single directive, the line it was split from is kept for record data
+    fn apply(&mut self, dir: &Path, words: &[&str], line: &str) -> std::result::Result<(), String> {
+        // everything up to the end of a view belongs to it, access to its zones is read below
+        if let (true, Some(view)) = (self.in_view, self.views.last_mut()) {
+            match words {
+                ["end"] => {
+                    self.in_view = false;
+                    return Ok(());
+                }
+                ["allow-transfer", ..] | ["allow-update", ..] => {}
+                _ => return view.apply(dir, words, line),
+            }
+        }
+
+        match words {
+            ["randomize-case", value] => self.randomize_case = parse_bool(value)?,
+
+            ["zone", ..] | ["forward", ..] | ["local", ..] | ["local-subtree", ..] => {
+                self.default_view.apply(dir, words, line)?;
+            }
+
+            ["view", name, clients @ ..] if !clients.is_empty() => {
+                if self.views.iter().any(|view| view.name == *name) {
+                    return Err(format!("view {} is declared twice", name));
+                }
+                let clients = clients
+                    .iter()
+                    .map(|word| {
+                        Cidr::parse(word).ok_or_else(|| format!("invalid address block {}", word))
+                    })
+                    .collect::<std::result::Result<_, _>>()?;
+                self.views.push(ViewConfig {
+                    name: String::from(*name),
+                    clients,
+                    ..ViewConfig::default()
+                });
+                self.in_view = true;
+            }
+
+            ["secondary", origin, primary, key @ ..] if key.len() <= 1 => {
+                self.default_view.zones.push(ZoneConfig {
+                    origin: String::from(*origin),
+                    data: ZoneData::Transfer(
+                        parse_socket_addr(primary, DNS_PORT)?,
+                        key.first().map(|key| String::from(*key)),
+                    ),
+                    allow_transfer: Acl::default(),
+                    allow_update: Acl::default(),
+                })
+            }
+
+            ["keyring", file] => self.keyring = Some(dir.join(file)),
+
+            ["tls-certificate", file] => self.tls_certificate = Some(dir.join(file)),
+
+            ["tls-key", file] => self.tls_key = Some(dir.join(file)),
+
+            ["doh", value] => self.doh = parse_bool(value)?,
+
+            ["doq", value] => self.doq = parse_bool(value)?,
+
+            ["upstream", server, key @ ..] if matches!(key, [] | ["key", _]) => {
+                let key = key.get(1).map(|key| String::from(*key));
+                self.upstream = Some(UpstreamConfig::Udp(
+                    parse_socket_addr(server, DNS_PORT)?,
+                    key,
+                ));
+            }
+
+            ["upstream-tls", server, name, pins @ ..] => {
+                self.upstream = Some(UpstreamConfig::Tls {
+                    server: parse_socket_addr(server, TLS_PORT)?,
+                    name: String::from(*name),
+                    pins: parse_pins(pins)?,
+                });
+            }
+
+            ["upstream-https", server, url, pins @ ..] => {
+                self.upstream = Some(UpstreamConfig::Https {
+                    server: parse_socket_addr(server, HTTPS_PORT)?,
+                    url: String::from(*url),
+                    pins: parse_pins(pins)?,
+                });
+            }
+
+            ["upstream-quic", server, name, pins @ ..] => {
+                self.upstream = Some(UpstreamConfig::Quic {
+                    server: parse_socket_addr(server, QUIC_PORT)?,
+                    name: String::from(*name),
+                    pins: parse_pins(pins)?,
+                });
+            }
+
+            ["upstream-ca", file] => self.upstream_ca = Some(dir.join(file)),
+
+            ["blocklist", file, action @ ..] if action.len() <= 1 => {
+                let action = match action.first() {
+                    Some(name) => BlockAction::from_name(name)
+                        .ok_or_else(|| format!("invalid block action {}", name))?,
+                    None => BlockAction::Nxdomain,
+                };
+                self.blocklists.push((dir.join(file), action));
+            }
+
+            ["allowlist", file] => self.allowlists.push(dir.join(file)),
+
+            ["local-ptr", value] => self.local_ptr = parse_bool(value)?,
+
+            ["cache-size", value] => self.cache_size = Some(parse_number(value)?),
+
+            ["serve-stale", value] => match parse_bool(value)? {
+                true => {
+                    self.serve_stale.get_or_insert_with(ServeStale::default);
+                }
+                false => self.serve_stale = None,
+            },
+
+            // how long past their TTL answers are kept, in seconds
+            ["serve-stale-window", seconds] => {
+                self.serve_stale
+                    .get_or_insert_with(ServeStale::default)
+                    .window = Duration::from_secs(parse_number(seconds)?);
+            }
+
+            ["serve-stale-ttl", seconds] => {
+                self.serve_stale.get_or_insert_with(ServeStale::default).ttl =
+                    parse_number(seconds)?;
+            }
+
+            // how long a client waits for a fresh answer, in milliseconds
+            ["serve-stale-client-timeout", millis] => {
+                self.serve_stale
+                    .get_or_insert_with(ServeStale::default)
+                    .client_timeout = Duration::from_millis(parse_number(millis)?);
+            }
+
+            // how long after a failed refresh the stale answer is given straight away, in seconds
+            ["serve-stale-recheck", seconds] => {
+                self.serve_stale
+                    .get_or_insert_with(ServeStale::default)
+                    .failure_recheck = Duration::from_secs(parse_number(seconds)?);
+            }
+
+            ["prefetch", value] => match parse_bool(value)? {
+                true => {
+                    self.prefetch.get_or_insert_with(Prefetch::default);
+                }
+                false => self.prefetch = None,
+            },
+
+            ["prefetch-min-hits", hits] => {
+                self.prefetch.get_or_insert_with(Prefetch::default).min_hits = parse_number(hits)?;
+            }
+
+            // share of the TTL left when a hit triggers the refresh, in percent
+            ["prefetch-threshold", percent] => {
+                let threshold = parse_number(percent)?;
+                if threshold > 100 {
+                    return Err(format!("invalid percentage {}", percent));
+                }
+                self.prefetch
+                    .get_or_insert_with(Prefetch::default)
+                    .threshold = threshold;
+            }
+
+            // refreshes per second
+            ["prefetch-rate", rate] => {
+                self.prefetch.get_or_insert_with(Prefetch::default).rate = parse_number(rate)?;
+            }
+
+            // the upstream has to validate, its AD bit is taken as proof the records are signed
+            ["aggressive-nsec", value] => self.aggressive_nsec = parse_bool(value)?,
+
+            // no means the subnet is never sent, whatever a client asks for
+            ["client-subnet", value] => match parse_bool(value)? {
+                true => {
+                    self.client_subnet
+                        .get_or_insert_with(ClientSubnetConfig::default);
+                }
+                false => self.client_subnet = None,
+            },
+
+            // the lengths cap what is sent, 0 asks the upstream not to tailor answers at all
+            ["client-subnet-prefix-length", ipv4, ipv6] => {
+                let config = self
+                    .client_subnet
+                    .get_or_insert_with(ClientSubnetConfig::default);
+                config.ipv4_prefix_length = parse_number(ipv4)?;
+                config.ipv6_prefix_length = parse_number(ipv6)?;
+                if config.ipv4_prefix_length > 32 || config.ipv6_prefix_length > 128 {
+                    return Err(format!("invalid prefix lengths {} {}", ipv4, ipv6));
+                }
+            }
+
+            ["dns64", value] => match parse_bool(value)? {
+                true => {
+                    self.dns64.get_or_insert_with(Dns64::default);
+                }
+                false => self.dns64 = None,
+            },
+
+            ["dns64-prefix", prefix] => {
+                let (addr, length) = prefix.split_once('/').unwrap_or((prefix, ""));
+                let addr = match (addr.parse::<Ipv6Addr>(), length) {
+                    (Ok(addr), "96") if u128::from(addr) & u32::MAX as u128 == 0 => addr,
+                    _ => return Err(format!("{} is not an IPv6 /96 prefix", prefix)),
+                };
+                self.dns64.get_or_insert_with(Dns64::default).prefix = addr;
+            }
+
+            ["dns64-exclude", entries @ ..] => {
+                self.dns64.get_or_insert_with(Dns64::default).exclude = entries
+                    .iter()
+                    .map(|word| {
+                        Cidr::parse(word).ok_or_else(|| format!("invalid address block {}", word))
+                    })
+                    .collect::<std::result::Result<_, _>>()?;
+            }
+
+            ["cookies", value] => match parse_bool(value)? {
+                true => {
+                    self.cookies.get_or_insert_with(CookieConfig::default);
+                }
+                false => self.cookies = None,
+            },
+
+            // servers behind one anycast address share the secret to accept each other's cookies
+            ["cookie-secret", secret] => {
+                let secret = STANDARD
+                    .decode(secret)
+                    .ok()
+                    .and_then(|secret| <[u8; COOKIE_SECRET_SIZE]>::try_from(secret).ok())
+                    .ok_or_else(|| {
+                        format!(
+                            "cookie secret must be {} bytes of base64",
+                            COOKIE_SECRET_SIZE
+                        )
+                    })?;
+                self.cookies
+                    .get_or_insert_with(CookieConfig::default)
+                    .secret = Some(secret);
+            }
+
+            ["require-cookies-above", size] => {
+                self.cookies
+                    .get_or_insert_with(CookieConfig::default)
+                    .required_above = Some(parse_number(size)?);
+            }
+
+            ["rate-limit", "responses-per-second", value] => {
+                self.rate_limit.responses_per_second = parse_number(value)?;
+            }
+
+            ["rate-limit", "nxdomains-per-second", value] => {
+                self.rate_limit.nxdomains_per_second = Some(parse_number(value)?);
+            }
+
+            ["rate-limit", "errors-per-second", value] => {
+                self.rate_limit.errors_per_second = Some(parse_number(value)?);
+            }
+
+            ["rate-limit", "slip", value] => self.rate_limit.slip = parse_number(value)?,
+
+            ["rate-limit", "window", value] => {
+                self.rate_limit.window = parse_number(value)?;
+                if self.rate_limit.window == 0 {
+                    return Err(String::from("window must be at least 1 second"));
+                }
+            }
+
+            ["rate-limit", "ipv4-prefix-length", value] => {
+                self.rate_limit.ipv4_prefix_length = parse_number(value)?;
+                if self.rate_limit.ipv4_prefix_length > 32 {
+                    return Err(format!("invalid IPv4 prefix length {}", value));
+                }
+            }
+
+            ["rate-limit", "ipv6-prefix-length", value] => {
+                self.rate_limit.ipv6_prefix_length = parse_number(value)?;
+                if self.rate_limit.ipv6_prefix_length > 128 {
+                    return Err(format!("invalid IPv6 prefix length {}", value));
+                }
+            }
+
+            ["rate-limit", "log-only", value] => self.rate_limit.log_only = parse_bool(value)?,
+
+            ["rate-limit", "exempt-clients", clients @ ..] => {
+                for word in clients {
+                    self.rate_limit.exempt.push(
+                        Cidr::parse(word)
+                            .ok_or_else(|| format!("invalid address block {}", word))?,
+                    );
+                }
+            }
+
+            ["acl", name, entries @ ..] => {
+                if BUILTIN_ACLS.contains(name) || self.acls.contains_key(*name) {
+                    return Err(format!("acl {} is already defined", name));
+                }
+                let acl = self.parse_acl(entries)?;
+                self.acls.insert(String::from(*name), acl);
+            }
+
+            ["allow-query", entries @ ..] => self.allow_query = Some(self.parse_acl(entries)?),
+
+            ["allow-recursion", entries @ ..] => {
+                self.allow_recursion = Some(self.parse_acl(entries)?);
+            }
+
+            ["allow-transfer", origin, entries @ ..] => {
+                let acl = self.parse_acl(entries)?;
+                self.zone_mut(origin)?.allow_transfer = acl;
+            }
+
+            ["allow-update", origin, entries @ ..] => {
+                let acl = self.parse_acl(entries)?;
+                self.zone_mut(origin)?.allow_update = acl;
+            }
+
+            [directive, ..] => return Err(format!("invalid directive {}", directive)),
+            [] => {}
+        }
+
+        Ok(())
+    }
+
+    /// Parse a list of address blocks, keys and named acls, each key name following the word key
+    ///
+    /// The acls any, none and localhost are always defined, others must be defined earlier.
+    fn parse_acl(&self, words: &[&str]) -> std::result::Result<Acl, String> {
+        let mut acl = Acl::default();
+        let mut words = words.iter();
+        while let Some(word) = words.next() {
+            match *word {
+                "key" => {
+                    let name = words.next().ok_or("missing key name")?;
+                    acl.keys
+                        .push(name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase());
+                }
+                "any" => acl.blocks.extend(
+                    ["0.0.0.0/0", "::/0"]
+                        .iter()
+                        .filter_map(|block| Cidr::parse(block)),
+                ),
+                "none" => {}
+                "localhost" => acl.blocks.extend(
+                    ["127.0.0.0/8", "::1"]
+                        .iter()
+                        .filter_map(|block| Cidr::parse(block)),
+                ),
+                _ => match self.acls.get(*word) {
+                    Some(group) => {
+                        acl.blocks.extend(&group.blocks);
+                        acl.keys.extend(group.keys.iter().cloned());
+                    }
+                    None => acl.blocks.push(
+                        Cidr::parse(word)
+                            .ok_or_else(|| format!("invalid address block or acl {}", word))?,
+                    ),
+                },
+            }
+        }
+
+        Ok(acl)
+    }
+
+    /// A zone declared earlier in the file, in the view being read if inside one
+    fn zone_mut(&mut self, origin: &str) -> std::result::Result<&mut ZoneConfig, String> {
+        let view = match (self.in_view, self.views.last_mut()) {
+            (true, Some(view)) => view,
+            _ => &mut self.default_view,
+        };
+
+        view.zones
+            .iter_mut()
+            .find(|zone| zone.origin.eq_ignore_ascii_case(origin))
+            .ok_or_else(|| format!("zone {} has not been declared", origin))
+    }
+}
+
+/// Parse an address with an optional port, which defaults to the given one
+fn parse_socket_addr(word: &str, port: u16) -> std::result::Result<SocketAddr, String> {
+    word.parse::<SocketAddr>()
+        .or_else(|_| word.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+        .map_err(|_| format!("invalid address {}", word))
+}
+
+/// Parse the base64 SHA-256 digest of a public key
+fn parse_pin(word: &str) -> std::result::Result<Vec<u8>, String> {
+    STANDARD
+        .decode(word)
+        .ok()
+        .filter(|pin| pin.len() == 32)
+        .ok_or_else(|| format!("invalid public key pin {}", word))
+}
+
+/// Parse a list of public key pins
+fn parse_pins(words: &[&str]) -> std::result::Result<Vec<Vec<u8>>, String> {
+    words.iter().map(|pin| parse_pin(pin)).collect()
+}
+
+/// Parse a number that is not negative
+fn parse_number<T: FromStr>(value: &str) -> std::result::Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid number {}", value))
+}
+
+/// The line up to a `#` that starts a comment, one inside a quoted string is kept
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (pos, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..pos],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+/// Everything on the line after the directive, as written
+fn after_directive(line: &str) -> &str {
+    line.trim_start()
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, rest)| rest)
+}
+
+/// Parse a yes or no setting
+fn parse_bool(value: &str) -> std::result::Result<bool, String> {
+    match value {
+        "yes" | "true" | "on" => Ok(true),
+        "no" | "false" | "off" => Ok(false),
+        _ => Err(format!("expected yes or no, found {}", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Apply one line as it would be read from the file
+    fn apply_line(config: &mut Config, line: &str) {
+        let line = strip_comment(line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        config.apply(Path::new(""), &words, line).unwrap();
+    }
+
+    #[test]
+    fn comments_end_outside_quotes() {
+        assert_eq!(strip_comment("cache-size 10 # small"), "cache-size 10 ");
+        assert_eq!(
+            strip_comment(r##"local t.test TXT "a#b" # note"##),
+            r##"local t.test TXT "a#b" "##
+        );
+        assert_eq!(
+            strip_comment(r##"local t.test TXT "say \"#\"" #"##),
+            r##"local t.test TXT "say \"#\"" "##
+        );
+    }
+
+    #[test]
+    fn local_txt_keeps_its_text() {
+        let mut config = Config::default();
+        apply_line(&mut config, r##"local t.test TXT "a#b  c" "d" # comment"##);
+
+        assert!(matches!(
+            &config.default_view.local_records[..],
+            [Record::Txt { data, .. }] if *data == [b"a#b  c".to_vec(), b"d".to_vec()]
+        ));
+    }
+
+    #[test]
+    fn zone_access_is_read_in_its_view() {
+        let mut config = Config::default();
+        for line in [
+            "zone example.com external.zone",
+            "view internal 10.0.0.0/8",
+            "zone example.com internal.zone",
+            "allow-transfer example.com 10.0.0.2",
+            "end",
+            "allow-update example.com 192.0.2.1",
+        ] {
+            apply_line(&mut config, line);
+        }
+
+        let internal = &config.views[0].zones[0];
+        assert_eq!(internal.allow_transfer.blocks.len(), 1);
+        assert!(internal.allow_update.blocks.is_empty());
+        let external = &config.default_view.zones[0];
+        assert!(external.allow_transfer.blocks.is_empty());
+        assert_eq!(external.allow_update.blocks.len(), 1);
+    }
+
+    #[test]
+    fn aggressive_nsec_needs_authenticated_upstreams() {
+        let mut config = Config::default();
+        apply_line(&mut config, "aggressive-nsec yes");
+        assert!(config.check_aggressive_nsec().is_err());
+
+        apply_line(&mut config, "upstream 192.0.2.53 key resolver-key");
+        assert!(config.check_aggressive_nsec().is_ok());
+
+        apply_line(&mut config, "forward corp.example 10.0.0.53");
+        assert!(config.check_aggressive_nsec().is_err());
+
+        let mut config = Config::default();
+        apply_line(&mut config, "aggressive-nsec yes");
+        apply_line(&mut config, "upstream-tls 9.9.9.9 dns.quad9.net");
+        apply_line(&mut config, "view lab 10.0.0.0/8");
+        apply_line(&mut config, "forward lab.example 10.0.0.55 key forward-key");
+        apply_line(&mut config, "end");
+        assert!(config.check_aggressive_nsec().is_ok());
+        apply_line(
+            &mut config,
+            "forward consul 127.0.0.1:8600 tcp key forward-key iterative",
+        );
+        assert!(config.check_aggressive_nsec().is_err());
+    }
+}
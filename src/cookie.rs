@@ -0,0 +1,355 @@
+use std::{
+    collections::HashMap,
+    hash::Hasher,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{thread_rng, Rng};
+use siphasher::sip::SipHasher24;
+
+use super::{
+    dns_packet::DNSPacket,
+    edns::{edns_options, set_extended_rcode, EdnsOption, OPTION_COOKIE, RCODE_BADCOOKIE},
+    record::Record,
+};
+
+pub const COOKIE_SECRET_SIZE: usize = 16; // SipHash-2-4 takes a 128 bit key
+const CLIENT_COOKIE_SIZE: usize = 8;
+const SERVER_COOKIE_SIZES: (usize, usize) = (8, 32); // what any server may hand out, RFC 7873 4.2
+const SERVER_COOKIE_SIZE: usize = 16; // the cookies we hand out, RFC 9018 section 4
+const COOKIE_VERSION: u8 = 1;
+const MAX_COOKIE_AGE: u32 = 3600; // older server cookies are no longer accepted, RFC 9018 4.3
+const MAX_COOKIE_SKEW: u32 = 300; // nor ones from further in the future
+const COOKIE_REFRESH_AGE: u32 = 1800; // a client gets a new server cookie past this age
+
+/// The client and server cookie of a COOKIE option, None when the option is malformed
+fn split_cookie(data: &[u8]) -> Option<([u8; CLIENT_COOKIE_SIZE], &[u8])> {
+    if data.len() < CLIENT_COOKIE_SIZE {
+        return None;
+    }
+    let (client, server) = data.split_at(CLIENT_COOKIE_SIZE);
+    if !server.is_empty()
+        && !(SERVER_COOKIE_SIZES.0..=SERVER_COOKIE_SIZES.1).contains(&server.len())
+    {
+        return None;
+    }
+
+    Some((client.try_into().ok()?, server))
+}
+
+/// The cookies carried by the message, None without a COOKIE option and Some(None) when it is
+/// malformed
+fn cookie_of(packet: &DNSPacket) -> Option<Option<([u8; CLIENT_COOKIE_SIZE], &[u8])>> {
+    edns_options(packet)?
+        .iter()
+        .find(|option| option.code == OPTION_COOKIE)
+        .map(|option| split_cookie(&option.data))
+}
+
+fn cookie_option(client: &[u8], server: &[u8]) -> EdnsOption {
+    EdnsOption {
+        code: OPTION_COOKIE,
+        data: [client, server].concat(),
+    }
+}
+
+/// If a response carrying a cookie carries the client cookie of the query, a spoofed one could
+/// only guess it
+pub fn is_cookie_echoed(query: &DNSPacket, res: &DNSPacket) -> bool {
+    match (cookie_of(query), cookie_of(res)) {
+        (Some(Some((sent, _))), Some(echoed)) => echoed.is_some_and(|(echoed, _)| echoed == sent),
+        _ => true,
+    }
+}
+
+/// Turn a response into BADCOOKIE, which only hands the client its server cookie, RFC 7873
+/// section 5.2.3
+pub fn bad_cookie(res_packet: &mut DNSPacket) {
+    res_packet.answer_sec.clear();
+    res_packet.authority_sec.clear();
+    res_packet
+        .additional_sec
+        .retain(|rec| matches!(rec, Record::Opt { .. }));
+    res_packet.header.aa = false;
+    // the MAC would cover the sections just removed
+    res_packet.signer = None;
+    set_extended_rcode(res_packet, RCODE_BADCOOKIE);
+}
+
+/// Seconds since the epoch, wrapping around like the timestamp of a server cookie
+fn timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
+
+fn write_ip(hasher: &mut SipHasher24, ip: IpAddr) {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => hasher.write(&ip.octets()),
+        IpAddr::V6(ip) => hasher.write(&ip.octets()),
+    }
+}
+
+#[derive(Debug)]
+/// Cookies sent to upstream servers, RFC 7873 section 5.1
+pub struct ClientCookies {
+    secret: [u8; COOKIE_SECRET_SIZE],
+    /// Server cookie each server last handed out
+    server_cookies: Mutex<HashMap<IpAddr, Vec<u8>>>,
+}
+
+impl Default for ClientCookies {
+    fn default() -> Self {
+        ClientCookies {
+            secret: thread_rng().gen(),
+            server_cookies: Mutex::default(),
+        }
+    }
+}
+
+impl ClientCookies {
+    /// Our client cookie for the server, a different one for each server so they cannot be used
+    /// to track us, RFC 9018 section 3
+    fn client_cookie(&self, server: IpAddr) -> [u8; CLIENT_COOKIE_SIZE] {
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        write_ip(&mut hasher, server);
+        hasher.finish().to_le_bytes()
+    }
+
+    /// The COOKIE option for a query to the server, along with the server cookie it gave us last
+    pub fn option_for(&self, server: IpAddr) -> EdnsOption {
+        let server_cookies = self
+            .server_cookies
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let server_cookie = server_cookies.get(&server).map_or(&[][..], Vec::as_slice);
+        cookie_option(&self.client_cookie(server), server_cookie)
+    }
+
+    /// Remember the server cookie of a response to our client cookie, RFC 7873 section 5.3
+    pub fn learn(&self, server: IpAddr, res_packet: &DNSPacket) {
+        let Some(Some((client, server_cookie))) = cookie_of(res_packet) else {
+            return;
+        };
+        if client == self.client_cookie(server) && !server_cookie.is_empty() {
+            self.server_cookies
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(server, server_cookie.to_vec());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// How the server hands out and checks cookies
+pub struct CookieConfig {
+    /// Secret server cookies are made with, shared by servers behind one address, random if not
+    /// given
+    pub secret: Option<[u8; COOKIE_SECRET_SIZE]>,
+    /// UDP responses larger than this many bytes only go to clients with a valid server cookie
+    pub required_above: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The COOKIE option of a query as the server sees it
+pub enum CookieCheck {
+    /// The query carries no cookie
+    Absent,
+    /// The option has a length no cookie can have
+    Malformed,
+    /// A client cookie, without a server cookie we gave this client or with an expired one
+    Unverified([u8; CLIENT_COOKIE_SIZE]),
+    /// A client cookie along with a server cookie we gave this client
+    Verified {
+        client: [u8; CLIENT_COOKIE_SIZE],
+        server: Vec<u8>,
+    },
+}
+
+#[derive(Debug)]
+/// Server cookies handed out to clients and checked on their later queries, RFC 9018
+pub struct ServerCookies {
+    secret: [u8; COOKIE_SECRET_SIZE],
+    /// UDP responses larger than this many bytes only go to clients with a valid server cookie
+    pub required_above: Option<usize>,
+}
+
+impl ServerCookies {
+    pub fn new(config: &CookieConfig) -> Self {
+        ServerCookies {
+            secret: config.secret.unwrap_or_else(|| thread_rng().gen()),
+            required_above: config.required_above,
+        }
+    }
+
+    /// The server cookie for the client cookie and address at the time, version 1 of RFC 9018
+    fn server_cookie(&self, client_cookie: &[u8], time: u32, client: IpAddr) -> Vec<u8> {
+        let mut cookie = vec![COOKIE_VERSION, 0, 0, 0];
+        cookie.extend(time.to_be_bytes());
+
+        let mut hasher = SipHasher24::new_with_key(&self.secret);
+        hasher.write(client_cookie);
+        hasher.write(&cookie);
+        write_ip(&mut hasher, client);
+        cookie.extend(hasher.finish().to_le_bytes());
+
+        cookie
+    }
+
+    /// Seconds since the server cookie was made, if it is one of ours for the client that has not
+    /// expired
+    fn age(&self, client_cookie: &[u8], server_cookie: &[u8], client: IpAddr) -> Option<u32> {
+        if server_cookie.len() != SERVER_COOKIE_SIZE || server_cookie[0] != COOKIE_VERSION {
+            return None;
+        }
+        let time = u32::from_be_bytes(server_cookie[4..8].try_into().ok()?);
+        if self.server_cookie(client_cookie, time, client) != server_cookie {
+            return None;
+        }
+
+        // the timestamp is a serial number, it may have wrapped around since, RFC 1982
+        let age = timestamp().wrapping_sub(time);
+        match age {
+            age if age <= MAX_COOKIE_AGE => Some(age),
+            age if age.wrapping_neg() <= MAX_COOKIE_SKEW => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Check the cookie of a query from the client
+    pub fn check(&self, query_packet: &DNSPacket, client: IpAddr) -> CookieCheck {
+        match cookie_of(query_packet) {
+            None => CookieCheck::Absent,
+            Some(None) => CookieCheck::Malformed,
+            Some(Some((client_cookie, server_cookie))) => {
+                match self.age(&client_cookie, server_cookie, client) {
+                    Some(_) => CookieCheck::Verified {
+                        client: client_cookie,
+                        server: server_cookie.to_vec(),
+                    },
+                    None => CookieCheck::Unverified(client_cookie),
+                }
+            }
+        }
+    }
+
+    /// The COOKIE option answering a query, the server cookie of the client given back unless it
+    /// is due to be replaced
+    pub fn option_for(&self, check: &CookieCheck, client: IpAddr) -> Option<EdnsOption> {
+        let client_cookie = match check {
+            CookieCheck::Absent | CookieCheck::Malformed => return None,
+            CookieCheck::Verified {
+                client: client_cookie,
+                server,
+            } => {
+                if self
+                    .age(client_cookie, server, client)
+                    .is_some_and(|age| age < COOKIE_REFRESH_AGE)
+                {
+                    return Some(cookie_option(client_cookie, server));
+                }
+                client_cookie
+            }
+            CookieCheck::Unverified(client_cookie) => client_cookie,
+        };
+
+        let server = self.server_cookie(client_cookie, timestamp(), client);
+        Some(cookie_option(client_cookie, &server))
+    }
+
+    /// If a UDP response of this size is held back from the client for lack of a valid cookie
+    pub fn holds_back(&self, check: &CookieCheck, size: usize) -> bool {
+        self.required_above.is_some_and(|limit| size > limit)
+            && !matches!(check, CookieCheck::Verified { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|pos| u8::from_str_radix(&text[pos..pos + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn server_cookies(secret: &str) -> ServerCookies {
+        ServerCookies::new(&CookieConfig {
+            secret: Some(hex(secret).try_into().unwrap()),
+            required_above: None,
+        })
+    }
+
+    #[test]
+    fn server_cookies_match_rfc_9018_vectors() {
+        // appendix A.1 and A.2, an IPv4 client learning a server cookie and then a renewed one
+        let cookies = server_cookies("e5e973e5a6b2a43f48e7dc849e37bfcf");
+        let client = "198.51.100.100".parse().unwrap();
+        assert_eq!(
+            cookies.server_cookie(&hex("2464c4abcf10c957"), 1559731985, client),
+            hex("010000005cf79f111f8130c3eee29480")
+        );
+        assert_eq!(
+            cookies.server_cookie(&hex("2464c4abcf10c957"), 1559734385, client),
+            hex("010000005cf7a871d4a564a1442aca77")
+        );
+    }
+
+    #[test]
+    fn server_cookie_is_refreshed_then_expires() {
+        let cookies = server_cookies("e5e973e5a6b2a43f48e7dc849e37bfcf");
+        let client_cookie = [1; CLIENT_COOKIE_SIZE];
+        let client = "192.0.2.1".parse().unwrap();
+        let made_ago = |secs: i64| {
+            let time = (timestamp() as i64 - secs) as u32;
+            cookies.server_cookie(&client_cookie, time, client)
+        };
+        let handed_back = |server: &[u8]| {
+            let check = CookieCheck::Verified {
+                client: client_cookie,
+                server: server.to_vec(),
+            };
+            cookies.option_for(&check, client).unwrap().data[CLIENT_COOKIE_SIZE..].to_vec()
+        };
+
+        let young = made_ago(60);
+        assert!(cookies
+            .age(&client_cookie, &young, client)
+            .is_some_and(|age| age >= 60));
+        assert_eq!(handed_back(&young), young);
+
+        // past the refresh age the cookie is still good but a new one is handed out
+        let old = made_ago(COOKIE_REFRESH_AGE as i64 + 5);
+        assert!(cookies.age(&client_cookie, &old, client).is_some());
+        assert_ne!(handed_back(&old), old);
+
+        assert_eq!(
+            cookies.age(&client_cookie, &made_ago(MAX_COOKIE_AGE as i64 + 5), client),
+            None
+        );
+        assert_eq!(
+            cookies.age(
+                &client_cookie,
+                &made_ago(-(MAX_COOKIE_SKEW as i64) + 5),
+                client
+            ),
+            Some(0)
+        );
+        assert_eq!(
+            cookies.age(
+                &client_cookie,
+                &made_ago(-(MAX_COOKIE_SKEW as i64) - 5),
+                client
+            ),
+            None
+        );
+
+        // made for another client
+        let other = "192.0.2.2".parse().unwrap();
+        assert_eq!(cookies.age(&client_cookie, &young, other), None);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+};
+
+use super::{
+    acl::Cidr,
+    dns_packet::DNSPacket,
+    edns::ClientSubnet,
+    errors::Result,
+    header::ResponseCode::Noerror,
+    local::reverse_name,
+    question::{QueryType, Question},
+    record::{Record, RecordPreamble},
+    server::{lookup, LookupOptions},
+    zone::labels,
+};
+
+pub const WELL_KNOWN_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0); // RFC 6052
+const MAX_SYNTHESIZED_TTL: u32 = 600; // cap when the AAAA response carries no SOA, RFC 6147 5.1.7
+
+#[derive(Debug, Clone)]
+/// Synthesis of AAAA records from A records for IPv6-only clients, RFC 6147
+pub struct Dns64 {
+    /// The /96 prefix IPv4 addresses are embedded under
+    pub prefix: Ipv6Addr,
+    /// AAAA records in these ranges count as missing, A records in these ranges are not mapped
+    pub exclude: Vec<Cidr>,
+}
+
+impl Default for Dns64 {
+    fn default() -> Self {
+        Dns64 {
+            prefix: WELL_KNOWN_PREFIX,
+            // IPv4-mapped addresses never reach an IPv6-only client, RFC 6147 section 5.1.4
+            exclude: Cidr::parse("::ffff:0:0/96").into_iter().collect(),
+        }
+    }
+}
+
+impl Dns64 {
+    /// Look up the question, synthesizing AAAA records and mapping reverse names where needed
+    pub fn lookup(
+        &self,
+        que: &Question,
+        subnet: Option<&ClientSubnet>,
+        options: &Arc<LookupOptions>,
+    ) -> Result<DNSPacket> {
+        match que.query_type {
+            QueryType::Aaaa => self.lookup_aaaa(&que.name, subnet, options),
+            QueryType::Ptr => match self.embedded_reverse(&que.name) {
+                Some(name) => lookup_mapped_ptr(&que.name, &name, subnet, options),
+                None => lookup(&que.name, QueryType::Ptr, subnet, options),
+            },
+            _ => lookup(&que.name, que.query_type.clone(), subnet, options),
+        }
+    }
+
+    fn is_excluded(&self, ip: IpAddr) -> bool {
+        // an IPv4-mapped AAAA record is not the same as an A record
+        self.exclude.iter().any(|block| block.contains_exact(ip))
+    }
+
+    /// Look up AAAA records, falling back to the A records of the name when there are none
+    ///
+    /// A name that does not exist or fails to resolve is passed through, RFC 6147 section 5.1.2.
+    fn lookup_aaaa(
+        &self,
+        name: &str,
+        subnet: Option<&ClientSubnet>,
+        options: &Arc<LookupOptions>,
+    ) -> Result<DNSPacket> {
+        let mut res_packet = lookup(name, QueryType::Aaaa, subnet, options)?;
+        if res_packet.header.rcode != Noerror {
+            return Ok(res_packet);
+        }
+
+        res_packet.answer_sec.retain(|rec| match rec {
+            Record::Aaaa { ip, .. } => !self.is_excluded(IpAddr::V6(*ip)),
+            _ => true,
+        });
+        if res_packet
+            .answer_sec
+            .iter()
+            .any(|rec| matches!(rec, Record::Aaaa { .. }))
+        {
+            return Ok(res_packet);
+        }
+
+        // the synthesized records live no longer than the negative AAAA answer
+        let negative_ttl = res_packet
+            .authority_sec
+            .iter()
+            .find_map(|rec| match rec {
+                Record::Soa { preamble, .. } => Some(preamble.ttl),
+                _ => None,
+            })
+            .unwrap_or(MAX_SYNTHESIZED_TTL);
+
+        // a failed A lookup still leaves the AAAA answer good to send
+        let a_packet = match lookup(name, QueryType::A, subnet, options) {
+            Ok(a_packet) if a_packet.header.rcode == Noerror => a_packet,
+            _ => return Ok(res_packet),
+        };
+        let answer: Vec<Record> = a_packet
+            .answer_sec
+            .into_iter()
+            .filter_map(|rec| match rec {
+                Record::A { preamble, ip } if !self.is_excluded(IpAddr::V4(ip)) => {
+                    Some(Record::Aaaa {
+                        preamble: RecordPreamble::new(
+                            &preamble.name,
+                            QueryType::Aaaa,
+                            preamble.ttl.min(negative_ttl),
+                        ),
+                        ip: self.synthesize(u32::from(ip)),
+                    })
+                }
+                Record::A { .. } => None,
+                rec => Some(rec),
+            })
+            .collect();
+
+        // without an address left to map the AAAA answer stands, aliases and all
+        if answer.iter().any(|rec| matches!(rec, Record::Aaaa { .. })) {
+            res_packet.answer_sec = answer;
+            res_packet.authority_sec.clear();
+            res_packet.additional_sec = a_packet.additional_sec;
+        }
+
+        Ok(res_packet)
+    }
+
+    /// The IPv6 address the IPv4 address is embedded in
+    fn synthesize(&self, ip: u32) -> Ipv6Addr {
+        Ipv6Addr::from(u128::from(self.prefix) | ip as u128)
+    }
+
+    /// The in-addr.arpa name of the IPv4 address embedded in an ip6.arpa name under the prefix
+    fn embedded_reverse(&self, name: &str) -> Option<String> {
+        let name_labels = labels(name);
+        let [arpa, ip6, nibbles @ ..] = name_labels.as_slice() else {
+            return None;
+        };
+        if arpa != "arpa" || ip6 != "ip6" || nibbles.len() != 32 {
+            return None;
+        }
+
+        let mut addr: u128 = 0;
+        for nibble in nibbles {
+            let [digit] = nibble.as_bytes() else {
+                return None;
+            };
+            addr = addr << 4 | (*digit as char).to_digit(16)? as u128;
+        }
+        if addr >> 32 != u128::from(self.prefix) >> 32 {
+            return None;
+        }
+
+        Some(reverse_name((addr as u32).into()))
+    }
+}
+
+/// Answer a reverse lookup of a synthesized address with the PTR records of its IPv4 address,
+/// RFC 6147 section 5.3.1
+fn lookup_mapped_ptr(
+    name: &str,
+    mapped: &str,
+    subnet: Option<&ClientSubnet>,
+    options: &Arc<LookupOptions>,
+) -> Result<DNSPacket> {
+    let mut res_packet = lookup(mapped, QueryType::Ptr, subnet, options)?;
+    for rec in &mut res_packet.answer_sec {
+        if labels(&rec.preamble().name) == labels(mapped) {
+            rec.preamble_mut().name = String::from(name);
+        }
+    }
+
+    Ok(res_packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, thread};
+
+    use super::*;
+    use crate::{raw_packet::RawPacket, server::Upstream, zone_file::parse_record};
+
+    fn rec(text: &str) -> Record {
+        parse_record(text, 3600).unwrap()
+    }
+
+    /// Lookups sent to a stand-in upstream answering from the records, with NODATA and an SOA
+    /// of TTL 60 for anything else
+    fn options(records: &[&str]) -> Arc<LookupOptions> {
+        let records: Vec<Record> = records.iter().map(|text| rec(text)).collect();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut query_buf = RawPacket::new();
+            let Ok((_, client)) = socket.recv_from(&mut query_buf.buf) else {
+                return;
+            };
+            let mut res_packet = DNSPacket::new();
+            res_packet.parse(&mut query_buf).unwrap();
+            res_packet.header.qr = true;
+            res_packet.additional_sec.clear();
+
+            let que = &res_packet.question_sec[0];
+            let answer: Vec<Record> = records
+                .iter()
+                .filter(|rec| {
+                    labels(&rec.preamble().name) == labels(&que.name)
+                        && rec.preamble().query_type == que.query_type
+                })
+                .cloned()
+                .collect();
+            if answer.is_empty() {
+                res_packet.authority_sec.push(rec(
+                    "example.com. 60 IN SOA ns.example.com. admin.example.com. 1 7200 900 86400 300",
+                ));
+            }
+            res_packet.answer_sec = answer;
+
+            let mut res_buf = RawPacket::new();
+            res_packet.write(&mut res_buf).unwrap();
+            let _ = socket.send_to(&res_buf.buf[..res_buf.cursor()], client);
+        });
+
+        Arc::new(LookupOptions {
+            upstream: Arc::new(Upstream::Udp(server, None)),
+            ..LookupOptions::default()
+        })
+    }
+
+    fn addresses(res_packet: &DNSPacket) -> Vec<(Ipv6Addr, u32)> {
+        res_packet
+            .answer_sec
+            .iter()
+            .filter_map(|rec| match rec {
+                Record::Aaaa { preamble, ip } => Some((*ip, preamble.ttl)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn address_is_embedded_under_the_prefix() {
+        let dns64 = Dns64::default();
+        assert_eq!(
+            dns64.synthesize(0xc000_0221),
+            "64:ff9b::c000:221".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn reverse_name_maps_to_the_embedded_address() {
+        let dns64 = Dns64::default();
+        let name = "1.2.2.0.0.0.0.c.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.b.9.f.f.4.6.0.0.ip6.arpa";
+        assert_eq!(
+            dns64.embedded_reverse(name).as_deref(),
+            Some("33.2.0.192.in-addr.arpa")
+        );
+
+        // one nibble short, or under another prefix
+        assert_eq!(dns64.embedded_reverse(&name[2..]), None);
+        let other = name.replace("b.9.f.f", "b.9.f.e");
+        assert_eq!(dns64.embedded_reverse(&other), None);
+    }
+
+    #[test]
+    fn synthesized_ttl_is_capped_by_the_negative_answer() {
+        let dns64 = Dns64::default();
+        let options = options(&[
+            "long.example.com. 300 IN A 192.0.2.33",
+            "short.example.com. 30 IN A 192.0.2.34",
+        ]);
+
+        let res_packet = dns64
+            .lookup_aaaa("long.example.com", None, &options)
+            .unwrap();
+        assert_eq!(
+            addresses(&res_packet),
+            [("64:ff9b::c000:221".parse().unwrap(), 60)]
+        );
+
+        let res_packet = dns64
+            .lookup_aaaa("short.example.com", None, &options)
+            .unwrap();
+        assert_eq!(
+            addresses(&res_packet),
+            [("64:ff9b::c000:222".parse().unwrap(), 30)]
+        );
+    }
+
+    #[test]
+    fn mapped_and_excluded_addresses_are_left_out() {
+        let dns64 = Dns64 {
+            exclude: ["::ffff:0:0/96", "10.0.0.0/8"]
+                .iter()
+                .filter_map(|block| Cidr::parse(block))
+                .collect(),
+            ..Dns64::default()
+        };
+        let options = options(&[
+            "mapped.example.com. 300 IN AAAA ::ffff:192.0.2.1",
+            "mapped.example.com. 300 IN A 192.0.2.1",
+            "native.example.com. 300 IN AAAA 2001:db8::1",
+            "native.example.com. 300 IN A 192.0.2.2",
+            "private.example.com. 300 IN A 10.0.0.1",
+        ]);
+
+        // an IPv4-mapped AAAA counts as none, with no SOA the A TTL stands
+        let res_packet = dns64
+            .lookup_aaaa("mapped.example.com", None, &options)
+            .unwrap();
+        assert_eq!(
+            addresses(&res_packet),
+            [("64:ff9b::c000:201".parse().unwrap(), 300)]
+        );
+
+        let res_packet = dns64
+            .lookup_aaaa("native.example.com", None, &options)
+            .unwrap();
+        assert_eq!(
+            addresses(&res_packet),
+            [("2001:db8::1".parse().unwrap(), 300)]
+        );
+
+        let res_packet = dns64
+            .lookup_aaaa("private.example.com", None, &options)
+            .unwrap();
+        assert!(addresses(&res_packet).is_empty());
+    }
+}
@@ -1,7 +1,14 @@
 use crate::raw_packet::RawPacket;
 
-use super::{errors::Result, header::Header, question::Question, record::Record};
+use super::{
+    errors::Result,
+    header::{Header, Opcode},
+    question::Question,
+    record::Record,
+    tsig::Signer,
+};
 
+#[derive(Clone)]
 /// The entire DNS Packet
 pub struct DNSPacket {
     /// Information about the query or response
@@ -14,6 +21,10 @@ pub struct DNSPacket {
     pub authority_sec: Vec<Record>,
     /// Additional records that may be useful
     pub additional_sec: Vec<Record>,
+    /// Where the TSIG closing a received message starts, needed to check its MAC
+    pub tsig_start: Option<usize>,
+    /// Key to sign the message with when it is written
+    pub signer: Option<Signer>,
 }
 
 impl DNSPacket {
@@ -25,9 +36,23 @@ impl DNSPacket {
             answer_sec: Vec::new(),
             authority_sec: Vec::new(),
             additional_sec: Vec::new(),
+            tsig_start: None,
+            signer: None,
         }
     }
 
+    /// Strip a response down to its question and OPT record with TC set, which invites the client
+    /// over to TCP
+    pub fn truncate(&mut self) {
+        self.header.tc = true;
+        self.answer_sec.clear();
+        self.authority_sec.clear();
+        self.additional_sec
+            .retain(|rec| matches!(rec, Record::Opt { .. }));
+        // the MAC would cover the sections just removed, a real client learns the answer over TCP
+        self.signer = None;
+    }
+
     /// Parse the entire DNS packet from the given buffer
     pub fn parse(&mut self, buf: &mut RawPacket) -> Result<()> {
         self.header.parse(buf)?;
@@ -38,18 +63,29 @@ impl DNSPacket {
             self.question_sec.push(question);
         }
 
+        // the prerequisites and updates of an UPDATE take the place of the answer and authority
+        let parse = match self.header.op_code {
+            Opcode::Update => Record::parse_update,
+            _ => Record::parse,
+        };
+
         for _ in 0..self.header.an_count {
-            let record = Record::parse(buf)?;
+            let record = parse(buf)?;
             self.answer_sec.push(record);
         }
 
         for _ in 0..self.header.ns_count {
-            let record = Record::parse(buf)?;
+            let record = parse(buf)?;
             self.authority_sec.push(record);
         }
 
-        for _ in 0..self.header.ar_count {
+        for pos in 0..self.header.ar_count {
+            let start = buf.cursor();
             let record = Record::parse(buf)?;
+            // a TSIG only counts as the last record of the message
+            if matches!(record, Record::Tsig { .. }) && pos + 1 == self.header.ar_count {
+                self.tsig_start = Some(start);
+            }
             self.additional_sec.push(record);
         }
 
@@ -58,7 +94,13 @@ impl DNSPacket {
 
     /// Write the entire DNS packet into the given buffer
     pub fn write(&self, buf: &mut RawPacket) -> Result<()> {
-        self.header.write(buf)?;
+        // section counts always follow the sections actually being written
+        let mut header = self.header.clone();
+        header.qd_count = self.question_sec.len() as u16;
+        header.an_count = self.answer_sec.len() as u16;
+        header.ns_count = self.authority_sec.len() as u16;
+        header.ar_count = self.additional_sec.len() as u16;
+        header.write(buf)?;
 
         for que in &self.question_sec {
             que.write(buf)?;
@@ -76,6 +118,10 @@ impl DNSPacket {
             rec.write(buf)?;
         }
 
+        if let Some(signer) = &self.signer {
+            signer.sign(buf, header.id, header.ar_count)?;
+        }
+
         Ok(())
     }
 }
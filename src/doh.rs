@@ -0,0 +1,451 @@
+use std::{
+    convert::Infallible,
+    error::Error,
+    net::SocketAddr,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    body::{Body, Incoming},
+    client::conn::http2::{self, SendRequest},
+    header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE},
+    service::service_fn,
+    Method, Request, Response, StatusCode, Uri,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use rustls::{pki_types::ServerName, ClientConfig, ServerConfig};
+use tokio::{net::TcpStream, runtime::Runtime, task, time::timeout};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{
+        Errors::{Http, IOErr},
+        Result,
+    },
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    record::Record,
+    server::{is_valid_response, respond, ServerState},
+};
+
+pub const HTTPS_PORT: u16 = 443; // port DNS over HTTPS is served on
+pub const ALPN: [&[u8]; 2] = [b"h2", b"http/1.1"]; // HTTP/2 is preferred, RFC 8484 section 5.2
+const PATH: &str = "/dns-query"; // the one URI template served, RFC 8484 section 4.1
+const DNS_MESSAGE: &str = "application/dns-message";
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5); // give up on the upstream after this long
+
+/// A response with no body
+fn status(code: StatusCode) -> Response<Full<Bytes>> {
+    let mut res = Response::new(Full::default());
+    *res.status_mut() = code;
+    res
+}
+
+/// The base64url encoded message of a GET request, RFC 8484 section 4.1
+fn query_param(uri: &Uri) -> Option<Vec<u8>> {
+    let dns = uri
+        .query()?
+        .split('&')
+        .find_map(|param| param.strip_prefix("dns="))?;
+    URL_SAFE_NO_PAD.decode(dns).ok()
+}
+
+/// Seconds a response may be cached, RFC 8484 section 5.1
+///
+/// This is the smallest TTL of the answer, or for a negative answer the TTL the SOA in the
+/// authority section gives it, RFC 2308. None when there is nothing to go by.
+fn cache_lifetime(res_packet: &DNSPacket) -> Option<u32> {
+    if !res_packet.answer_sec.is_empty() {
+        return res_packet
+            .answer_sec
+            .iter()
+            .map(|rec| rec.preamble().ttl)
+            .min();
+    }
+
+    res_packet.authority_sec.iter().find_map(|rec| match rec {
+        Record::Soa {
+            preamble, minimum, ..
+        } => Some(preamble.ttl.min(*minimum)),
+        _ => None,
+    })
+}
+
+/// Parse and answer one DNS message, giving the encoded response and how long it may be cached
+fn answer_message(
+    query: &[u8],
+    query_src: SocketAddr,
+    state: &ServerState,
+) -> Result<(Vec<u8>, Option<u32>)> {
+    let mut query_buf = RawPacket::with_size(query.len());
+    query_buf.buf.copy_from_slice(query);
+    let mut query_packet = DNSPacket::new();
+    query_packet.parse(&mut query_buf)?;
+
+    // a response is a single message, so transfers are answered as over UDP
+    let res_packet = respond(&query_buf, query_packet, query_src, state, false)?.remove(0);
+
+    let mut res_buf = RawPacket::with_size(MAX_PACKET_SIZE);
+    res_packet.write(&mut res_buf)?;
+
+    Ok((res_buf.written().to_vec(), cache_lifetime(&res_packet)))
+}
+
+/// Answer one HTTP request, GET and POST are both accepted, RFC 8484 section 4.1
+async fn answer_request<B>(
+    req: Request<B>,
+    query_src: SocketAddr,
+    state: Arc<ServerState>,
+) -> std::result::Result<Response<Full<Bytes>>, Infallible>
+where
+    B: Body,
+    B::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    if req.uri().path() != PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+
+    let query = match *req.method() {
+        Method::GET => match query_param(req.uri()) {
+            Some(query) => query,
+            None => return Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        Method::POST => {
+            let content_type = req.headers().get(CONTENT_TYPE);
+            if content_type.map(|value| value.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(req.into_body(), MAX_PACKET_SIZE)
+                .collect()
+                .await
+            {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    // resolving may block on upstream lookups
+    let answer = task::spawn_blocking(move || answer_message(&query, query_src, &state)).await;
+    let (res_bytes, lifetime) = match answer {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(_)) => return Ok(status(StatusCode::BAD_REQUEST)),
+        Err(_) => return Ok(status(StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let mut res = Response::builder().header(CONTENT_TYPE, DNS_MESSAGE);
+    if let Some(lifetime) = lifetime {
+        res = res.header(CACHE_CONTROL, format!("max-age={}", lifetime));
+    }
+    Ok(res
+        .body(Full::new(Bytes::from(res_bytes)))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+/// Accept DNS over HTTPS connections forever, RFC 8484
+///
+/// HTTP runs on its own async runtime, each query is still resolved on a blocking thread.
+pub fn serve_https(
+    listener: std::net::TcpListener,
+    config: Arc<ServerConfig>,
+    state: Arc<ServerState>,
+) -> Result<()> {
+    let runtime = Runtime::new().map_err(IOErr)?;
+    runtime.block_on(async {
+        listener.set_nonblocking(true).map_err(IOErr)?;
+        let listener = tokio::net::TcpListener::from_std(listener).map_err(IOErr)?;
+        let acceptor = TlsAcceptor::from(config);
+
+        loop {
+            let (stream, query_src) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("failed to accept HTTPS connection: {}", e);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("failed to handle HTTPS connection: {}", e);
+                        return;
+                    }
+                };
+
+                let service = service_fn(move |req: Request<Incoming>| {
+                    answer_request(req, query_src, Arc::clone(&state))
+                });
+                if let Err(e) = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    eprintln!("failed to handle HTTPS connection: {}", e);
+                }
+            });
+        }
+    })
+}
+
+#[derive(Debug)]
+/// A resolver reached over DNS over HTTPS, lookups share one HTTP/2 connection
+pub struct HttpsUpstream {
+    pub server: SocketAddr,
+    /// URI of the DoH endpoint, its host is the name the certificate must be valid for
+    uri: Uri,
+    name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    /// Drives the HTTP/2 connection between lookups
+    runtime: Runtime,
+    /// Connection left open by the last lookup
+    sender: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl HttpsUpstream {
+    pub fn new(server: SocketAddr, url: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        let uri: Uri = url
+            .parse()
+            .map_err(|_| Http(format!("invalid URL {}", url)))?;
+        if uri.scheme_str() != Some("https") {
+            return Err(Http(format!("{} is not an https URL", url)));
+        }
+        let host = uri.host().unwrap_or_default();
+        let name = ServerName::try_from(host)
+            .map_err(|_| Http(format!("invalid server name {}", host)))?
+            .to_owned();
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(IOErr)?;
+
+        Ok(HttpsUpstream {
+            server,
+            uri,
+            name,
+            config,
+            runtime,
+            sender: Mutex::new(None),
+        })
+    }
+
+    /// Open a new HTTP/2 connection to the server
+    async fn connect(&self) -> Result<SendRequest<Full<Bytes>>> {
+        let stream = TcpStream::connect(self.server).await.map_err(IOErr)?;
+        let stream = TlsConnector::from(Arc::clone(&self.config))
+            .connect(self.name.clone(), stream)
+            .await
+            .map_err(IOErr)?;
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(|e| Http(e.to_string()))?;
+
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                eprintln!("DNS over HTTPS upstream connection failed: {}", e);
+            }
+        });
+
+        Ok(sender)
+    }
+
+    /// The open connection, or a new one once the server closed it
+    async fn sender(&self) -> Result<SendRequest<Full<Bytes>>> {
+        let open = self
+            .sender
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .filter(|sender| !sender.is_closed())
+            .cloned();
+        if let Some(sender) = open {
+            return Ok(sender);
+        }
+
+        let sender = self.connect().await?;
+        *self.sender.lock().unwrap_or_else(PoisonError::into_inner) = Some(sender.clone());
+
+        Ok(sender)
+    }
+
+    /// POST the encoded query and return the body of the response
+    async fn post(&self, query: Bytes) -> Result<Bytes> {
+        let mut sender = self.sender().await?;
+        sender.ready().await.map_err(|e| Http(e.to_string()))?;
+
+        let req = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Full::new(query))
+            .map_err(|e| Http(e.to_string()))?;
+        let res = sender
+            .send_request(req)
+            .await
+            .map_err(|e| Http(e.to_string()))?;
+
+        if res.status() != StatusCode::OK {
+            return Err(Http(format!("upstream answered {}", res.status())));
+        }
+        let body = Limited::new(res.into_body(), MAX_PACKET_SIZE)
+            .collect()
+            .await
+            .map_err(|e| Http(e.to_string()))?;
+
+        Ok(body.to_bytes())
+    }
+
+    /// Send the query and wait for the response that answers it
+    pub fn send(&self, mut query_packet: DNSPacket) -> Result<DNSPacket> {
+        // an ID of 0 lets HTTP caches share responses, RFC 8484 section 4.1
+        query_packet.header.id = 0;
+
+        let mut query_buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        query_packet.write(&mut query_buf)?;
+        let query_bytes = Bytes::copy_from_slice(query_buf.written());
+
+        let res_bytes = self
+            .runtime
+            .block_on(async { timeout(UPSTREAM_TIMEOUT, self.post(query_bytes)).await })
+            .map_err(|_| Http(String::from("upstream timed out")))??;
+
+        let mut res_buf = RawPacket::with_size(res_bytes.len());
+        res_buf.buf.copy_from_slice(&res_bytes);
+        let mut res_packet = DNSPacket::new();
+        res_packet.parse(&mut res_buf)?;
+
+        if !is_valid_response(&query_packet, self.server, &res_packet, self.server) {
+            return Err(Http(String::from("response does not answer the query")));
+        }
+
+        Ok(res_packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, question::QueryType, server::new_query, zone_file::parse_record};
+
+    fn rec(text: &str) -> Record {
+        parse_record(text, 3600).unwrap()
+    }
+
+    /// A server answering api.example.com from a local record
+    fn state() -> Arc<ServerState> {
+        let mut config = Config::default();
+        config
+            .default_view
+            .local_records
+            .push(rec("api.example.com. 60 IN A 192.0.2.5"));
+        Arc::new(ServerState::from_config(&config).unwrap())
+    }
+
+    fn answer(req: Request<Full<Bytes>>) -> Response<Full<Bytes>> {
+        let query_src = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let runtime = Runtime::new().unwrap();
+        runtime
+            .block_on(answer_request(req, query_src, state()))
+            .unwrap()
+    }
+
+    /// The query for api.example.com as it is sent
+    fn query_bytes() -> Vec<u8> {
+        let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        new_query("api.example.com", QueryType::A)
+            .write(&mut buf)
+            .unwrap();
+        buf.written().to_vec()
+    }
+
+    #[test]
+    fn get_takes_base64url_without_padding() {
+        // 0xfb 0xff encodes to characters only base64url has
+        let uri: Uri = "/dns-query?ct&dns=-_8".parse().unwrap();
+        assert_eq!(query_param(&uri), Some(vec![0xfb, 0xff]));
+
+        let padded: Uri = "/dns-query?dns=-_8=".parse().unwrap();
+        assert_eq!(query_param(&padded), None);
+        let standard: Uri = "/dns-query?dns=+/8".parse().unwrap();
+        assert_eq!(query_param(&standard), None);
+        let missing: Uri = "/dns-query?ct".parse().unwrap();
+        assert_eq!(query_param(&missing), None);
+    }
+
+    #[test]
+    fn lifetime_is_the_smallest_ttl() {
+        let mut res_packet = DNSPacket::new();
+        assert_eq!(cache_lifetime(&res_packet), None);
+
+        res_packet.answer_sec = vec![
+            rec("www.example.com. 300 IN CNAME web.example.com."),
+            rec("web.example.com. 60 IN A 192.0.2.1"),
+        ];
+        res_packet
+            .authority_sec
+            .push(rec("example.com. 10 IN NS ns.example.com."));
+        assert_eq!(cache_lifetime(&res_packet), Some(60));
+    }
+
+    #[test]
+    fn negative_lifetime_is_capped_by_the_soa_minimum() {
+        let mut res_packet = DNSPacket::new();
+        res_packet.authority_sec.push(rec(
+            "example.com. 3600 IN SOA ns.example.com. admin.example.com. 1 7200 900 86400 300",
+        ));
+        assert_eq!(cache_lifetime(&res_packet), Some(300));
+
+        res_packet.authority_sec[0] =
+            rec("example.com. 120 IN SOA ns.example.com. admin.example.com. 1 7200 900 86400 300");
+        assert_eq!(cache_lifetime(&res_packet), Some(120));
+    }
+
+    #[test]
+    fn get_and_post_are_answered() {
+        let dns = URL_SAFE_NO_PAD.encode(query_bytes());
+        let get = Request::get(format!("{}?dns={}", PATH, dns))
+            .body(Full::default())
+            .unwrap();
+        let res = answer(get);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CACHE_CONTROL], "max-age=60");
+
+        let post = Request::post(PATH)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query_bytes())))
+            .unwrap();
+        assert_eq!(answer(post).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn bad_requests_get_their_status() {
+        let elsewhere = Request::get("/resolve").body(Full::default()).unwrap();
+        assert_eq!(answer(elsewhere).status(), StatusCode::NOT_FOUND);
+
+        let put = Request::put(PATH)
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .body(Full::new(Bytes::from(query_bytes())))
+            .unwrap();
+        assert_eq!(answer(put).status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let json = Request::post(PATH)
+            .header(CONTENT_TYPE, "application/dns-json")
+            .body(Full::new(Bytes::from(query_bytes())))
+            .unwrap();
+        assert_eq!(answer(json).status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let no_query = Request::get(PATH).body(Full::default()).unwrap();
+        assert_eq!(answer(no_query).status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, EndpointConfig, RecvStream, SendStream, ServerConfig,
+    TokioRuntime, VarInt,
+};
+use rustls::pki_types::ServerName;
+use tokio::{runtime::Runtime, task, time::timeout};
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{
+        Errors::{IOErr, Quic},
+        Result,
+    },
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    server::{is_valid_response, respond, ServerState},
+    tcp::write_message,
+};
+
+pub const QUIC_PORT: u16 = 853; // UDP port DNS over QUIC is served on, RFC 9250
+pub const DOQ_ALPN: &[u8] = b"doq";
+const MAX_STREAM_SIZE: usize = 2 + MAX_PACKET_SIZE; // one length prefixed message
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5); // give up on the upstream after this long
+
+// error codes carried when closing a connection or resetting a stream, RFC 9250 section 8.4
+const DOQ_INTERNAL_ERROR: VarInt = VarInt::from_u32(1);
+const DOQ_PROTOCOL_ERROR: VarInt = VarInt::from_u32(2);
+
+/// The message on a stream, which carries exactly one behind a 2 byte length
+fn unframe(stream: &[u8]) -> Result<RawPacket> {
+    let [high, low, message @ ..] = stream else {
+        return Err(Quic(String::from("stream too short")));
+    };
+    if u16::from_be_bytes([*high, *low]) as usize != message.len() {
+        return Err(Quic(String::from("length does not match the stream")));
+    }
+
+    let mut buf = RawPacket::with_size(message.len());
+    buf.buf.copy_from_slice(message);
+    Ok(buf)
+}
+
+/// The query read from a stream, errors are protocol errors of the client
+fn read_query(stream: &[u8]) -> Result<(RawPacket, DNSPacket)> {
+    let mut query_buf = unframe(stream)?;
+    let mut query_packet = DNSPacket::new();
+    query_packet.parse(&mut query_buf)?;
+
+    // the stream identifies the query, RFC 9250 section 4.2.1
+    if query_packet.header.id != 0 {
+        return Err(Quic(String::from("message ID is not 0")));
+    }
+
+    Ok((query_buf, query_packet))
+}
+
+/// Answer the query, giving the framed responses to write back
+fn answer_query(
+    query_buf: &RawPacket,
+    query_packet: DNSPacket,
+    query_src: SocketAddr,
+    state: &ServerState,
+) -> Result<Vec<u8>> {
+    // a stream holds as many messages as a TCP connection, so transfers go through
+    let mut res = Vec::new();
+    for res_packet in respond(query_buf, query_packet, query_src, state, true)? {
+        write_message(&mut res, &res_packet)?;
+    }
+
+    Ok(res)
+}
+
+/// Answer the query on one stream
+///
+/// A malformed query ends the whole connection, a query we fail to answer only its own stream.
+async fn answer_stream(
+    conn: Connection,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    state: Arc<ServerState>,
+) {
+    let query_src = conn.remote_address();
+    let Ok(query) = recv.read_to_end(MAX_STREAM_SIZE).await else {
+        conn.close(DOQ_PROTOCOL_ERROR, b"");
+        return;
+    };
+    let (query_buf, query_packet) = match read_query(&query) {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("closing QUIC connection from {}: {}", query_src, e);
+            conn.close(DOQ_PROTOCOL_ERROR, b"");
+            return;
+        }
+    };
+
+    // resolving may block on upstream lookups
+    let answer =
+        task::spawn_blocking(move || answer_query(&query_buf, query_packet, query_src, &state))
+            .await;
+    match answer {
+        Ok(Ok(res)) => {
+            if send.write_all(&res).await.is_ok() {
+                let _ = send.finish();
+            }
+        }
+        Ok(Err(e)) => {
+            eprintln!("failed to answer QUIC stream from {}: {}", query_src, e);
+            let _ = send.reset(DOQ_INTERNAL_ERROR);
+        }
+        Err(_) => {
+            let _ = send.reset(DOQ_INTERNAL_ERROR);
+        }
+    }
+}
+
+/// Accept DNS over QUIC connections forever, each query on its own stream, RFC 9250
+///
+/// QUIC runs on its own async runtime, each query is still resolved on a blocking thread.
+pub fn serve_quic(
+    socket: UdpSocket,
+    config: Arc<rustls::ServerConfig>,
+    state: Arc<ServerState>,
+) -> Result<()> {
+    let crypto = QuicServerConfig::try_from(config).map_err(|e| Quic(e.to_string()))?;
+    let runtime = Runtime::new().map_err(IOErr)?;
+
+    runtime.block_on(async {
+        let endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(ServerConfig::with_crypto(Arc::new(crypto))),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .map_err(IOErr)?;
+
+        while let Some(incoming) = endpoint.accept().await {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let conn = match incoming.await {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        eprintln!("failed to handle QUIC connection: {}", e);
+                        return;
+                    }
+                };
+
+                // streams are answered concurrently until the client goes away
+                while let Ok((send, recv)) = conn.accept_bi().await {
+                    tokio::spawn(answer_stream(conn.clone(), send, recv, Arc::clone(&state)));
+                }
+            });
+        }
+
+        Ok(())
+    })
+}
+
+#[derive(Debug)]
+/// A resolver reached over DNS over QUIC, lookups share one connection with a stream each
+pub struct QuicUpstream {
+    pub server: SocketAddr,
+    /// Name the certificate must be valid for
+    name: String,
+    endpoint: Endpoint,
+    /// Drives the QUIC connection between lookups
+    runtime: Runtime,
+    /// Connection left open by the last lookup
+    conn: Mutex<Option<Connection>>,
+}
+
+impl QuicUpstream {
+    pub fn new(server: SocketAddr, name: &str, config: Arc<rustls::ClientConfig>) -> Result<Self> {
+        ServerName::try_from(name).map_err(|_| Quic(format!("invalid server name {}", name)))?;
+        let crypto = QuicClientConfig::try_from(config).map_err(|e| Quic(e.to_string()))?;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(IOErr)?;
+
+        // use the same address family as the server
+        let local = match server {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let mut endpoint = {
+            let _runtime = runtime.enter();
+            Endpoint::client(local).map_err(IOErr)?
+        };
+        endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+
+        Ok(QuicUpstream {
+            server,
+            name: String::from(name),
+            endpoint,
+            runtime,
+            conn: Mutex::new(None),
+        })
+    }
+
+    /// The open connection, or a new one once it was closed
+    async fn connection(&self) -> Result<Connection> {
+        let open = self
+            .conn
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .filter(|conn| conn.close_reason().is_none())
+            .cloned();
+        if let Some(conn) = open {
+            return Ok(conn);
+        }
+
+        let conn = self
+            .endpoint
+            .connect(self.server, &self.name)
+            .map_err(|e| Quic(e.to_string()))?
+            .await
+            .map_err(|e| Quic(e.to_string()))?;
+        *self.conn.lock().unwrap_or_else(PoisonError::into_inner) = Some(conn.clone());
+
+        Ok(conn)
+    }
+
+    /// Send the framed query on a new stream and read everything the server writes back
+    async fn exchange(&self, query: &[u8]) -> Result<Vec<u8>> {
+        let conn = self.connection().await?;
+        let (mut send, mut recv) = conn.open_bi().await.map_err(|e| Quic(e.to_string()))?;
+
+        send.write_all(query)
+            .await
+            .map_err(|e| Quic(e.to_string()))?;
+        send.finish().map_err(|e| Quic(e.to_string()))?;
+
+        recv.read_to_end(MAX_STREAM_SIZE)
+            .await
+            .map_err(|e| Quic(e.to_string()))
+    }
+
+    /// Send the query and wait for the response that answers it
+    pub fn send(&self, mut query_packet: DNSPacket) -> Result<DNSPacket> {
+        // the stream identifies the query, RFC 9250 section 4.2.1
+        query_packet.header.id = 0;
+
+        let mut query_bytes = Vec::new();
+        write_message(&mut query_bytes, &query_packet)?;
+
+        let res_bytes = self
+            .runtime
+            .block_on(async { timeout(UPSTREAM_TIMEOUT, self.exchange(&query_bytes)).await })
+            .map_err(|_| Quic(String::from("upstream timed out")))??;
+
+        let mut res_buf = unframe(&res_bytes)?;
+        let mut res_packet = DNSPacket::new();
+        res_packet.parse(&mut res_buf)?;
+
+        if !is_valid_response(&query_packet, self.server, &res_packet, self.server) {
+            return Err(Quic(String::from("response does not answer the query")));
+        }
+
+        Ok(res_packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{question::QueryType, server::new_query};
+
+    /// The query framed as it is written to a stream
+    fn framed(id: u16) -> Vec<u8> {
+        let mut query_packet = new_query("example.com", QueryType::A);
+        query_packet.header.id = id;
+        let mut stream = Vec::new();
+        write_message(&mut stream, &query_packet).unwrap();
+        stream
+    }
+
+    #[test]
+    fn stream_holds_exactly_one_message() {
+        let stream = framed(0);
+        assert!(unframe(&stream).is_ok());
+
+        let mut longer = stream.clone();
+        longer.push(0);
+        assert!(unframe(&longer).is_err());
+        assert!(unframe(&stream[..stream.len() - 1]).is_err());
+        assert!(unframe(&stream[..1]).is_err());
+    }
+
+    #[test]
+    fn message_id_must_be_0() {
+        let (_, query_packet) = read_query(&framed(0)).unwrap();
+        assert_eq!(query_packet.question_sec[0].name, "example.com");
+
+        assert!(read_query(&framed(1234)).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use super::{
+    acl::Cidr,
+    dns_packet::DNSPacket,
+    errors::Errors,
+    header::ResponseCode,
+    question::QueryType,
+    record::{Record, RecordPreamble},
+};
+
+pub const EDNS_PAYLOAD_SIZE: u16 = 512; // largest UDP message read, the receive buffers are this size
+const MAX_UDP_RESPONSE_SIZE: u16 = 1232; // larger UDP responses risk IP fragmentation (DNS flag day 2020)
+pub const OPTION_CLIENT_SUBNET: u16 = 8; // RFC 7871
+pub const OPTION_COOKIE: u16 = 10; // RFC 7873
+const OPTION_EXTENDED_ERROR: u16 = 15; // RFC 8914
+pub const RCODE_BADCOOKIE: u16 = 23; // RFC 7873, its upper bits go in the OPT record
+const DNSSEC_OK: u32 = 0x8000; // DO flag in the TTL of the OPT record, RFC 3225
+
+// address families of the client subnet option, as numbered by IANA
+const FAMILY_IPV4: u16 = 1;
+const FAMILY_IPV6: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+/// An option carried in the OPT pseudo-record, RFC 6891 section 6.1.2
+pub struct EdnsOption {
+    pub code: u16,
+    pub data: Vec<u8>,
+}
+
+/// An OPT pseudo-record announcing EDNS support along with the options
+pub fn opt_record(options: Vec<EdnsOption>) -> Record {
+    Record::Opt {
+        preamble: RecordPreamble {
+            class: EDNS_PAYLOAD_SIZE,
+            ..RecordPreamble::new("", QueryType::Opt, 0)
+        },
+        options,
+    }
+}
+
+/// Options of the OPT record of the message, None when it does not use EDNS
+pub fn edns_options(packet: &DNSPacket) -> Option<&[EdnsOption]> {
+    packet.additional_sec.iter().find_map(|rec| match rec {
+        Record::Opt { options, .. } => Some(options.as_slice()),
+        _ => None,
+    })
+}
+
+/// Add an option to the OPT record of the message, adding the record first if there is none
+pub fn add_option(packet: &mut DNSPacket, option: EdnsOption) {
+    let options = packet.additional_sec.iter_mut().find_map(|rec| match rec {
+        Record::Opt { options, .. } => Some(options),
+        _ => None,
+    });
+    match options {
+        Some(options) => options.push(option),
+        None => packet.additional_sec.push(opt_record(vec![option])),
+    }
+}
+
+/// If the sender of the message wants DNSSEC records, the DO bit of its OPT record
+pub fn dnssec_ok(packet: &DNSPacket) -> bool {
+    packet.additional_sec.iter().any(|rec| match rec {
+        Record::Opt { preamble, .. } => preamble.ttl & DNSSEC_OK > 0,
+        _ => false,
+    })
+}
+
+/// Set the DO bit of the OPT record, asking for DNSSEC records or telling the client they are
+/// included
+pub fn set_dnssec_ok(packet: &mut DNSPacket) {
+    for rec in &mut packet.additional_sec {
+        if let Record::Opt { preamble, .. } = rec {
+            preamble.ttl |= DNSSEC_OK;
+        }
+    }
+}
+
+/// Largest UDP response the sender of the query takes, RFC 6891 section 6.2.5
+pub fn udp_payload_size(query_packet: &DNSPacket) -> usize {
+    let size = query_packet
+        .additional_sec
+        .iter()
+        .find_map(|rec| match rec {
+            Record::Opt { preamble, .. } => Some(preamble.class),
+            _ => None,
+        })
+        .unwrap_or(EDNS_PAYLOAD_SIZE);
+
+    size.clamp(EDNS_PAYLOAD_SIZE, MAX_UDP_RESPONSE_SIZE) as usize
+}
+
+/// The full response code, its upper 8 bits are kept in the OPT record, RFC 6891 section 6.1.3
+pub fn extended_rcode(packet: &DNSPacket) -> u16 {
+    let upper = packet
+        .additional_sec
+        .iter()
+        .find_map(|rec| match rec {
+            Record::Opt { preamble, .. } => Some(preamble.ttl >> 24),
+            _ => None,
+        })
+        .unwrap_or(0);
+
+    (upper as u16) << 4 | packet.header.rcode.to_num() as u16
+}
+
+/// Set the full response code, adding an OPT record for its upper bits if there is none
+pub fn set_extended_rcode(packet: &mut DNSPacket, rcode: u16) {
+    packet.header.rcode = ResponseCode::from_num((rcode & 0xf) as u8);
+    if edns_options(packet).is_none() {
+        packet.additional_sec.push(opt_record(Vec::new()));
+    }
+    for rec in &mut packet.additional_sec {
+        if let Record::Opt { preamble, .. } = rec {
+            preamble.ttl = preamble.ttl & 0x00ff_ffff | ((rcode >> 4) as u32) << 24;
+        }
+    }
+}
+
+/// Drop the OPT record of a message, it only describes the hop the message came over
+pub fn strip_opt(packet: &mut DNSPacket) {
+    packet
+        .additional_sec
+        .retain(|rec| !matches!(rec, Record::Opt { .. }));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The EDNS Client Subnet option, RFC 7871
+pub struct ClientSubnet {
+    /// Leading bits of the client address given out
+    pub source: Cidr,
+    /// Leading bits the answer is valid for, 0 in queries
+    pub scope_prefix: u8,
+}
+
+impl ClientSubnet {
+    /// Read the option data, None when it is malformed
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let [family_high, family_low, source_prefix, scope_prefix, addr @ ..] = data else {
+            return None;
+        };
+        let source_prefix = *source_prefix;
+        // the address is cut down to the bytes the source prefix covers, section 6
+        if addr.len() != (source_prefix as usize).div_ceil(8) {
+            return None;
+        }
+
+        let ip = match u16::from_be_bytes([*family_high, *family_low]) {
+            FAMILY_IPV4 if source_prefix <= 32 => {
+                let mut octets = [0; 4];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            FAMILY_IPV6 if source_prefix <= 128 => {
+                let mut octets = [0; 16];
+                octets[..addr.len()].copy_from_slice(addr);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+
+        Some(ClientSubnet {
+            source: Cidr::around(ip, source_prefix),
+            scope_prefix: *scope_prefix,
+        })
+    }
+
+    /// The client subnet option of the message, if it carries a well formed one
+    pub fn from_packet(packet: &DNSPacket) -> Option<Self> {
+        edns_options(packet)?
+            .iter()
+            .find(|option| option.code == OPTION_CLIENT_SUBNET)
+            .and_then(|option| Self::parse(&option.data))
+    }
+
+    /// Encode the option with the address cut down to the source prefix
+    pub fn to_option(self) -> EdnsOption {
+        // bits past the prefix must be zero, section 6
+        let source = Cidr::around(self.source.addr(), self.source.prefix());
+        let (family, octets) = match source.addr() {
+            IpAddr::V4(ip) => (FAMILY_IPV4, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (FAMILY_IPV6, ip.octets().to_vec()),
+        };
+        let prefix = source.prefix();
+
+        let mut data = family.to_be_bytes().to_vec();
+        data.extend([prefix, self.scope_prefix]);
+        data.extend(&octets[..(prefix as usize).div_ceil(8)]);
+
+        EdnsOption {
+            code: OPTION_CLIENT_SUBNET,
+            data,
+        }
+    }
+
+    /// If a response option answers for this subnet, the same source echoed back
+    pub fn is_echoed_by(&self, res: &ClientSubnet) -> bool {
+        self.source == res.source
+    }
+}
+
+#[derive(Debug, Clone)]
+/// How much of the client address is given to upstream servers
+pub struct ClientSubnetConfig {
+    /// Longest IPv4 prefix sent, 0 asks the upstream not to tailor answers at all
+    pub ipv4_prefix_length: u8,
+    /// Longest IPv6 prefix sent
+    pub ipv6_prefix_length: u8,
+}
+
+impl Default for ClientSubnetConfig {
+    fn default() -> Self {
+        // the lengths RFC 7871 section 11.1 recommends for privacy
+        ClientSubnetConfig {
+            ipv4_prefix_length: 24,
+            ipv6_prefix_length: 56,
+        }
+    }
+}
+
+impl ClientSubnetConfig {
+    /// The subnet sent upstream for a client, the one it asked for itself if it sent an option
+    pub fn subnet_for(&self, client: IpAddr, requested: Option<&ClientSubnet>) -> ClientSubnet {
+        let (addr, prefix) = match requested {
+            Some(requested) => (requested.source.addr(), requested.source.prefix()),
+            None => (client.to_canonical(), u8::MAX),
+        };
+        let cap = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix_length,
+            IpAddr::V6(_) => self.ipv6_prefix_length,
+        };
+
+        ClientSubnet {
+            source: Cidr::around(addr, prefix.min(cap)),
+            scope_prefix: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Info codes of Extended DNS Errors, RFC 8914 section 4
+pub enum InfoCode {
+    Other,
+    StaleAnswer,
+    NotReady,
+    Blocked,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+}
+
+impl InfoCode {
+    fn to_num(self) -> u16 {
+        match self {
+            Self::Other => 0,
+            Self::StaleAnswer => 3,
+            Self::NotReady => 14,
+            Self::Blocked => 15,
+            Self::Prohibited => 18,
+            Self::StaleNxdomainAnswer => 19,
+            Self::NotAuthoritative => 20,
+            Self::NoReachableAuthority => 22,
+            Self::NetworkError => 23,
+            Self::InvalidData => 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An Extended DNS Error telling the client why it got the response it did, RFC 8914
+pub struct ExtendedError {
+    pub code: InfoCode,
+    /// Explanation meant for whoever is debugging, not for the client software
+    pub text: String,
+}
+
+impl ExtendedError {
+    pub fn new(code: InfoCode, text: &str) -> Self {
+        ExtendedError {
+            code,
+            text: String::from(text),
+        }
+    }
+
+    pub fn to_option(&self) -> EdnsOption {
+        let mut data = self.code.to_num().to_be_bytes().to_vec();
+        data.extend(self.text.as_bytes());
+
+        EdnsOption {
+            code: OPTION_EXTENDED_ERROR,
+            data,
+        }
+    }
+}
+
+impl From<&Errors> for ExtendedError {
+    /// Why a lookup failed, in words that give away no upstream or internal detail
+    fn from(error: &Errors) -> Self {
+        let (code, text) = match error {
+            // a read timeout reads as "resource temporarily unavailable" otherwise
+            Errors::IOErr(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                (InfoCode::NoReachableAuthority, "no response in time")
+            }
+            Errors::IOErr(_) | Errors::Tls(_) | Errors::Http(_) | Errors::Quic(_) => {
+                (InfoCode::NetworkError, "upstream could not be reached")
+            }
+            Errors::Resolve(_) => (InfoCode::NoReachableAuthority, "no authority answered"),
+            // the response could not be read
+            Errors::BufferEnd
+            | Errors::BufferOverflow
+            | Errors::InvalidLabelLen
+            | Errors::JumpCycle
+            | Errors::RangeErr
+            | Errors::CaseMismatch => (InfoCode::InvalidData, "malformed upstream response"),
+            _ => (InfoCode::Other, "lookup failed"),
+        };
+
+        ExtendedError::new(code, text)
+    }
+}
+
+/// Extended DNS Error options of the message, as they are passed on from an upstream
+pub fn extended_errors(packet: &DNSPacket) -> Vec<EdnsOption> {
+    edns_options(packet)
+        .unwrap_or_default()
+        .iter()
+        .filter(|option| option.code == OPTION_EXTENDED_ERROR)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnet(block: &str) -> ClientSubnet {
+        ClientSubnet {
+            source: Cidr::parse(block).unwrap(),
+            scope_prefix: 0,
+        }
+    }
+
+    #[test]
+    fn address_is_cut_down_to_the_source_prefix() {
+        let option = subnet("192.0.2.0/20").to_option();
+        assert_eq!(option.code, OPTION_CLIENT_SUBNET);
+        assert_eq!(option.data, [0, 1, 20, 0, 192, 0, 0]);
+
+        let option = subnet("2001:db8:aaaa::/33").to_option();
+        assert_eq!(option.data, [0, 2, 33, 0, 0x20, 0x01, 0x0d, 0xb8, 0x80]);
+
+        assert_eq!(
+            ClientSubnet::parse(&[0, 1, 24, 16, 192, 0, 2]),
+            Some(ClientSubnet {
+                source: Cidr::parse("192.0.2.0/24").unwrap(),
+                scope_prefix: 16,
+            })
+        );
+        // bits past the source prefix are not kept
+        assert_eq!(
+            ClientSubnet::parse(&[0, 1, 20, 0, 192, 0, 0xff]).map(|subnet| subnet.source),
+            Cidr::parse("192.0.240.0/20")
+        );
+    }
+
+    #[test]
+    fn address_length_must_match_the_source_prefix() {
+        assert_eq!(ClientSubnet::parse(&[0, 1, 24, 0, 192, 0]), None);
+        assert_eq!(ClientSubnet::parse(&[0, 1, 24, 0, 192, 0, 2, 1]), None);
+        assert_eq!(ClientSubnet::parse(&[0, 1, 33, 0, 192, 0, 2, 1, 0]), None);
+        assert_eq!(ClientSubnet::parse(&[0, 3, 0, 0]), None);
+        assert_eq!(ClientSubnet::parse(&[0, 1, 0]), None);
+        assert!(ClientSubnet::parse(&[0, 1, 0, 0]).is_some());
+    }
+
+    #[test]
+    fn prefix_sent_is_capped_for_privacy() {
+        let config = ClientSubnetConfig::default();
+
+        let sent = config.subnet_for("192.0.2.77".parse().unwrap(), None);
+        assert_eq!(sent.source, Cidr::parse("192.0.2.0/24").unwrap());
+        let sent = config.subnet_for("2001:db8:1:2:3::1".parse().unwrap(), None);
+        assert_eq!(sent.source, Cidr::parse("2001:db8:1::/56").unwrap());
+        // a mapped IPv4 client is an IPv4 client
+        let sent = config.subnet_for("::ffff:192.0.2.77".parse().unwrap(), None);
+        assert_eq!(sent.source, Cidr::parse("192.0.2.0/24").unwrap());
+
+        // a client may ask for less to be sent, not more
+        let client = "198.51.100.1".parse().unwrap();
+        let sent = config.subnet_for(client, Some(&subnet("192.0.2.0/16")));
+        assert_eq!(sent.source, Cidr::parse("192.0.0.0/16").unwrap());
+        let sent = config.subnet_for(client, Some(&subnet("192.0.2.1/32")));
+        assert_eq!(sent.source, Cidr::parse("192.0.2.0/24").unwrap());
+        assert_eq!(sent.scope_prefix, 0);
+    }
+
+    #[test]
+    fn errors_map_to_fixed_info_codes() {
+        let mapped = |error: Errors| {
+            let error = ExtendedError::from(&error);
+            (error.code, error.text)
+        };
+
+        for kind in [ErrorKind::TimedOut, ErrorKind::WouldBlock] {
+            assert_eq!(
+                mapped(Errors::IOErr(kind.into())),
+                (
+                    InfoCode::NoReachableAuthority,
+                    String::from("no response in time")
+                )
+            );
+        }
+        for error in [
+            Errors::IOErr(ErrorKind::ConnectionRefused.into()),
+            Errors::Tls(String::from("certificate for 192.0.2.1 expired")),
+            Errors::Http(String::from("status 502")),
+            Errors::Quic(String::from("handshake failed")),
+        ] {
+            assert_eq!(
+                mapped(error),
+                (
+                    InfoCode::NetworkError,
+                    String::from("upstream could not be reached")
+                )
+            );
+        }
+        // the upstream named in the error is not given away
+        assert_eq!(
+            mapped(Errors::Resolve(String::from(
+                "192.0.2.1 keeps rejecting our cookie"
+            ))),
+            (
+                InfoCode::NoReachableAuthority,
+                String::from("no authority answered")
+            )
+        );
+        for error in [Errors::BufferEnd, Errors::JumpCycle, Errors::CaseMismatch] {
+            assert_eq!(
+                mapped(error),
+                (
+                    InfoCode::InvalidData,
+                    String::from("malformed upstream response")
+                )
+            );
+        }
+        assert_eq!(
+            mapped(Errors::Tsig(String::from("BADSIG"))),
+            (InfoCode::Other, String::from("lookup failed"))
+        );
+    }
+
+    #[test]
+    fn extended_error_option_is_code_then_text() {
+        let option = ExtendedError::new(InfoCode::Prohibited, "no").to_option();
+        assert_eq!(option.code, OPTION_EXTENDED_ERROR);
+        assert_eq!(option.data, [0, 18, b'n', b'o']);
+    }
+}
@@ -8,10 +8,19 @@ use std::{
 pub enum Errors {
     BufferEnd,
     BufferOverflow,
+    CaseMismatch,
+    Config(String),
+    Http(String),
     IOErr(io::Error),
     InvalidLabelLen,
     JumpCycle,
+    Quic(String),
     RangeErr,
+    Resolve(String),
+    Tls(String),
+    Transfer(String),
+    Tsig(String),
+    ZoneFile(String),
 }
 pub type Result<T> = result::Result<T, Errors>;
 
@@ -20,10 +29,19 @@ impl Display for Errors {
         match self {
             Self::BufferEnd => write!(f, "buffer end reached"),
             Self::BufferOverflow => write!(f, "buffer overflow"),
+            Self::CaseMismatch => write!(f, "response did not echo query name case"),
+            Self::Config(msg) => write!(f, "invalid configuration: {}", msg),
+            Self::Http(msg) => write!(f, "HTTP failed: {}", msg),
             Self::IOErr(e) => write!(f, "{}", e),
             Self::InvalidLabelLen => write!(f, "label exceeds 63 characters"),
             Self::RangeErr => write!(f, "invalid range"),
+            Self::Resolve(msg) => write!(f, "lookup failed: {}", msg),
             Self::JumpCycle => write!(f, "max number of jumps exceeded"),
+            Self::Quic(msg) => write!(f, "QUIC failed: {}", msg),
+            Self::Tls(msg) => write!(f, "TLS failed: {}", msg),
+            Self::Transfer(msg) => write!(f, "zone transfer failed: {}", msg),
+            Self::Tsig(msg) => write!(f, "message authentication failed: {}", msg),
+            Self::ZoneFile(msg) => write!(f, "invalid zone file: {}", msg),
         }
     }
 }
@@ -1,6 +1,6 @@
 use super::{errors::Result, raw_packet::RawPacket};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 /// Represent the response code of the packet
 pub enum ResponseCode {
     Noerror,
@@ -9,21 +9,31 @@ pub enum ResponseCode {
     Nxdomain,
     Notimp,
     Refused,
+    Yxdomain,
+    Yxrrset,
+    Nxrrset,
+    Notauth,
+    Notzone,
 }
 
 impl ResponseCode {
-    fn from_num(code: u8) -> Self {
+    pub fn from_num(code: u8) -> Self {
         match code {
             1 => Self::Formerr,
             2 => Self::Servfail,
             3 => Self::Nxdomain,
             4 => Self::Notimp,
             5 => Self::Refused,
+            6 => Self::Yxdomain,
+            7 => Self::Yxrrset,
+            8 => Self::Nxrrset,
+            9 => Self::Notauth,
+            10 => Self::Notzone,
             _ => Self::Noerror,
         }
     }
 
-    fn to_num(&self) -> u8 {
+    pub fn to_num(self) -> u8 {
         match self {
             Self::Noerror => 0,
             Self::Formerr => 1,
@@ -31,29 +41,67 @@ impl ResponseCode {
             Self::Nxdomain => 3,
             Self::Notimp => 4,
             Self::Refused => 5,
+            Self::Yxdomain => 6,
+            Self::Yxrrset => 7,
+            Self::Nxrrset => 8,
+            Self::Notauth => 9,
+            Self::Notzone => 10,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Represent the kind of operation the packet carries
+pub enum Opcode {
+    Query,
+    Notify,
+    Update,
+    Unknown(u8),
+}
+
+impl Opcode {
+    fn from_num(code: u8) -> Self {
+        match code {
+            0 => Self::Query,
+            4 => Self::Notify,
+            5 => Self::Update,
+            _ => Self::Unknown(code),
+        }
+    }
+
+    fn to_num(self) -> u8 {
+        match self {
+            Self::Query => 0,
+            Self::Notify => 4,
+            Self::Update => 5,
+            Self::Unknown(code) => code,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 /// DNS Header stores meta information about the packet
 pub struct Header {
     /// Random identifier assigned to query packets. Response packets must reply wth same id
     pub id: u16, // 16 bits
     /// If packet is a query
     pub qr: bool, // 1 bit
-    /// Operation Code, usually always 0
-    op_code: u8, // 4 bits
+    /// Operation Code, usually a standard query
+    pub op_code: Opcode, // 4 bits
     /// If responding server is authoritative
-    aa: bool, // 1 bit
+    pub aa: bool, // 1 bit
     /// If it is a truncated message (original packet exceeds 512 bytes)
-    tc: bool, // 1 bit
+    pub tc: bool, // 1 bit
     /// If server should attempt recursive resolution
     pub rd: bool, // 1 bit
     /// If server can satisfy recursive queries
     pub ra: bool, // 1 bit
-    /// Used for DNSSEC queries
-    z: u8, // 3 bits
+    /// Reserved, must be zero
+    z: bool, // 1 bit
+    /// If the data of the answer and authority sections was validated with DNSSEC, RFC 4035
+    pub ad: bool, // 1 bit
+    /// If the sender does not want the data validated
+    pub cd: bool, // 1 bit
     /// Response code
     pub rcode: ResponseCode, // 4 bits
     /// Number of entries in Question Section
@@ -72,12 +120,14 @@ impl Header {
         Self {
             id: 0,
             qr: false,
-            op_code: 0,
+            op_code: Opcode::Query,
             aa: false,
             tc: false,
             rd: false,
             ra: false,
-            z: 0,
+            z: false,
+            ad: false,
+            cd: false,
             rcode: ResponseCode::Noerror,
             qd_count: 0,
             an_count: 0,
@@ -92,14 +142,16 @@ impl Header {
 
         let byte1 = buf.read_u8()?;
         self.qr = byte1 & 0b1000_0000 > 0;
-        self.op_code = (byte1 & 0b0111_1000) >> 3;
+        self.op_code = Opcode::from_num((byte1 & 0b0111_1000) >> 3);
         self.aa = byte1 & 0b0000_0100 > 0;
         self.tc = byte1 & 0b0000_0010 > 0;
         self.rd = byte1 & 0b0000_0001 > 0;
 
         let byte2 = buf.read_u8()?;
         self.ra = byte2 & 0b1000_0000 > 0;
-        self.z = byte2 & 0b0111_0000 >> 4;
+        self.z = byte2 & 0b0100_0000 > 0;
+        self.ad = byte2 & 0b0010_0000 > 0;
+        self.cd = byte2 & 0b0001_0000 > 0;
         self.rcode = ResponseCode::from_num(byte2 & 0b0000_1111);
 
         self.qd_count = buf.read_u16()?;
@@ -115,12 +167,18 @@ impl Header {
         buf.write_u16(self.id)?;
         buf.write_u8(
             ((self.qr as u8) << 7)
-                | (self.op_code << 3)
+                | (self.op_code.to_num() << 3)
                 | ((self.aa as u8) << 2)
                 | ((self.tc as u8) << 1)
                 | (self.rd as u8),
         )?;
-        buf.write_u8(((self.ra as u8) << 7) | (self.z << 4) | self.rcode.to_num())?;
+        buf.write_u8(
+            ((self.ra as u8) << 7)
+                | ((self.z as u8) << 6)
+                | ((self.ad as u8) << 5)
+                | ((self.cd as u8) << 4)
+                | self.rcode.to_num(),
+        )?;
         buf.write_u16(self.qd_count)?;
         buf.write_u16(self.an_count)?;
         buf.write_u16(self.ns_count)?;
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, net::Ipv4Addr, sync::Arc};
+
+use super::{
+    dns_packet::DNSPacket,
+    edns::ClientSubnet,
+    question::{QueryType, Question},
+    record::{Record, RecordPreamble},
+    server::{lookup, LookupOptions},
+    zone::labels,
+};
+
+pub const LOCAL_TTL: u32 = 300; // TTL of local records written without one
+const MAX_CNAME_CHAIN: usize = 8; // aliases followed before giving up on a loop
+
+/// Name a PTR for the address is found at
+pub fn reverse_name(ip: Ipv4Addr) -> String {
+    let [a, b, c, d] = ip.octets();
+    format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+}
+
+#[derive(Debug, Default)]
+/// Static records answered before forwarding, shadowing whatever upstream has for their names
+pub struct LocalRecords {
+    /// Records by owner, keyed by lowercase labels from the root
+    names: HashMap<Vec<String>, Vec<Record>>,
+    /// Records answering for their owner and every name below it
+    subtrees: HashMap<Vec<String>, Vec<Record>>,
+}
+
+impl LocalRecords {
+    /// Index the records, adding a PTR for each A record when asked to
+    pub fn new(records: &[Record], subtrees: &[Record], ptr: bool) -> Self {
+        let mut local = LocalRecords::default();
+
+        for rec in records {
+            let owner = labels(&rec.preamble().name);
+            local.names.entry(owner).or_default().push(rec.clone());
+        }
+        for rec in subtrees {
+            let owner = labels(&rec.preamble().name);
+            local.subtrees.entry(owner).or_default().push(rec.clone());
+        }
+
+        if ptr {
+            for rec in records.iter().chain(subtrees) {
+                if let Record::A { preamble, ip } = rec {
+                    let name = reverse_name(*ip);
+                    local
+                        .names
+                        .entry(labels(&name))
+                        .or_default()
+                        .push(Record::Ptr {
+                            preamble: RecordPreamble::new(&name, QueryType::Ptr, preamble.ttl),
+                            name: preamble.name.clone(),
+                        });
+                }
+            }
+        }
+
+        local
+    }
+
+    /// Records owned by the name, or by the closest subtree above it taken over by the name
+    fn find(&self, name: &str) -> Option<Vec<Record>> {
+        let name_labels = labels(name);
+        if let Some(records) = self.names.get(&name_labels) {
+            return Some(records.clone());
+        }
+
+        let records = (0..=name_labels.len())
+            .rev()
+            .find_map(|len| self.subtrees.get(&name_labels[..len]))?;
+        Some(
+            records
+                .iter()
+                .map(|rec| {
+                    let mut rec = rec.clone();
+                    rec.preamble_mut().name = String::from(name);
+                    rec
+                })
+                .collect(),
+        )
+    }
+
+    /// Answer the question from the local records, false when none cover its name
+    ///
+    /// A local name with no records of the type asked for gets an empty answer, so the
+    /// upstream data is shadowed too. Aliases leading out of the local records are followed
+    /// upstream.
+    pub fn answer(
+        &self,
+        que: &Question,
+        res_packet: &mut DNSPacket,
+        subnet: Option<&ClientSubnet>,
+        options: &Arc<LookupOptions>,
+    ) -> bool {
+        let Some(mut records) = self.find(&que.name) else {
+            return false;
+        };
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matching: Vec<Record> = records
+                .iter()
+                .filter(|rec| rec.preamble().query_type == que.query_type)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                res_packet.answer_sec.extend(matching);
+                return true;
+            }
+
+            let Some(cname @ Record::Cname { name: target, .. }) = records
+                .iter()
+                .find(|rec| matches!(rec, Record::Cname { .. }))
+            else {
+                return true;
+            };
+            res_packet.answer_sec.push(cname.clone());
+
+            match self.find(target) {
+                Some(next) => records = next,
+                None => {
+                    if let Ok(result) = lookup(target, que.query_type.clone(), subnet, options) {
+                        res_packet.header.rcode = result.header.rcode;
+                        res_packet.answer_sec.extend(result.answer_sec);
+                    }
+                    return true;
+                }
+            }
+        }
+
+        true
+    }
+}
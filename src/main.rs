@@ -1,17 +1,53 @@
+mod acl;
+mod blocklist;
+mod cache;
+mod config;
+mod cookie;
+mod dns64;
 mod dns_packet;
+mod doh;
+mod doq;
+mod edns;
 mod errors;
 mod header;
+mod local;
+mod nsec;
 mod question;
 mod raw_packet;
 mod record;
+mod rrl;
+mod secondary;
 mod server;
+mod tcp;
+mod tls;
+mod transfer;
+mod tsig;
+mod update;
+mod zone;
+mod zone_file;
 
-use errors::Result;
-use question::QueryType;
-use server::lookup;
+use std::{env, path::Path};
+
+use config::Config;
+use errors::{Errors::Config as ConfigErr, Result};
+use server::{serve, ServerState};
 
 fn main() -> Result<()> {
-    let _packet = lookup("", QueryType::A)?;
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let mut config = match args.iter().position(|arg| arg == "--config") {
+        Some(pos) => {
+            let path = args
+                .get(pos + 1)
+                .ok_or_else(|| ConfigErr(String::from("--config needs a file")))?;
+            Config::load(Path::new(path))?
+        }
+        None => Config::default(),
+    };
+
+    if args.iter().any(|arg| arg == "--randomize-case") {
+        config.randomize_case = true;
+    }
 
-    Ok(())
+    serve(ServerState::from_config(&config)?)
 }
@@ -0,0 +1,614 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use super::{
+    dns_packet::DNSPacket,
+    header::ResponseCode::{self, Noerror, Nxdomain},
+    question::QueryType,
+    record::Record,
+    zone::labels,
+};
+
+const MAX_DENIALS: usize = 10_000; // NSEC and NSEC3 records kept across every zone
+const MAX_NSEC3_ITERATIONS: u16 = 100; // more cost too much to hash for every query, RFC 9276 3.2
+const NSEC3_SHA1: u8 = 1; // the only NSEC3 hash algorithm, RFC 5155 section 11
+const NSEC3_OPT_OUT: u8 = 0b0000_0001; // flag bit of ranges that skip unsigned delegations
+const RRSIG_SIGNER_OFFSET: usize = 18; // the signer name follows the fixed fields, RFC 4034 3.1
+
+/// Type covered, labels of the signed owner name and signer name of an RRSIG record, RFC 4034
+/// section 3.1
+fn rrsig_fields(rec: &Record) -> Option<(u16, u8, Vec<String>)> {
+    let Record::Unknown { preamble, data } = rec else {
+        return None;
+    };
+    if preamble.query_type != QueryType::Rrsig {
+        return None;
+    }
+    let covered = u16::from_be_bytes([*data.first()?, *data.get(1)?]);
+    let label_count = *data.get(3)?;
+
+    // the signer name is never compressed
+    let mut signer = Vec::new();
+    let mut pos = RRSIG_SIGNER_OFFSET;
+    loop {
+        let len = *data.get(pos)? as usize;
+        if len == 0 {
+            break;
+        }
+        let label = data.get(pos + 1..pos + 1 + len)?;
+        signer.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len + 1;
+    }
+    signer.reverse();
+
+    Some((covered, label_count, signer))
+}
+
+/// The domain name of labels as returned by `labels`
+fn name_of(name_labels: &[String]) -> String {
+    let mut name_labels = name_labels.to_vec();
+    name_labels.reverse();
+    name_labels.join(".")
+}
+
+/// Hash of a name as NSEC3 owner names are made, RFC 5155 section 5
+fn nsec3_hash(name_labels: &[String], salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name_labels.iter().rev() {
+        wire.push(label.len() as u8);
+        wire.extend(label.as_bytes());
+    }
+    wire.push(0);
+
+    let mut hash = Sha1::new()
+        .chain_update(&wire)
+        .chain_update(salt)
+        .finalize();
+    for _ in 0..iterations {
+        hash = Sha1::new().chain_update(hash).chain_update(salt).finalize();
+    }
+
+    hash.to_vec()
+}
+
+/// Decode the Base32 encoding with extended hex alphabet NSEC3 owner names are written in
+fn base32hex_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.chars() {
+        bits = bits << 5 | c.to_digit(32)?;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+/// If the types of an NSEC or NSEC3 record mark its owner as a zone cut of a child zone or a
+/// DNAME, names below it are not denied by the parent
+fn is_cut(types: &[u16]) -> bool {
+    let has = |query_type: QueryType| types.contains(&query_type.to_num());
+    has(QueryType::NS) && !has(QueryType::Soa) || has(QueryType::Dname)
+}
+
+#[derive(Debug)]
+/// A record set from a validated response along with the RRSIG records covering it
+struct Signed {
+    records: Vec<Record>,
+    signatures: Vec<Record>,
+    expires: Instant,
+}
+
+impl Signed {
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.expires > now
+    }
+
+    /// The records and signatures owned by the name, each TTL counted down to the expiry
+    fn records_for(&self, name: Option<&str>, now: Instant) -> Vec<Record> {
+        let left = self.expires.duration_since(now).as_secs() as u32;
+        let mut records: Vec<Record> = self
+            .records
+            .iter()
+            .chain(&self.signatures)
+            .cloned()
+            .collect();
+        for rec in &mut records {
+            let preamble = rec.preamble_mut();
+            preamble.ttl = preamble.ttl.min(left);
+            if let Some(name) = name {
+                preamble.name = String::from(name);
+            }
+        }
+
+        records
+    }
+
+    /// If an NSEC3 record leaves unsigned delegations out of its range, RFC 5155 section 6
+    fn is_opt_out(&self) -> bool {
+        matches!(self.records.first(), Some(Record::Nsec3 { flags, .. }) if flags & NSEC3_OPT_OUT > 0)
+    }
+
+    /// The types listed by an NSEC or NSEC3 record
+    fn types(&self) -> &[u16] {
+        match self.records.first() {
+            Some(Record::Nsec { types, .. } | Record::Nsec3 { types, .. }) => types,
+            _ => &[],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// What is known of one signed zone
+struct SignedZone {
+    soa: Option<Signed>,
+    /// NSEC records by owner name in canonical order, RFC 4034 section 6.1
+    nsec: BTreeMap<Vec<String>, Signed>,
+    /// NSEC3 records by the hash in their owner name
+    nsec3: BTreeMap<Vec<u8>, Signed>,
+    /// Record sets of wildcards by name and type, learned from answers expanded from them
+    wildcards: HashMap<(Vec<String>, u16), Signed>,
+}
+
+impl SignedZone {
+    fn len(&self) -> usize {
+        self.nsec.len() + self.nsec3.len() + self.wildcards.len()
+    }
+
+    fn retain_fresh(&mut self, now: Instant) {
+        self.nsec.retain(|_, signed| signed.is_fresh(now));
+        self.nsec3.retain(|_, signed| signed.is_fresh(now));
+        self.wildcards.retain(|_, signed| signed.is_fresh(now));
+    }
+
+    /// A response made up of kept record sets, None when the SOA a negative one needs has expired
+    fn response(
+        &self,
+        rcode: ResponseCode,
+        answer: Vec<Record>,
+        proofs: &[&Signed],
+        now: Instant,
+    ) -> Option<DNSPacket> {
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.rcode = rcode;
+        // made of records the upstream validated
+        res_packet.header.ad = true;
+        if answer.is_empty() {
+            let soa = self.soa.as_ref().filter(|soa| soa.is_fresh(now))?;
+            // the negative answer is cached by the SOA, it lasts no longer than its proof
+            let expires = proofs
+                .iter()
+                .map(|proof| proof.expires)
+                .min()
+                .unwrap_or(now);
+            let left = expires.duration_since(now).as_secs() as u32;
+            for mut rec in soa.records_for(None, now) {
+                let preamble = rec.preamble_mut();
+                preamble.ttl = preamble.ttl.min(left);
+                res_packet.authority_sec.push(rec);
+            }
+        }
+        res_packet.answer_sec = answer;
+
+        let mut added: Vec<&Signed> = Vec::new();
+        for proof in proofs {
+            if !added.iter().any(|signed| std::ptr::eq(*signed, *proof)) {
+                res_packet
+                    .authority_sec
+                    .extend(proof.records_for(None, now));
+                added.push(proof);
+            }
+        }
+
+        Some(res_packet)
+    }
+
+    /// The fresh NSEC record owned by the name or covering it, and whether it is owned by it
+    fn nsec_for(&self, name: &[String], now: Instant) -> Option<(&Signed, bool)> {
+        let (owner, signed) = self
+            .nsec
+            .range::<[String], _>((Bound::Unbounded, Bound::Included(name)))
+            .next_back()?;
+        let Some(Record::Nsec { next, .. }) = signed.records.first() else {
+            return None;
+        };
+        if !signed.is_fresh(now) {
+            return None;
+        }
+        if owner == name {
+            return Some((signed, true));
+        }
+
+        // a record owned by an ancestor only covers the name if the ancestor is in this zone
+        if name.starts_with(owner) && is_cut(signed.types()) {
+            return None;
+        }
+        // the last record of the zone wraps around to the apex
+        let next = labels(next);
+        (next.as_slice() > name || next <= *owner).then_some((signed, false))
+    }
+
+    /// Answer from NSEC records, RFC 8198 sections 5.1 to 5.3
+    fn synthesize_nsec(
+        &self,
+        name: &[String],
+        query_type: &QueryType,
+        now: Instant,
+    ) -> Option<DNSPacket> {
+        let (signed, owned) = self.nsec_for(name, now)?;
+        let lacks = |types: &[u16]| {
+            !types.contains(&query_type.to_num()) && !types.contains(&QueryType::Cname.to_num())
+        };
+
+        if owned {
+            if !lacks(signed.types()) || is_cut(signed.types()) {
+                return None;
+            }
+            return self.response(Noerror, Vec::new(), &[signed], now);
+        }
+
+        // the closest encloser is the longest ancestor shared with either end of the range
+        let Some(Record::Nsec { preamble, next, .. }) = signed.records.first() else {
+            return None;
+        };
+        let owner = labels(&preamble.name);
+        let common = |other: &[String]| name.iter().zip(other).take_while(|(a, b)| a == b).count();
+        let encloser = common(&owner).max(common(&labels(next)));
+        // names below it exist, the name is an empty non-terminal without any records
+        if encloser == name.len() {
+            return self.response(Noerror, Vec::new(), &[signed], now);
+        }
+        let wildcard = [&name[..encloser], &[String::from("*")]].concat();
+
+        let (wildcard_signed, wildcard_owned) = self.nsec_for(&wildcard, now)?;
+        if !wildcard_owned {
+            return self.response(Nxdomain, Vec::new(), &[signed, wildcard_signed], now);
+        }
+        if lacks(wildcard_signed.types()) {
+            return self.response(Noerror, Vec::new(), &[signed, wildcard_signed], now);
+        }
+
+        // the wildcard has the type, its records are expanded to the name, RFC 4592
+        let records = self
+            .wildcards
+            .get(&(wildcard, query_type.to_num()))
+            .filter(|records| records.is_fresh(now))?;
+        let answer = records.records_for(Some(&name_of(name)), now);
+        self.response(Noerror, answer, &[signed], now)
+    }
+
+    /// The fresh NSEC3 record whose hashed owner is the hash of the name
+    fn nsec3_matching(&self, hash: &[u8], now: Instant) -> Option<&Signed> {
+        self.nsec3.get(hash).filter(|signed| signed.is_fresh(now))
+    }
+
+    /// The fresh NSEC3 record whose range of hashes covers the hash of the name
+    fn nsec3_covering(&self, hash: &[u8], now: Instant) -> Option<&Signed> {
+        // below the first hash the last record of the chain covers it, wrapping around
+        let (owner, signed) = self
+            .nsec3
+            .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(hash)))
+            .next_back()
+            .or_else(|| self.nsec3.iter().next_back())?;
+        let Some(Record::Nsec3 { next_hashed, .. }) = signed.records.first() else {
+            return None;
+        };
+
+        let next = next_hashed.as_slice();
+        let covers = match owner.as_slice() < next {
+            true => owner.as_slice() < hash && hash < next,
+            false => owner.as_slice() < hash || hash < next,
+        };
+        (covers && signed.is_fresh(now)).then_some(signed)
+    }
+
+    /// Answer from NSEC3 records with a closest encloser proof, RFC 5155 sections 8.4 to 8.6
+    fn synthesize_nsec3(
+        &self,
+        apex: &[String],
+        name: &[String],
+        query_type: &QueryType,
+        now: Instant,
+    ) -> Option<DNSPacket> {
+        let (salt, iterations) =
+            self.nsec3
+                .values()
+                .find_map(|signed| match signed.records.first() {
+                    Some(Record::Nsec3 {
+                        salt, iterations, ..
+                    }) if signed.is_fresh(now) => Some((salt, *iterations)),
+                    _ => None,
+                })?;
+        let hash = |name_labels: &[String]| nsec3_hash(name_labels, salt, iterations);
+
+        if let Some(signed) = self.nsec3_matching(&hash(name), now) {
+            let types = signed.types();
+            if types.contains(&query_type.to_num())
+                || types.contains(&QueryType::Cname.to_num())
+                || is_cut(types)
+            {
+                return None;
+            }
+            return self.response(Noerror, Vec::new(), &[signed], now);
+        }
+
+        // the closest encloser exists, the name one label below it and its wildcard do not
+        let (encloser, encloser_signed) = (apex.len()..name.len())
+            .rev()
+            .find_map(|len| Some((len, self.nsec3_matching(&hash(&name[..len]), now)?)))?;
+        if is_cut(encloser_signed.types()) {
+            return None;
+        }
+        let next_closer = self.nsec3_covering(&hash(&name[..encloser + 1]), now)?;
+        // opt-out ranges may hide unsigned delegations
+        if next_closer.is_opt_out() {
+            return None;
+        }
+        let wildcard = [&name[..encloser], &[String::from("*")]].concat();
+        let wildcard_signed = self.nsec3_covering(&hash(&wildcard), now)?;
+
+        self.response(
+            Nxdomain,
+            Vec::new(),
+            &[encloser_signed, next_closer, wildcard_signed],
+            now,
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+/// Denials of existence from validated responses, reused for other names they cover, RFC 8198
+///
+/// Nothing is validated here, the records are only kept from responses the upstream marked with
+/// the AD bit, so it has to be a validating resolver reached over a path that can be trusted.
+pub struct NsecCache {
+    zones: Mutex<HashMap<Vec<String>, SignedZone>>,
+}
+
+impl NsecCache {
+    /// Keep the NSEC and NSEC3 records, SOA and wildcard expansions of a validated response
+    pub fn learn(&self, res_packet: &DNSPacket) {
+        if !res_packet.header.ad || !matches!(res_packet.header.rcode, Noerror | Nxdomain) {
+            return;
+        }
+        let mut zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        if zones.values().map(SignedZone::len).sum::<usize>() >= MAX_DENIALS {
+            zones.values_mut().for_each(|zone| zone.retain_fresh(now));
+            zones.retain(|_, zone| {
+                zone.len() > 0 || zone.soa.as_ref().is_some_and(|soa| soa.is_fresh(now))
+            });
+            if zones.values().map(SignedZone::len).sum::<usize>() >= MAX_DENIALS {
+                return;
+            }
+        }
+
+        // denials live no longer than the negative answer they came with, RFC 9077
+        let negative_ttl = res_packet.authority_sec.iter().find_map(|rec| match rec {
+            Record::Soa {
+                preamble, minimum, ..
+            } => Some(preamble.ttl.min(*minimum)),
+            _ => None,
+        });
+
+        let sections = [
+            (&res_packet.answer_sec, true),
+            (&res_packet.authority_sec, false),
+        ];
+        for (section, is_answer) in sections {
+            for rrsig in section {
+                let Some((covered, label_count, signer)) = rrsig_fields(rrsig) else {
+                    continue;
+                };
+                let owner = labels(&rrsig.preamble().name);
+                if !owner.starts_with(&signer) {
+                    continue;
+                }
+                let owned_by = |rec: &&Record| labels(&rec.preamble().name) == owner;
+                let records: Vec<Record> = section
+                    .iter()
+                    .filter(owned_by)
+                    .filter(|rec| rec.preamble().query_type.to_num() == covered)
+                    .cloned()
+                    .collect();
+                let signatures: Vec<Record> = section
+                    .iter()
+                    .filter(owned_by)
+                    .filter(|rec| rrsig_fields(rec).is_some_and(|(other, ..)| other == covered))
+                    .cloned()
+                    .collect();
+                let Some(first) = records.first() else {
+                    continue;
+                };
+
+                let query_type = QueryType::from_num(covered);
+                let mut ttl = records
+                    .iter()
+                    .chain(&signatures)
+                    .map(|rec| rec.preamble().ttl)
+                    .min()
+                    .unwrap_or(0);
+                if matches!(query_type, QueryType::Nsec | QueryType::Nsec3) {
+                    ttl = negative_ttl.map_or(ttl, |negative_ttl| ttl.min(negative_ttl));
+                }
+                let expires = now + Duration::from_secs(ttl as u64);
+
+                match (query_type, first) {
+                    (QueryType::Soa, _) if owner == signer => {
+                        zones.entry(signer).or_default().soa = Some(Signed {
+                            records,
+                            signatures,
+                            expires,
+                        });
+                    }
+                    (QueryType::Nsec, _) => {
+                        let signed = Signed {
+                            records,
+                            signatures,
+                            expires,
+                        };
+                        zones.entry(signer).or_default().nsec.insert(owner, signed);
+                    }
+                    (
+                        QueryType::Nsec3,
+                        Record::Nsec3 {
+                            hash_algorithm: NSEC3_SHA1,
+                            iterations,
+                            ..
+                        },
+                    ) if *iterations <= MAX_NSEC3_ITERATIONS => {
+                        // hashed owner names sit right below the apex
+                        let Some((label, parent)) = owner.split_last() else {
+                            continue;
+                        };
+                        let Some(hash) = base32hex_decode(label).filter(|_| *parent == signer)
+                        else {
+                            continue;
+                        };
+                        let signed = Signed {
+                            records,
+                            signatures,
+                            expires,
+                        };
+                        zones.entry(signer).or_default().nsec3.insert(hash, signed);
+                    }
+                    (QueryType::Nsec3, _) => {}
+                    // fewer signed labels than the owner has means it was expanded from a
+                    // wildcard, RFC 4035 section 5.3.4
+                    (_, _) if is_answer && (label_count as usize) < owner.len() => {
+                        let mut wildcard = owner[..label_count as usize].to_vec();
+                        wildcard.push(String::from("*"));
+                        let expanded = Signed {
+                            records,
+                            signatures,
+                            expires,
+                        };
+                        // kept under the wildcard name, signatures along with the records
+                        let signed = Signed {
+                            records: expanded.records_for(Some(&name_of(&wildcard)), now),
+                            signatures: Vec::new(),
+                            expires,
+                        };
+                        let key = (wildcard, covered);
+                        zones
+                            .entry(signer)
+                            .or_default()
+                            .wildcards
+                            .insert(key, signed);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// A response for the name made up from what is kept, when that proves the answer
+    ///
+    /// Names that do not exist and types a name lacks are denied, and names covered by a
+    /// wildcard are answered from its records.
+    pub fn synthesize(&self, name: &str, query_type: &QueryType) -> Option<DNSPacket> {
+        let name = labels(name);
+        let zones = self.zones.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        // the closest signed zone the name is in
+        let (apex, zone) = (0..=name.len())
+            .rev()
+            .find_map(|len| zones.get_key_value(&name[..len]))?;
+
+        zone.synthesize_nsec(&name, query_type, now)
+            .or_else(|| zone.synthesize_nsec3(apex, &name, query_type, now))
+    }
+}
+
+/// Drop DNSSEC records from a response to a client that did not set the DO bit, unless it asked
+/// for them, RFC 4035 section 3.2.1
+pub fn strip_dnssec(res_packet: &mut DNSPacket, query_type: &QueryType) {
+    let is_dnssec = |rec: &Record| {
+        matches!(
+            rec.preamble().query_type,
+            QueryType::Rrsig | QueryType::Nsec | QueryType::Nsec3
+        )
+    };
+    res_packet
+        .answer_sec
+        .retain(|rec| !is_dnssec(rec) || rec.preamble().query_type == *query_type);
+    res_packet.authority_sec.retain(|rec| !is_dnssec(rec));
+    res_packet.additional_sec.retain(|rec| !is_dnssec(rec));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{record::RecordPreamble, zone_file::parse_record};
+
+    /// Kept for an hour, signatures are not looked at once learned
+    fn signed(records: Vec<Record>, now: Instant) -> Signed {
+        Signed {
+            records,
+            signatures: Vec::new(),
+            expires: now + Duration::from_secs(3600),
+        }
+    }
+
+    /// The zone example with an NSEC record from a.example to x.b.example
+    fn zone(now: Instant) -> SignedZone {
+        let soa = parse_record(
+            "example. 3600 IN SOA ns.example. admin.example. 1 7200 900 86400 300",
+            3600,
+        )
+        .unwrap();
+        let nsec = Record::Nsec {
+            preamble: RecordPreamble::new("a.example", QueryType::Nsec, 300),
+            next: String::from("x.b.example"),
+            types: vec![QueryType::A.to_num(), QueryType::Nsec.to_num()],
+        };
+
+        let mut zone = SignedZone {
+            soa: Some(signed(vec![soa], now)),
+            ..SignedZone::default()
+        };
+        zone.nsec
+            .insert(labels("a.example"), signed(vec![nsec], now));
+        zone
+    }
+
+    #[test]
+    fn name_with_descendants_is_nodata() {
+        let now = Instant::now();
+        let res_packet = zone(now)
+            .synthesize_nsec(&labels("b.example"), &QueryType::A, now)
+            .unwrap();
+
+        assert_eq!(res_packet.header.rcode, Noerror);
+        assert!(res_packet.answer_sec.is_empty());
+    }
+
+    #[test]
+    fn name_in_the_range_is_nxdomain() {
+        let now = Instant::now();
+        let mut zone = zone(now);
+        let wildcard = Record::Nsec {
+            preamble: RecordPreamble::new("example", QueryType::Nsec, 300),
+            next: String::from("a.example"),
+            types: vec![QueryType::Soa.to_num(), QueryType::Nsec.to_num()],
+        };
+        zone.nsec
+            .insert(labels("example"), signed(vec![wildcard], now));
+
+        let res_packet = zone
+            .synthesize_nsec(&labels("c.b.example"), &QueryType::A, now)
+            .unwrap();
+        assert_eq!(res_packet.header.rcode, Nxdomain);
+    }
+}
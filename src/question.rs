@@ -1,13 +1,24 @@
 use super::{errors::Result, raw_packet::RawPacket};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryType {
     Unknown(u16),
     A,
     NS,
     Cname,
+    Soa,
+    Ptr,
     MX,
+    Txt,
     Aaaa,
+    Dname,
+    Opt,
+    Rrsig,
+    Nsec,
+    Nsec3,
+    Tsig,
+    Ixfr,
+    Axfr,
 }
 
 impl QueryType {
@@ -16,8 +27,19 @@ impl QueryType {
             1 => Self::A,
             2 => Self::NS,
             5 => Self::Cname,
+            6 => Self::Soa,
+            12 => Self::Ptr,
             15 => Self::MX,
+            16 => Self::Txt,
             28 => Self::Aaaa,
+            39 => Self::Dname,
+            41 => Self::Opt,
+            46 => Self::Rrsig,
+            47 => Self::Nsec,
+            50 => Self::Nsec3,
+            250 => Self::Tsig,
+            251 => Self::Ixfr,
+            252 => Self::Axfr,
             _ => Self::Unknown(rec_type),
         }
     }
@@ -28,13 +50,45 @@ impl QueryType {
             Self::A => 1,
             Self::NS => 2,
             Self::Cname => 5,
+            Self::Soa => 6,
+            Self::Ptr => 12,
             Self::MX => 15,
+            Self::Txt => 16,
             Self::Aaaa => 28,
+            Self::Dname => 39,
+            Self::Opt => 41,
+            Self::Rrsig => 46,
+            Self::Nsec => 47,
+            Self::Nsec3 => 50,
+            Self::Tsig => 250,
+            Self::Ixfr => 251,
+            Self::Axfr => 252,
         }
     }
+
+    /// Look up a record type by its mnemonic, including the generic TYPEnnn form
+    pub fn from_name(name: &str) -> Option<Self> {
+        let rec_type = match name.to_ascii_uppercase().as_str() {
+            "A" => Self::A,
+            "NS" => Self::NS,
+            "CNAME" => Self::Cname,
+            "SOA" => Self::Soa,
+            "PTR" => Self::Ptr,
+            "MX" => Self::MX,
+            "TXT" => Self::Txt,
+            "AAAA" => Self::Aaaa,
+            "DNAME" => Self::Dname,
+            "RRSIG" => Self::Rrsig,
+            "NSEC" => Self::Nsec,
+            "NSEC3" => Self::Nsec3,
+            other => Self::from_num(other.strip_prefix("TYPE")?.parse().ok()?),
+        };
+
+        Some(rec_type)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// The Question Section stories information about the query
 pub struct Question {
     /// The domain name being queried
@@ -72,4 +126,11 @@ impl Question {
 
         Ok(())
     }
+
+    /// If both questions ask for the same name, type and class
+    pub fn matches(&self, other: &Question) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+            && self.query_type == other.query_type
+            && self.class == other.class
+    }
 }
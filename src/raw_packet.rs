@@ -3,12 +3,13 @@ use super::errors::{
     Result,
 };
 
-const PACKET_SIZE: usize = 512;
+const PACKET_SIZE: usize = 512; // largest packet sent over plain UDP
+pub const MAX_PACKET_SIZE: usize = 65535; // largest message that fits the 2 byte TCP length prefix
 
 /// Representation of a network packet as its bytes
 pub struct RawPacket {
     /// Buffer to store the bytes of the packet
-    pub buf: [u8; PACKET_SIZE],
+    pub buf: Vec<u8>,
     /// Cursor to store current position in buffer
     cursor: usize,
 }
@@ -16,8 +17,13 @@ pub struct RawPacket {
 impl RawPacket {
     /// Return a new, empty BytePacket
     pub fn new() -> Self {
+        Self::with_size(PACKET_SIZE)
+    }
+
+    /// Return a new, empty BytePacket that can hold the given number of bytes
+    pub fn with_size(size: usize) -> Self {
         RawPacket {
-            buf: [0; PACKET_SIZE],
+            buf: vec![0; size],
             cursor: 0,
         }
     }
@@ -27,19 +33,9 @@ impl RawPacket {
         self.cursor
     }
 
-    /// Increment cursor by step positions
-    pub fn step(&mut self, step: usize) -> Result<()> {
-        if self.cursor + step >= PACKET_SIZE {
-            return Err(BufferOverflow);
-        }
-        self.cursor += step;
-
-        Ok(())
-    }
-
     /// Move buffer position to given position
-    fn seek(&mut self, pos: usize) -> Result<()> {
-        if pos >= PACKET_SIZE {
+    pub fn seek(&mut self, pos: usize) -> Result<()> {
+        if pos >= self.buf.len() {
             return Err(BufferOverflow);
         }
         self.cursor = pos;
@@ -49,11 +45,11 @@ impl RawPacket {
 
     /// Read 1 byte
     pub fn read_u8(&mut self) -> Result<u8> {
-        if self.cursor >= PACKET_SIZE {
+        if self.cursor >= self.buf.len() {
             return Err(BufferEnd);
         }
 
-        self.cursor += 1; // only read_byte can set cursor to 512 to mark entire buffer as read
+        self.cursor += 1; // only read_byte can set cursor to the buffer size to mark entire buffer as read
 
         Ok(self.buf[self.cursor - 1])
     }
@@ -78,6 +74,11 @@ impl RawPacket {
         Ok(data)
     }
 
+    /// Read the given number of bytes
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        (0..len).map(|_| self.read_u8()).collect()
+    }
+
     pub fn read_query_name(&mut self, output: &mut String) -> Result<()> {
         let mut pos = self.cursor; // track position locally as there can be jumps
         let mut delim = ""; // let it be empty string for first iteration
@@ -125,8 +126,7 @@ impl RawPacket {
             output.push_str(
                 &String::from_utf8_lossy(
                     self.get_bytes_from(pos, pos + len as usize - 1)?, // get label from buffer
-                ) // convert to utf-8 string
-                .to_lowercase(), // convert to lowercase
+                ), // convert to utf-8 string, preserving case
             );
 
             pos += len as usize;
@@ -142,19 +142,24 @@ impl RawPacket {
 
     /// Get byte at given position without updating cursor
     fn get_byte_at(&self, pos: usize) -> Result<u8> {
-        if pos >= PACKET_SIZE {
+        if pos >= self.buf.len() {
             return Err(BufferOverflow);
         }
 
         Ok(self.buf[pos])
     }
 
+    /// The bytes written so far
+    pub fn written(&self) -> &[u8] {
+        &self.buf[..self.cursor]
+    }
+
     /// Get bytes from start to end (inclusive) as a byte slice
     pub fn get_bytes_from(&self, start: usize, end: usize) -> Result<&[u8]> {
         if start > end {
             return Err(RangeErr);
         }
-        if start >= PACKET_SIZE || end >= PACKET_SIZE {
+        if start >= self.buf.len() || end >= self.buf.len() {
             return Err(BufferOverflow);
         }
 
@@ -163,7 +168,7 @@ impl RawPacket {
 
     /// Write one byte into the buffer
     pub fn write_u8(&mut self, data: u8) -> Result<()> {
-        if self.cursor >= PACKET_SIZE {
+        if self.cursor >= self.buf.len() {
             return Err(BufferOverflow);
         }
         self.buf[self.cursor] = data;
@@ -194,7 +199,8 @@ impl RawPacket {
 
     /// Write the given domain in labeled form into the buffer
     pub fn write_query_name(&mut self, domain: &str) -> Result<()> {
-        for label in domain.split('.') {
+        // the root domain and a trailing dot have no label of their own
+        for label in domain.split('.').filter(|label| !label.is_empty()) {
             let len = label.len();
             if len > 63 {
                 return Err(InvalidLabelLen);
@@ -211,7 +217,7 @@ impl RawPacket {
     }
 
     fn set(&mut self, pos: usize, data: u8) -> Result<()> {
-        if pos >= PACKET_SIZE {
+        if pos >= self.buf.len() {
             return Err(BufferOverflow);
         }
 
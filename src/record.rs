@@ -1,23 +1,54 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-use super::{errors::Result, question::QueryType, raw_packet::RawPacket};
+use super::{
+    edns::EdnsOption,
+    errors::{Errors::RangeErr, Result},
+    question::QueryType,
+    raw_packet::RawPacket,
+};
 
-#[derive(Debug)]
+pub const CLASS_NONE: u16 = 254; // marks a specific record to delete, or a name or RRset that must not exist
+pub const CLASS_ANY: u16 = 255; // marks a whole RRset or name, to delete or that must exist
+
+#[derive(Debug, Clone)]
 /// Record Preamble that is common for all different types of records
 pub struct RecordPreamble {
     /// Domain name
-    name: String, // variable number of bits
+    pub name: String, // variable number of bits
     /// Record type
-    query_type: QueryType, // 16 bits
+    pub query_type: QueryType, // 16 bits
     /// The class, in practice always 1
-    class: u16, // 16 bits
+    pub class: u16, // 16 bits
     /// How long a record can be cached before it has to be queried again
-    ttl: u32, // 32 bits
+    pub ttl: u32, // 32 bits
     /// Length of record specific data
-    len: u16, // 16 bits
+    pub len: u16, // 16 bits
+}
+
+impl RecordPreamble {
+    /// A new preamble for a record of the given type, the length is filled in when written
+    pub fn new(name: &str, query_type: QueryType, ttl: u32) -> Self {
+        RecordPreamble {
+            name: String::from(name),
+            query_type,
+            class: 1, // always 1 in practice
+            ttl,
+            len: 0,
+        }
+    }
+}
+
+/// Records are compared as DNS does, ignoring the case of the name and the wire length
+impl PartialEq for RecordPreamble {
+    fn eq(&self, other: &Self) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name)
+            && self.query_type == other.query_type
+            && self.class == other.class
+            && self.ttl == other.ttl
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 /// Information about the record being sent
 pub enum Record {
     Unknown {
@@ -36,19 +67,150 @@ pub enum Record {
         preamble: RecordPreamble,
         name: String,
     },
+    Soa {
+        preamble: RecordPreamble,
+        /// Primary name server for the zone
+        mname: String,
+        /// Mailbox of the person responsible for the zone
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        /// TTL for negative answers
+        minimum: u32,
+    },
+    Ptr {
+        preamble: RecordPreamble,
+        name: String,
+    },
     MX {
         preamble: RecordPreamble,
         priority: u16,
         name: String,
     },
+    Txt {
+        preamble: RecordPreamble,
+        /// One or more character strings of up to 255 bytes each, not necessarily text
+        data: Vec<Vec<u8>>,
+    },
     Aaaa {
         preamble: RecordPreamble,
         ip: Ipv6Addr,
     },
+    Dname {
+        preamble: RecordPreamble,
+        /// Name substituted for the owner name in every name below it
+        name: String,
+    },
+    /// EDNS pseudo-record, RFC 6891, its class carries the UDP payload size and its TTL the
+    /// extended RCODE, version and flags
+    Opt {
+        preamble: RecordPreamble,
+        options: Vec<EdnsOption>,
+    },
+    /// Proof that no names sort between the owner and the next name, and which types the owner
+    /// has, RFC 4034 section 4
+    Nsec {
+        preamble: RecordPreamble,
+        /// Next name of the zone in canonical order, wrapping around to the apex
+        next: String,
+        types: Vec<u16>,
+    },
+    /// The same proof over hashed owner names, RFC 5155 section 3
+    Nsec3 {
+        preamble: RecordPreamble,
+        hash_algorithm: u8,
+        /// Opt-out flag in the lowest bit, unsigned delegations may lie in the range
+        flags: u8,
+        /// Times the hash is applied again
+        iterations: u16,
+        salt: Vec<u8>,
+        /// Hash of the next owner name in hash order, not encoded
+        next_hashed: Vec<u8>,
+        types: Vec<u16>,
+    },
+    Tsig {
+        preamble: RecordPreamble,
+        /// Name of the MAC algorithm, e.g. hmac-sha256
+        algorithm: String,
+        /// Seconds since the epoch when the message was signed, 48 bits on the wire
+        time_signed: u64,
+        /// Seconds of clock skew allowed either way
+        fudge: u16,
+        mac: Vec<u8>,
+        /// ID of the message when it was signed
+        original_id: u16,
+        /// Extended error, e.g. BADSIG
+        error: u16,
+        /// The server's time when the error is BADTIME
+        other: Vec<u8>,
+    },
+}
+
+/// Read the types listed by NSEC and NSEC3 records up to the end of the record data, RFC 4034
+/// section 4.1.2
+fn read_type_bitmaps(buf: &mut RawPacket, end: usize) -> Result<Vec<u16>> {
+    let mut types = Vec::new();
+    while buf.cursor() < end {
+        let window = buf.read_u8()? as u16;
+        let bitmap_len = buf.read_u8()?;
+        for (i, byte) in buf.read_bytes(bitmap_len as usize)?.into_iter().enumerate() {
+            for bit in 0..8 {
+                if byte & 0b1000_0000 >> bit > 0 {
+                    types.push(window << 8 | (i as u16) << 3 | bit);
+                }
+            }
+        }
+    }
+
+    Ok(types)
+}
+
+/// Write the types as one bitmap per window of 256 types that has any
+fn write_type_bitmaps(buf: &mut RawPacket, types: &[u16]) -> Result<()> {
+    let mut types = types.to_vec();
+    types.sort_unstable();
+    types.dedup();
+
+    for window in types.chunk_by(|a, b| a >> 8 == b >> 8) {
+        let mut bitmap = vec![0u8; (window[window.len() - 1] as usize & 0xff) / 8 + 1];
+        for rec_type in window {
+            bitmap[(*rec_type as usize & 0xff) / 8] |= 0b1000_0000 >> (rec_type & 0b111);
+        }
+        buf.write_u8((window[0] >> 8) as u8)?;
+        buf.write_u8(bitmap.len() as u8)?;
+        for byte in bitmap {
+            buf.write_u8(byte)?;
+        }
+    }
+
+    Ok(())
 }
 
 impl Record {
     pub fn parse(buf: &mut RawPacket) -> Result<Record> {
+        let preamble = Self::parse_preamble(buf)?;
+        Self::parse_data(preamble, buf)
+    }
+
+    /// Parse a prerequisite or update of an UPDATE message, RFC 2136 section 2.4 and 2.5
+    ///
+    /// Those of class ANY or NONE may name a type without carrying its data.
+    pub fn parse_update(buf: &mut RawPacket) -> Result<Record> {
+        let preamble = Self::parse_preamble(buf)?;
+        if preamble.len == 0 && matches!(preamble.class, CLASS_NONE | CLASS_ANY) {
+            return Ok(Record::Unknown {
+                preamble,
+                data: Vec::new(),
+            });
+        }
+
+        Self::parse_data(preamble, buf)
+    }
+
+    /// Parse the name, type, class, TTL and data length that open every record
+    fn parse_preamble(buf: &mut RawPacket) -> Result<RecordPreamble> {
         let mut name = String::new();
         buf.read_query_name(&mut name)?;
 
@@ -57,21 +219,34 @@ impl Record {
         let ttl = buf.read_u32()?;
         let len = buf.read_u16()?;
 
-        let preamble = RecordPreamble {
+        Ok(RecordPreamble {
             name,
             query_type: QueryType::from_num(query_type_num),
             class,
             ttl,
             len,
-        };
+        })
+    }
 
-        match QueryType::from_num(query_type_num) {
-            QueryType::Unknown(_) => {
-                let pos = buf.cursor();
-                buf.step(len as usize)?; // move buffer cursor ahead to adjust for future reads
+    /// Parse the data of a record following its preamble, which must take up exactly its length
+    fn parse_data(preamble: RecordPreamble, buf: &mut RawPacket) -> Result<Record> {
+        let end = buf.cursor() + preamble.len as usize;
+        let rec = Self::parse_rdata(preamble, buf)?;
+        if buf.cursor() != end {
+            return Err(RangeErr);
+        }
+
+        Ok(rec)
+    }
+
+    fn parse_rdata(preamble: RecordPreamble, buf: &mut RawPacket) -> Result<Record> {
+        let len = preamble.len;
+        match preamble.query_type {
+            // transfer types are only ever asked for, anything carrying them is kept as opaque data
+            QueryType::Unknown(_) | QueryType::Rrsig | QueryType::Ixfr | QueryType::Axfr => {
                 Ok(Record::Unknown {
                     preamble,
-                    data: buf.get_bytes_from(pos, pos + len as usize - 1)?.into(),
+                    data: buf.read_bytes(len as usize)?,
                 })
             }
 
@@ -99,6 +274,31 @@ impl Record {
                 Ok(Record::Cname { preamble, name })
             }
 
+            QueryType::Soa => {
+                let mut mname = String::new();
+                buf.read_query_name(&mut mname)?;
+                let mut rname = String::new();
+                buf.read_query_name(&mut rname)?;
+
+                Ok(Record::Soa {
+                    preamble,
+                    mname,
+                    rname,
+                    serial: buf.read_u32()?,
+                    refresh: buf.read_u32()?,
+                    retry: buf.read_u32()?,
+                    expire: buf.read_u32()?,
+                    minimum: buf.read_u32()?,
+                })
+            }
+
+            QueryType::Ptr => {
+                let mut name = String::new();
+                buf.read_query_name(&mut name)?;
+
+                Ok(Record::Ptr { preamble, name })
+            }
+
             QueryType::MX => {
                 let priority = buf.read_u16()?;
                 let mut name = String::new();
@@ -111,6 +311,18 @@ impl Record {
                 })
             }
 
+            QueryType::Txt => {
+                let end = buf.cursor() + len as usize;
+                let mut data = Vec::new();
+                // character strings are length prefixed and fill the whole record data
+                while buf.cursor() < end {
+                    let str_len = buf.read_u8()?;
+                    data.push(buf.read_bytes(str_len as usize)?);
+                }
+
+                Ok(Record::Txt { preamble, data })
+            }
+
             QueryType::Aaaa => Ok(Record::Aaaa {
                 preamble,
                 ip: Ipv6Addr::new(
@@ -124,71 +336,225 @@ impl Record {
                     buf.read_u16()?,
                 ),
             }),
+
+            QueryType::Dname => {
+                let mut name = String::new();
+                buf.read_query_name(&mut name)?;
+
+                Ok(Record::Dname { preamble, name })
+            }
+
+            QueryType::Opt => {
+                let end = buf.cursor() + len as usize;
+                let mut options = Vec::new();
+                while buf.cursor() < end {
+                    let code = buf.read_u16()?;
+                    let option_len = buf.read_u16()?;
+                    options.push(EdnsOption {
+                        code,
+                        data: buf.read_bytes(option_len as usize)?,
+                    });
+                }
+
+                Ok(Record::Opt { preamble, options })
+            }
+
+            QueryType::Nsec => {
+                let end = buf.cursor() + len as usize;
+                let mut next = String::new();
+                buf.read_query_name(&mut next)?;
+                let types = read_type_bitmaps(buf, end)?;
+
+                Ok(Record::Nsec {
+                    preamble,
+                    next,
+                    types,
+                })
+            }
+
+            QueryType::Nsec3 => {
+                let end = buf.cursor() + len as usize;
+                let hash_algorithm = buf.read_u8()?;
+                let flags = buf.read_u8()?;
+                let iterations = buf.read_u16()?;
+                let salt_len = buf.read_u8()?;
+                let salt = buf.read_bytes(salt_len as usize)?;
+                let hash_len = buf.read_u8()?;
+                let next_hashed = buf.read_bytes(hash_len as usize)?;
+                let types = read_type_bitmaps(buf, end)?;
+
+                Ok(Record::Nsec3 {
+                    preamble,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed,
+                    types,
+                })
+            }
+
+            QueryType::Tsig => {
+                let mut algorithm = String::new();
+                buf.read_query_name(&mut algorithm)?;
+                let time_signed = (buf.read_u16()? as u64) << 32 | buf.read_u32()? as u64;
+                let fudge = buf.read_u16()?;
+                let mac_len = buf.read_u16()?;
+                let mac = buf.read_bytes(mac_len as usize)?;
+                let original_id = buf.read_u16()?;
+                let error = buf.read_u16()?;
+                let other_len = buf.read_u16()?;
+
+                Ok(Record::Tsig {
+                    preamble,
+                    algorithm,
+                    time_signed,
+                    fudge,
+                    mac,
+                    original_id,
+                    error,
+                    other: buf.read_bytes(other_len as usize)?,
+                })
+            }
         }
     }
 
     /// Write a record into a RawPacket
     pub fn write(&self, buf: &mut RawPacket) -> Result<()> {
-        match self {
-            Self::A { preamble, ip } => {
-                Self::write_preamble(preamble, buf)?;
+        let len_pos = Self::write_preamble(self.preamble(), buf)?;
 
+        match self {
+            Self::A { ip, .. } => {
                 for octet in ip.octets() {
                     buf.write_u8(octet)?;
                 }
-
-                Ok(())
             }
 
-            Self::NS { preamble, name } => {
-                let len_pos = Self::write_preamble(preamble, buf)?;
+            Self::NS { name, .. }
+            | Self::Cname { name, .. }
+            | Self::Ptr { name, .. }
+            | Self::Dname { name, .. } => {
                 buf.write_query_name(name)?;
-                buf.set_u16(len_pos, (buf.cursor() - (len_pos + 2)) as u16)?;
-
-                Ok(())
             }
 
-            Self::Cname { preamble, name } => {
-                let len_pos = Self::write_preamble(preamble, buf)?;
-                buf.write_query_name(name)?;
-                buf.set_u16(len_pos, (buf.cursor() - (len_pos + 2)) as u16)?;
-
-                Ok(())
+            Self::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => {
+                buf.write_query_name(mname)?;
+                buf.write_query_name(rname)?;
+                for num in [serial, refresh, retry, expire, minimum] {
+                    buf.write_u32(*num)?;
+                }
             }
 
-            Self::MX {
-                preamble,
-                priority,
-                name,
-            } => {
-                let len_pos = Self::write_preamble(preamble, buf)?;
+            Self::MX { priority, name, .. } => {
                 buf.write_u16(*priority)?;
                 buf.write_query_name(name)?;
-                buf.set_u16(len_pos, (buf.cursor() - (len_pos + 2)) as u16)?;
-
-                Ok(())
             }
 
-            Self::Aaaa { preamble, ip } => {
-                Self::write_preamble(preamble, buf)?;
+            Self::Txt { data, .. } => {
+                for text in data {
+                    // a character string holds 255 bytes at most, longer text goes on in the next
+                    // one as it does in SPF and DKIM records
+                    let mut chunks = text.chunks(255).peekable();
+                    if chunks.peek().is_none() {
+                        buf.write_u8(0)?;
+                    }
+                    for chunk in chunks {
+                        buf.write_u8(chunk.len() as u8)?;
+                        for byte in chunk {
+                            buf.write_u8(*byte)?;
+                        }
+                    }
+                }
+            }
 
+            Self::Aaaa { ip, .. } => {
                 for segment in ip.segments() {
                     buf.write_u16(segment)?;
                 }
-
-                Ok(())
             }
 
-            Self::Unknown { preamble, data } => {
-                Self::write_preamble(preamble, buf)?;
-
+            Self::Unknown { data, .. } => {
                 for byte in data {
                     buf.write_u8(*byte)?;
                 }
+            }
+
+            Self::Opt { options, .. } => {
+                for option in options {
+                    buf.write_u16(option.code)?;
+                    buf.write_u16(option.data.len() as u16)?;
+                    for byte in &option.data {
+                        buf.write_u8(*byte)?;
+                    }
+                }
+            }
 
-                Ok(())
+            Self::Nsec { next, types, .. } => {
+                buf.write_query_name(next)?;
+                write_type_bitmaps(buf, types)?;
+            }
+
+            Self::Nsec3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed,
+                types,
+                ..
+            } => {
+                buf.write_u8(*hash_algorithm)?;
+                buf.write_u8(*flags)?;
+                buf.write_u16(*iterations)?;
+                for bytes in [salt, next_hashed] {
+                    buf.write_u8(bytes.len() as u8)?;
+                    for byte in bytes {
+                        buf.write_u8(*byte)?;
+                    }
+                }
+                write_type_bitmaps(buf, types)?;
+            }
+
+            Self::Tsig {
+                algorithm,
+                time_signed,
+                fudge,
+                mac,
+                original_id,
+                error,
+                other,
+                ..
+            } => {
+                buf.write_query_name(algorithm)?;
+                buf.write_u16((time_signed >> 32) as u16)?;
+                buf.write_u32(*time_signed as u32)?;
+                buf.write_u16(*fudge)?;
+                buf.write_u16(mac.len() as u16)?;
+                for byte in mac {
+                    buf.write_u8(*byte)?;
+                }
+                buf.write_u16(*original_id)?;
+                buf.write_u16(*error)?;
+                buf.write_u16(other.len() as u16)?;
+                for byte in other {
+                    buf.write_u8(*byte)?;
+                }
             }
         }
+
+        // record data length is only known once it has been written
+        buf.set_u16(len_pos, (buf.cursor() - (len_pos + 2)) as u16)?;
+
+        Ok(())
     }
 
     /// Write a record into a RawPacket and return the position where the length was written
@@ -202,4 +568,119 @@ impl Record {
 
         Ok(len_pos)
     }
+
+    /// The preamble shared by every type of record
+    pub fn preamble(&self) -> &RecordPreamble {
+        match self {
+            Self::Unknown { preamble, .. }
+            | Self::A { preamble, .. }
+            | Self::NS { preamble, .. }
+            | Self::Cname { preamble, .. }
+            | Self::Soa { preamble, .. }
+            | Self::Ptr { preamble, .. }
+            | Self::MX { preamble, .. }
+            | Self::Txt { preamble, .. }
+            | Self::Aaaa { preamble, .. }
+            | Self::Dname { preamble, .. }
+            | Self::Opt { preamble, .. }
+            | Self::Nsec { preamble, .. }
+            | Self::Nsec3 { preamble, .. }
+            | Self::Tsig { preamble, .. } => preamble,
+        }
+    }
+
+    /// Mutable access to the preamble shared by every type of record
+    pub fn preamble_mut(&mut self) -> &mut RecordPreamble {
+        match self {
+            Self::Unknown { preamble, .. }
+            | Self::A { preamble, .. }
+            | Self::NS { preamble, .. }
+            | Self::Cname { preamble, .. }
+            | Self::Soa { preamble, .. }
+            | Self::Ptr { preamble, .. }
+            | Self::MX { preamble, .. }
+            | Self::Txt { preamble, .. }
+            | Self::Aaaa { preamble, .. }
+            | Self::Dname { preamble, .. }
+            | Self::Opt { preamble, .. }
+            | Self::Nsec { preamble, .. }
+            | Self::Nsec3 { preamble, .. }
+            | Self::Tsig { preamble, .. } => preamble,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dns_packet::DNSPacket,
+        header::Opcode,
+        raw_packet::{RawPacket, MAX_PACKET_SIZE},
+        zone_file::parse_record,
+    };
+
+    /// Write a message holding an A record without data and parse it back
+    fn round_trip(op_code: Opcode, class: u16) -> Result<DNSPacket> {
+        let mut preamble = RecordPreamble::new("www.example.com", QueryType::A, 0);
+        preamble.class = class;
+
+        let mut packet = DNSPacket::new();
+        packet.header.op_code = op_code;
+        packet.authority_sec.push(Record::Unknown {
+            preamble,
+            data: Vec::new(),
+        });
+
+        let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        packet.write(&mut buf)?;
+        buf.seek(0)?;
+        let mut parsed = DNSPacket::new();
+        parsed.parse(&mut buf)?;
+
+        Ok(parsed)
+    }
+
+    #[test]
+    fn update_deletion_without_data() {
+        let packet = round_trip(Opcode::Update, CLASS_ANY).unwrap();
+
+        assert!(matches!(
+            &packet.authority_sec[..],
+            [Record::Unknown { preamble, data }]
+                if preamble.query_type == QueryType::A && data.is_empty()
+        ));
+    }
+
+    #[test]
+    fn empty_record_data_is_rejected() {
+        assert!(round_trip(Opcode::Query, 1).is_err());
+        assert!(round_trip(Opcode::Update, 1).is_err());
+    }
+
+    #[test]
+    fn txt_escapes_are_single_bytes() {
+        let rec = parse_record(r#"t.example.com. 300 IN TXT "\200\255 é" "plain""#, 300).unwrap();
+        assert!(matches!(
+            &rec,
+            Record::Txt { data, .. } if *data == [vec![200, 255, b' ', 0xc3, 0xa9], b"plain".to_vec()]
+        ));
+
+        let mut packet = DNSPacket::new();
+        packet.answer_sec.push(rec.clone());
+        let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        packet.write(&mut buf).unwrap();
+        buf.seek(0).unwrap();
+        let mut parsed = DNSPacket::new();
+        parsed.parse(&mut buf).unwrap();
+        assert_eq!(parsed.answer_sec, [rec]);
+    }
+
+    #[test]
+    fn txt_over_255_bytes_is_refused() {
+        let text = format!(r#"t.example.com. 300 IN TXT "{}""#, "\\200".repeat(256));
+        assert!(parse_record(&text, 300).is_err());
+        let text = format!(r#"t.example.com. 300 IN TXT "{}""#, "\\200".repeat(255));
+        assert!(parse_record(&text, 300).is_ok());
+    }
 }
@@ -0,0 +1,400 @@
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::{
+    acl::Cidr,
+    config::RateLimitConfig,
+    dns_packet::DNSPacket,
+    header::ResponseCode::{Noerror, Nxdomain},
+};
+
+const MAX_BUCKETS: usize = 100_000; // idle buckets are dropped once the table grows past this
+const EVICT_SHARE: usize = 10; // one in this many buckets, the least used lately, go when all are busy
+
+/// Source of the current time, so the limiter can be driven by a fake clock
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+/// The monotonic clock of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// Responses counted against a limit of their own
+enum ResponseKind {
+    Answer,
+    Nxdomain,
+    Error,
+}
+
+impl ResponseKind {
+    fn of(res_packet: &DNSPacket) -> Self {
+        match res_packet.header.rcode {
+            Noerror => Self::Answer,
+            Nxdomain => Self::Nxdomain,
+            _ => Self::Error,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Answer => "answer",
+            Self::Nxdomain => "NXDOMAIN",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// What becomes of a response once it has been counted
+pub enum Verdict {
+    Send,
+    Drop,
+    /// Send it truncated instead, so a real client retries over TCP
+    Slip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// Responses sharing a bucket: one kind sent to one block of clients, answers also by name
+struct BucketKey {
+    block: Cidr,
+    kind: ResponseKind,
+    name: String,
+}
+
+#[derive(Debug)]
+/// Token bucket refilled at the rate of its kind
+struct Bucket {
+    /// Responses that may still be sent, negative while the clients are over their limit
+    balance: f64,
+    updated: Instant,
+    /// Responses limited since the bucket ran out, every slip-th one is slipped
+    limited: u64,
+}
+
+/// Response Rate Limiting as done by BIND, keeping a spoofed UDP source from being flooded
+/// with responses
+pub struct RateLimiter<C: Clock = SystemClock> {
+    config: RateLimitConfig,
+    clock: C,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self::with_clock(config, SystemClock)
+    }
+}
+
+impl<C: Clock> RateLimiter<C> {
+    pub fn with_clock(config: RateLimitConfig, clock: C) -> Self {
+        RateLimiter {
+            config,
+            clock,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Responses per second allowed for the kind, 0 when it is not limited
+    fn rate(&self, kind: ResponseKind) -> u32 {
+        let config = &self.config;
+        match kind {
+            ResponseKind::Answer => config.responses_per_second,
+            ResponseKind::Nxdomain => config
+                .nxdomains_per_second
+                .unwrap_or(config.responses_per_second),
+            ResponseKind::Error => config
+                .errors_per_second
+                .unwrap_or(config.responses_per_second),
+        }
+    }
+
+    /// Count the response against the bucket of its client and decide if it goes out
+    pub fn check(&self, client: IpAddr, res_packet: &DNSPacket) -> Verdict {
+        let config = &self.config;
+        let kind = ResponseKind::of(res_packet);
+        let rate = self.rate(kind);
+        if rate == 0 || config.exempt.iter().any(|block| block.contains(client)) {
+            return Verdict::Send;
+        }
+
+        // answers are counted per name, so a busy client asking for many names is not held up
+        let name = match (kind, res_packet.question_sec.first()) {
+            (ResponseKind::Answer, Some(que)) => que.name.to_ascii_lowercase(),
+            _ => String::new(),
+        };
+        let client = client.to_canonical();
+        let prefix_length = match client {
+            IpAddr::V4(_) => config.ipv4_prefix_length,
+            IpAddr::V6(_) => config.ipv6_prefix_length,
+        };
+        let key = BucketKey {
+            block: Cidr::around(client, prefix_length),
+            kind,
+            name,
+        };
+
+        let now = self.clock.now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            // a bucket left alone for longer than the window has filled up again
+            let idle = Duration::from_secs(config.window as u64 + 1);
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle);
+        }
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&key) {
+            // a flood from many blocks at once still may not grow the table without bound
+            let evict = MAX_BUCKETS / EVICT_SHARE;
+            let mut updated: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+            let cutoff = *updated.select_nth_unstable(evict).1;
+            // of the buckets last used at the cutoff itself only as many go as are needed
+            let mut ties = evict - updated.iter().filter(|time| **time < cutoff).count();
+            buckets.retain(|_, bucket| match bucket.updated.cmp(&cutoff) {
+                Ordering::Less => false,
+                Ordering::Equal if ties > 0 => {
+                    ties -= 1;
+                    false
+                }
+                _ => true,
+            });
+        }
+
+        let block = key.block;
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            balance: rate as f64,
+            updated: now,
+            limited: 0,
+        });
+
+        // refill for the time since the last response, one second's worth at most, and never
+        // owe more than the window so a flood is forgiven once it has been over that long
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.balance =
+            ((bucket.balance + elapsed * rate).min(rate) - 1.0).max(-rate * config.window as f64);
+        bucket.updated = now;
+
+        if bucket.balance >= 0.0 {
+            bucket.limited = 0;
+            return Verdict::Send;
+        }
+
+        bucket.limited += 1;
+        if bucket.limited == 1 {
+            eprintln!(
+                "{}rate limiting {} responses to {}/{}",
+                if config.log_only { "would be " } else { "" },
+                kind.name(),
+                block.addr(),
+                block.prefix()
+            );
+        }
+
+        if config.log_only {
+            return Verdict::Send;
+        }
+        match config.slip {
+            0 => Verdict::Drop,
+            slip if bucket.limited.is_multiple_of(slip as u64) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, net::Ipv4Addr};
+
+    use super::*;
+    use crate::{
+        header::ResponseCode::{self, Servfail},
+        question::Question,
+    };
+
+    /// A clock that only moves when told to
+    struct FakeClock(Cell<Instant>);
+
+    impl FakeClock {
+        fn advance(&self, secs: u64) {
+            self.0.set(self.0.get() + Duration::from_secs(secs));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    fn limiter(config: RateLimitConfig) -> RateLimiter<FakeClock> {
+        RateLimiter::with_clock(config, FakeClock(Cell::new(Instant::now())))
+    }
+
+    fn config(responses_per_second: u32, slip: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            responses_per_second,
+            slip,
+            ..RateLimitConfig::default()
+        }
+    }
+
+    fn response(name: &str, rcode: ResponseCode) -> DNSPacket {
+        let mut que = Question::new();
+        que.name = String::from(name);
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.rcode = rcode;
+        res_packet.question_sec.push(que);
+        res_packet
+    }
+
+    fn client(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    /// Verdicts for the same response sent the given number of times without the clock moving
+    fn verdicts(
+        limiter: &RateLimiter<FakeClock>,
+        res_packet: &DNSPacket,
+        count: usize,
+    ) -> Vec<Verdict> {
+        (0..count)
+            .map(|_| limiter.check(client(1), res_packet))
+            .collect()
+    }
+
+    #[test]
+    fn bucket_runs_out_and_refills() {
+        let limiter = limiter(config(2, 0));
+        let answer = response("www.example.com", Noerror);
+
+        assert_eq!(
+            verdicts(&limiter, &answer, 3),
+            [Verdict::Send, Verdict::Send, Verdict::Drop]
+        );
+        // the whole block shares the bucket, other names have their own
+        assert_eq!(limiter.check(client(200), &answer), Verdict::Drop);
+        let other = response("mail.example.com", Noerror);
+        assert_eq!(limiter.check(client(1), &other), Verdict::Send);
+
+        // the two limited responses are paid off before anything more goes out
+        limiter.clock.advance(1);
+        assert_eq!(limiter.check(client(1), &answer), Verdict::Drop);
+        limiter.clock.advance(1);
+        assert_eq!(limiter.check(client(1), &answer), Verdict::Send);
+    }
+
+    #[test]
+    fn every_slip_th_limited_response_is_truncated() {
+        let limiter = limiter(config(1, 2));
+        let answer = response("www.example.com", Noerror);
+
+        assert_eq!(
+            verdicts(&limiter, &answer, 6),
+            [
+                Verdict::Send,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop,
+            ]
+        );
+    }
+
+    #[test]
+    fn nxdomains_and_errors_have_their_own_rates() {
+        let limiter = limiter(RateLimitConfig {
+            nxdomains_per_second: Some(2),
+            errors_per_second: Some(3),
+            ..config(1, 0)
+        });
+
+        // negative responses share a bucket whatever the name
+        let nxdomains = [
+            response("a.example.com", Nxdomain),
+            response("b.example.com", Nxdomain),
+            response("c.example.com", Nxdomain),
+        ];
+        let sent: Vec<Verdict> = nxdomains
+            .iter()
+            .map(|res_packet| limiter.check(client(1), res_packet))
+            .collect();
+        assert_eq!(sent, [Verdict::Send, Verdict::Send, Verdict::Drop]);
+
+        let error = response("a.example.com", Servfail);
+        assert_eq!(
+            verdicts(&limiter, &error, 4),
+            [Verdict::Send, Verdict::Send, Verdict::Send, Verdict::Drop]
+        );
+
+        let answer = response("a.example.com", Noerror);
+        assert_eq!(
+            verdicts(&limiter, &answer, 2),
+            [Verdict::Send, Verdict::Drop]
+        );
+    }
+
+    #[test]
+    fn log_only_sends_everything() {
+        let limiter = limiter(RateLimitConfig {
+            log_only: true,
+            ..config(1, 0)
+        });
+        let answer = response("www.example.com", Noerror);
+
+        assert_eq!(verdicts(&limiter, &answer, 3), [Verdict::Send; 3]);
+    }
+
+    #[test]
+    fn exempt_clients_are_not_limited() {
+        let limiter = limiter(RateLimitConfig {
+            exempt: vec![Cidr::parse("192.0.2.0/28").unwrap()],
+            ..config(1, 0)
+        });
+        let answer = response("www.example.com", Noerror);
+
+        assert_eq!(verdicts(&limiter, &answer, 3), [Verdict::Send; 3]);
+        // the rest of the block is counted as usual
+        assert_eq!(limiter.check(client(100), &answer), Verdict::Send);
+        assert_eq!(limiter.check(client(100), &answer), Verdict::Drop);
+    }
+
+    #[test]
+    fn flood_is_forgiven_after_the_window() {
+        let limiter = limiter(config(1, 0));
+        let answer = response("www.example.com", Noerror);
+        verdicts(&limiter, &answer, 1000);
+
+        // the debt is capped at a window's worth, however long the flood went on
+        limiter.clock.advance(limiter.config.window as u64 - 2);
+        assert_eq!(limiter.check(client(1), &answer), Verdict::Drop);
+        limiter.clock.advance(limiter.config.window as u64 + 1);
+        assert_eq!(limiter.check(client(1), &answer), Verdict::Send);
+    }
+
+    #[test]
+    fn bucket_table_is_capped() {
+        let limiter = limiter(config(1, 0));
+        let answer = response("www.example.com", Noerror);
+
+        // every client in a block of its own, none of them idle for long enough to be dropped
+        for block in 0..MAX_BUCKETS as u32 * 3 / 2 {
+            let client = IpAddr::from(Ipv4Addr::from(block << 8));
+            limiter.check(client, &answer);
+            assert!(limiter.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+        // only a share of the busy buckets made room each time
+        assert!(limiter.buckets.lock().unwrap().len() > MAX_BUCKETS / 2);
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{Condvar, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+
+use super::{
+    acl::Acl,
+    dns_packet::DNSPacket,
+    errors::{
+        Errors::{IOErr, Transfer},
+        Result,
+    },
+    header::{
+        Opcode,
+        ResponseCode::{Noerror, Notauth, Refused},
+    },
+    question::{QueryType, Question},
+    record::Record,
+    server::{query_server, ServerState},
+    tcp::{read_message, write_message},
+    tsig::{sign, ResponseVerifier, Signer, TsigKey},
+    zone::{labels, serial_gt, Zone},
+};
+
+const INITIAL_RETRY: Duration = Duration::from_secs(30); // retry interval before the first SOA is known
+const MIN_TIMER: Duration = Duration::from_secs(1); // never poll the primary faster than this
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(30); // give up on a stalled transfer after this long
+
+/// A zone copied from a primary server and kept up to date with it
+pub struct Secondary {
+    /// Domain name at the apex of the zone
+    pub origin: String,
+    /// Server the zone is transferred from
+    pub primary: SocketAddr,
+    /// Key the primary signs its transfers and notifies with
+    pub key: Option<TsigKey>,
+    /// Clients allowed to transfer the zone from us in turn
+    pub allow_transfer: Acl,
+    /// Set when a NOTIFY asks for an immediate refresh
+    notified: Mutex<bool>,
+    /// Wakes the refresh loop when notified
+    wakeup: Condvar,
+}
+
+/// Timers from the SOA of a zone
+struct SoaTimers {
+    refresh: Duration,
+    retry: Duration,
+    expire: Duration,
+}
+
+impl Secondary {
+    /// A secondary zone that has not been transferred yet
+    pub fn new(
+        origin: &str,
+        primary: SocketAddr,
+        key: Option<TsigKey>,
+        allow_transfer: Acl,
+    ) -> Self {
+        Secondary {
+            origin: String::from(origin.strip_suffix('.').unwrap_or(origin)),
+            primary,
+            key,
+            allow_transfer,
+            notified: Mutex::new(false),
+            wakeup: Condvar::new(),
+        }
+    }
+
+    /// Ask the refresh loop to check the primary right away
+    pub fn notify(&self) {
+        *self.notified.lock().unwrap_or_else(PoisonError::into_inner) = true;
+        self.wakeup.notify_one();
+    }
+
+    /// Sleep until the timer runs out or a NOTIFY arrives
+    fn wait(&self, timeout: Duration) {
+        let notified = self.notified.lock().unwrap_or_else(PoisonError::into_inner);
+        let (mut notified, _) = self
+            .wakeup
+            .wait_timeout_while(notified, timeout, |notified| !*notified)
+            .unwrap_or_else(PoisonError::into_inner);
+        *notified = false;
+    }
+}
+
+/// Serial number of an SOA record
+fn soa_serial(rec: &Record) -> Option<u32> {
+    match rec {
+        Record::Soa { serial, .. } => Some(*serial),
+        _ => None,
+    }
+}
+
+/// Refresh, retry and expire timers of the zone
+fn soa_timers(zone: &Zone) -> Option<SoaTimers> {
+    match zone.soa()? {
+        Record::Soa {
+            refresh,
+            retry,
+            expire,
+            ..
+        } => Some(SoaTimers {
+            refresh: Duration::from_secs(*refresh as u64),
+            retry: Duration::from_secs(*retry as u64),
+            expire: Duration::from_secs(*expire as u64),
+        }),
+        _ => None,
+    }
+}
+
+/// Keep a secondary zone in step with its primary forever
+///
+/// The primary's SOA serial is polled on the refresh timer, or the retry timer after a failure,
+/// and the zone stops being served once it could not be refreshed for the expire interval.
+pub fn run_secondary(state: &ServerState, secondary: &Secondary) {
+    let mut last_refresh: Option<Instant> = None;
+
+    loop {
+        let refreshed = match refresh(state, secondary) {
+            Ok(()) => {
+                last_refresh = Some(Instant::now());
+                true
+            }
+            Err(e) => {
+                eprintln!(
+                    "failed to refresh zone {} from {}: {}",
+                    secondary.origin, secondary.primary, e
+                );
+                false
+            }
+        };
+
+        let timers = state.zones().get(&secondary.origin).and_then(soa_timers);
+        let mut wait = match &timers {
+            Some(timers) if refreshed => timers.refresh,
+            Some(timers) => timers.retry,
+            None => INITIAL_RETRY,
+        };
+
+        if let (Some(timers), Some(last_refresh)) = (&timers, last_refresh) {
+            let until_expiry = timers.expire.saturating_sub(last_refresh.elapsed());
+            if until_expiry.is_zero() {
+                if let Some(zone) = state.zones_mut().get_mut(&secondary.origin) {
+                    zone.expired = true;
+                }
+            } else {
+                wait = wait.min(until_expiry);
+            }
+        }
+
+        secondary.wait(wait.max(MIN_TIMER));
+    }
+}
+
+/// Compare serials with the primary and transfer the zone if it has a newer version
+fn refresh(state: &ServerState, secondary: &Secondary) -> Result<()> {
+    let current = state
+        .zones()
+        .get(&secondary.origin)
+        .map(|zone| zone.records());
+    let current_serial = current.as_ref().and_then(|records| soa_serial(&records[0]));
+
+    let res = query_server(
+        secondary.primary,
+        &secondary.origin,
+        QueryType::Soa,
+        secondary.key.as_ref(),
+    )?;
+    let primary_serial = res
+        .answer_sec
+        .iter()
+        .find_map(soa_serial)
+        .ok_or_else(|| Transfer(String::from("primary did not answer with an SOA")))?;
+
+    if current_serial.is_some_and(|serial| !serial_gt(primary_serial, serial)) {
+        // the copy we hold is current again
+        if let Some(zone) = state.zones_mut().get_mut(&secondary.origin) {
+            zone.expired = false;
+        }
+        return Ok(());
+    }
+
+    let mut zone = transfer_zone(secondary, current.as_deref())?;
+    zone.allow_transfer = secondary.allow_transfer.clone();
+    state.zones_mut().replace(zone)
+}
+
+/// Transfer the zone from the primary, incrementally when we already hold a copy and in full if
+/// the primary will not or cannot give the changes, RFC 1995 section 4
+fn transfer_zone(secondary: &Secondary, current: Option<&[Record]>) -> Result<Zone> {
+    match transfer_in(secondary, current) {
+        Err(Transfer(e)) if current.is_some() => {
+            eprintln!(
+                "incremental transfer of zone {} failed, asking for all of it: {}",
+                secondary.origin, e
+            );
+            transfer_in(secondary, None)
+        }
+        result => result,
+    }
+}
+
+/// Transfer the zone from the primary with a single AXFR, or IXFR when we already hold a copy
+fn transfer_in(secondary: &Secondary, current: Option<&[Record]>) -> Result<Zone> {
+    let mut query = DNSPacket::new();
+    query.header.id = thread_rng().gen();
+
+    let mut que = Question::new();
+    que.name = secondary.origin.clone();
+    que.class = 1;
+    que.query_type = match current {
+        // the client tells the primary which version it has with an SOA in the authority section
+        Some(records) => {
+            query.authority_sec.push(records[0].clone());
+            QueryType::Ixfr
+        }
+        None => QueryType::Axfr,
+    };
+    query.question_sec.push(que);
+
+    let request_mac = match &secondary.key {
+        Some(key) => sign(&mut query, Signer::new(key.clone()))?,
+        None => Vec::new(),
+    };
+    let mut verifier = secondary
+        .key
+        .as_ref()
+        .map(|key| ResponseVerifier::new(key, request_mac));
+
+    let mut stream =
+        TcpStream::connect_timeout(&secondary.primary, TRANSFER_TIMEOUT).map_err(IOErr)?;
+    stream
+        .set_read_timeout(Some(TRANSFER_TIMEOUT))
+        .map_err(IOErr)?;
+    write_message(&mut stream, &query)?;
+
+    let mut records = Vec::new();
+    loop {
+        let mut res_buf = read_message(&mut stream)?
+            .ok_or_else(|| Transfer(String::from("primary closed the connection")))?;
+        let mut res = DNSPacket::new();
+        res.parse(&mut res_buf)?;
+        if let Some(verifier) = &mut verifier {
+            verifier.check(&res_buf, &res)?;
+        }
+
+        if !res.header.qr || res.header.id != query.header.id {
+            return Err(Transfer(String::from("unexpected message from primary")));
+        }
+        if res.header.rcode != Noerror {
+            return Err(Transfer(format!(
+                "primary refused the transfer with {:?}",
+                res.header.rcode
+            )));
+        }
+
+        records.extend(res.answer_sec);
+        if let Some(zone) = finish_transfer(&secondary.origin, &records, current)? {
+            // the last message of a signed transfer must itself be signed
+            if verifier
+                .as_ref()
+                .is_some_and(|verifier| !verifier.is_signed())
+            {
+                return Err(Transfer(String::from("last message is not signed")));
+            }
+            return Ok(zone);
+        }
+    }
+}
+
+/// Build the zone once every record of the transfer has arrived, None while more are expected
+///
+/// Handles full transfers, which end with the SOA repeated, and incremental ones, which are
+/// sequences of deleted and added records each opened by an SOA, RFC 1995.
+fn finish_transfer(
+    origin: &str,
+    records: &[Record],
+    current: Option<&[Record]>,
+) -> Result<Option<Zone>> {
+    let Some(first) = records.first() else {
+        return Ok(None);
+    };
+    let new_serial = soa_serial(first)
+        .ok_or_else(|| Transfer(String::from("transfer does not start with an SOA")))?;
+    let current_serial = current.and_then(|records| soa_serial(&records[0]));
+
+    if records.len() == 1 {
+        // a lone SOA means the copy we hold is already current
+        return match current {
+            Some(current) if current_serial.is_some_and(|s| !serial_gt(new_serial, s)) => {
+                Ok(Some(Zone::new(origin, current.to_vec())?))
+            }
+            _ => Ok(None),
+        };
+    }
+
+    let incremental = current_serial.is_some() && soa_serial(&records[1]) == current_serial;
+    let (Some(current), true) = (current, incremental) else {
+        // a full transfer is complete once the SOA comes round again
+        if soa_serial(&records[records.len() - 1]) == Some(new_serial) {
+            return Ok(Some(Zone::new(
+                origin,
+                records[..records.len() - 1].to_vec(),
+            )?));
+        }
+        return Ok(None);
+    };
+
+    let mut zone_records = current.to_vec();
+    let mut adding = true; // the SOA that opens the first deletions flips this off
+    for (pos, rec) in records.iter().enumerate().skip(1) {
+        match soa_serial(rec) {
+            Some(serial) if adding && serial == new_serial => {
+                if pos != records.len() - 1 {
+                    return Err(Transfer(String::from("records after the final SOA")));
+                }
+                zone_records[0] = first.clone();
+                return Ok(Some(Zone::new(origin, zone_records)?));
+            }
+            Some(_) => adding = !adding,
+            None if adding => zone_records.push(rec.clone()),
+            None => zone_records.retain(|existing| existing != rec),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Acknowledge a NOTIFY and refresh the zone it names right away, RFC 1996
+///
+/// Key names the TSIG key the NOTIFY was verified with.
+pub fn handle_notify(
+    query: &DNSPacket,
+    query_src: SocketAddr,
+    key: Option<&str>,
+    state: &ServerState,
+) -> DNSPacket {
+    let mut res = DNSPacket::new();
+    res.header.id = query.header.id;
+    res.header.qr = true;
+    res.header.aa = true;
+    res.header.op_code = Opcode::Notify;
+    res.question_sec = query.question_sec.clone();
+
+    // the NOTIFY is for a secondary of the view its sender is in
+    let view = state.view(query_src.ip());
+    let secondary = query.question_sec.first().and_then(|que| {
+        state
+            .secondaries_of(view)
+            .iter()
+            .find(|secondary| labels(&secondary.origin) == labels(&que.name))
+    });
+
+    match secondary {
+        None => res.header.rcode = Notauth,
+        // only the primary may tell us the zone changed
+        Some(secondary) if secondary.primary.ip() != query_src.ip().to_canonical() => {
+            res.header.rcode = Refused;
+        }
+        // with a key configured the NOTIFY must also be signed with it
+        Some(secondary)
+            if secondary
+                .key
+                .as_ref()
+                .is_some_and(|secondary_key| key != Some(secondary_key.name.as_str())) =>
+        {
+            res.header.rcode = Refused;
+        }
+        Some(secondary) => secondary.notify(),
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+    use crate::{
+        acl::Cidr, header::ResponseCode::Notimp, transfer::answer_transfer, zone::ZoneStore,
+        zone_file::parse_record,
+    };
+
+    fn zone(serial: u32, extra: &[&str]) -> Zone {
+        let soa = format!(
+            "example.com. 3600 IN SOA ns.example.com. hostmaster.example.com. {} 7200 900 1209600 300",
+            serial
+        );
+        let records = [soa.as_str(), "ns.example.com. 3600 IN A 192.0.2.1"]
+            .iter()
+            .chain(extra)
+            .map(|text| parse_record(text, 3600).unwrap())
+            .collect();
+
+        let mut zone = Zone::new("example.com", records).unwrap();
+        zone.allow_transfer
+            .blocks
+            .push(Cidr::parse("127.0.0.1").unwrap());
+        zone
+    }
+
+    /// Answer transfers from the zones on a loopback port, standing in for the primary
+    fn stand_in_primary(zones: ZoneStore) -> SocketAddr {
+        stand_in(zones, true)
+    }
+
+    /// A stand-in primary that answers IXFR with NOTIMP if it does not support it
+    fn stand_in(zones: ZoneStore, ixfr: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let client = stream.peer_addr().unwrap();
+                while let Some(mut buf) = read_message(&mut stream).unwrap() {
+                    let mut query = DNSPacket::new();
+                    query.parse(&mut buf).unwrap();
+                    if !ixfr && query.question_sec[0].query_type == QueryType::Ixfr {
+                        let mut res = query.clone();
+                        res.header.qr = true;
+                        res.header.rcode = Notimp;
+                        res.authority_sec.clear();
+                        write_message(&mut stream, &res).unwrap();
+                        continue;
+                    }
+                    for res in answer_transfer(&query, client, None, &zones, true).unwrap() {
+                        write_message(&mut stream, &res).unwrap();
+                    }
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn same_records(a: &Zone, b: &Zone) -> bool {
+        let (a, b) = (a.records(), b.records());
+        a.len() == b.len() && a.iter().all(|rec| b.contains(rec))
+    }
+
+    #[test]
+    fn full_and_incremental_transfer() {
+        let old = zone(1, &["www.example.com. 3600 IN A 192.0.2.2"]);
+        let new = zone(2, &["mail.example.com. 3600 IN A 192.0.2.3"]);
+
+        let mut zones = ZoneStore::default();
+        zones.insert(zone(1, &["www.example.com. 3600 IN A 192.0.2.2"]));
+        zones
+            .replace(zone(2, &["mail.example.com. 3600 IN A 192.0.2.3"]))
+            .unwrap();
+        let secondary =
+            Secondary::new("example.com", stand_in_primary(zones), None, Acl::default());
+
+        let full = transfer_in(&secondary, None).unwrap();
+        assert!(same_records(&full, &new));
+
+        let incremental = transfer_in(&secondary, Some(&old.records())).unwrap();
+        assert!(same_records(&incremental, &new));
+    }
+
+    #[test]
+    fn refused_ixfr_falls_back_to_axfr() {
+        let old = zone(1, &["www.example.com. 3600 IN A 192.0.2.2"]);
+        let new = zone(2, &["mail.example.com. 3600 IN A 192.0.2.3"]);
+
+        let mut zones = ZoneStore::default();
+        zones.insert(zone(2, &["mail.example.com. 3600 IN A 192.0.2.3"]));
+        let secondary = Secondary::new("example.com", stand_in(zones, false), None, Acl::default());
+
+        assert!(transfer_in(&secondary, Some(&old.records())).is_err());
+        let zone = transfer_zone(&secondary, Some(&old.records())).unwrap();
+        assert!(same_records(&zone, &new));
+    }
+
+    #[test]
+    fn expired_primary_refuses_transfer() {
+        let mut expired = zone(1, &[]);
+        expired.expired = true;
+        let mut zones = ZoneStore::default();
+        zones.insert(expired);
+        let secondary =
+            Secondary::new("example.com", stand_in_primary(zones), None, Acl::default());
+
+        assert!(transfer_in(&secondary, None).is_err());
+    }
+}
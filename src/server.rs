@@ -1,27 +1,956 @@
 use super::{
+    acl::{Acl, Cidr},
+    blocklist::{answer_blocked, Filter},
+    cache::{Cache, Refresh, CACHE_SIZE},
+    config::{Config, UpstreamConfig, ViewConfig, ZoneData},
+    cookie::{bad_cookie, is_cookie_echoed, ClientCookies, CookieCheck, ServerCookies},
+    dns64::Dns64,
     dns_packet::DNSPacket,
-    errors::{Errors::IOErr, Result},
-    header::ResponseCode::{Formerr, Servfail},
+    doh::{serve_https, HttpsUpstream, ALPN, HTTPS_PORT},
+    doq::{serve_quic, QuicUpstream, DOQ_ALPN, QUIC_PORT},
+    edns::{
+        add_option, dnssec_ok, edns_options, extended_errors, extended_rcode, opt_record,
+        set_dnssec_ok, strip_opt, udp_payload_size, ClientSubnet, ClientSubnetConfig,
+        ExtendedError, InfoCode, RCODE_BADCOOKIE,
+    },
+    errors::{
+        Errors::{CaseMismatch, Config as ConfigErr, IOErr, Resolve},
+        Result,
+    },
+    header::{
+        Opcode,
+        ResponseCode::{Formerr, Noerror, Notauth, Notimp, Nxdomain, Refused, Servfail},
+    },
+    local::LocalRecords,
+    nsec::{strip_dnssec, NsecCache},
     question::{QueryType, Question},
-    raw_packet::RawPacket,
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    record::Record,
+    rrl::{RateLimiter, Verdict},
+    secondary::{handle_notify, run_secondary, Secondary},
+    tcp::{read_message, serve_tcp, write_message},
+    tls::{client_config, serve_tls, server_config, TlsUpstream, TLS_PORT},
+    transfer::answer_transfer,
+    tsig::{sign, sign_all, verify_request, Keyring, ResponseVerifier, Signer, TsigKey},
+    update::handle_update,
+    zone::{is_in_zone, labels, Zone, ZoneStore},
 };
 
 use rand::{thread_rng, Rng};
-use std::net::{Ipv4Addr, UdpSocket};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    iter,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::Path,
+    ptr,
+    sync::{
+        atomic::Ordering, mpsc, Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 const UDP_PORT: u16 = 53; // Default UDP port for DNS Packets
 const DNS_RESOLVER_IP: Ipv4Addr = Ipv4Addr::new(8, 8, 8, 8); // Google's  public DNS server
 const LOOKUP_SERVER: (Ipv4Addr, u16) = (DNS_RESOLVER_IP, UDP_PORT);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5); // give up on the resolver after this long
+const MAX_REFERRALS: usize = 16; // referrals followed when resolving iteratively
+const MAX_GLUELESS: usize = 3; // name server addresses looked up within one another without glue
+const MAX_CASE_MISMATCHES: usize = 3; // timeouts on wrong-case responses before 0x20 is given up
+const CASE_RETRY_AFTER: Duration = Duration::from_secs(600); // 0x20 is tried again after this long
+const MAX_MINIMISE_COUNT: usize = 10; // queries for shortened names per lookup, RFC 9156 2.3
+const MINIMISE_ONE_LAB: usize = 4; // the first of them add one label each, the rest catch up
+
+const EPHEMERAL_PORTS: (u16, u16) = (49152, 65535); // IANA dynamic port range
+const BIND_ATTEMPTS: usize = 8; // random ports to try before letting the OS pick one
 
 const DNS_SERVER_IP: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const SERVER: (Ipv4Addr, u16) = (DNS_SERVER_IP, UDP_PORT);
 
-/// Perform a lookup for the given domain and requested record type
-pub fn lookup(query: &str, query_type: QueryType) -> Result<DNSPacket> {
-    // bind a UDP socket to an arbitrary port
-    let socket = UdpSocket::bind(SERVER).map_err(IOErr)?;
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10); // how often zone files are checked for changes
+
+/// Resolver lookups are sent to
+#[derive(Debug)]
+pub enum Upstream {
+    /// Plain DNS over UDP, signed with the TSIG key if given
+    Udp(SocketAddr, Option<TsigKey>),
+    /// Plain DNS over TCP, a new connection for each query, signed with the TSIG key if given
+    Tcp(SocketAddr, Option<TsigKey>),
+    /// DNS over TLS, RFC 7858
+    Tls(Box<TlsUpstream>),
+    /// DNS over HTTPS, RFC 8484
+    Https(Box<HttpsUpstream>),
+    /// DNS over QUIC, RFC 9250
+    Quic(Box<QuicUpstream>),
+}
+
+impl Default for Upstream {
+    fn default() -> Self {
+        Self::Udp(SocketAddr::from(LOOKUP_SERVER), None)
+    }
+}
+
+impl Upstream {
+    /// Set up the upstream, certificates checked against the CA file if given
+    fn from_config(
+        config: &UpstreamConfig,
+        ca_file: Option<&Path>,
+        keyring: &Keyring,
+    ) -> Result<Self> {
+        let key = |name: &Option<String>| match name {
+            Some(name) => keyring
+                .get(name)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| ConfigErr(format!("key {} is not in the keyring", name))),
+            None => Ok(None),
+        };
+
+        let upstream = match config {
+            UpstreamConfig::Udp(server, name) => Upstream::Udp(*server, key(name)?),
+            UpstreamConfig::Tcp(server, name) => Upstream::Tcp(*server, key(name)?),
+            UpstreamConfig::Tls { server, name, pins } => {
+                let client_config = client_config(ca_file, pins, &[])?;
+                Upstream::Tls(Box::new(TlsUpstream::new(*server, name, client_config)?))
+            }
+            UpstreamConfig::Https { server, url, pins } => {
+                let client_config = client_config(ca_file, pins, &[b"h2"])?;
+                Upstream::Https(Box::new(HttpsUpstream::new(*server, url, client_config)?))
+            }
+            UpstreamConfig::Quic { server, name, pins } => {
+                let client_config = client_config(ca_file, pins, &[DOQ_ALPN])?;
+                Upstream::Quic(Box::new(QuicUpstream::new(*server, name, client_config)?))
+            }
+        };
+
+        Ok(upstream)
+    }
+}
+
+/// Names under a domain sent to their own upstream instead of the default one
+#[derive(Debug)]
+pub struct ForwardRule {
+    /// Domain the rule covers, along with every name below it
+    pub domain: String,
+    pub upstream: Upstream,
+    /// Ask the upstream to recurse, or resolve iteratively by following its referrals
+    pub recursive: bool,
+    /// Port the name servers found through referrals are asked on
+    pub name_server_port: u16,
+}
+
+/// Options controlling how lookups are sent upstream
+#[derive(Debug, Default)]
+pub struct LookupOptions {
+    /// Randomize the case of the query name (DNS 0x20) and expect it echoed back
+    pub randomize_case: bool,
+    /// Resolver lookups are sent to, shared by every view
+    pub upstream: Arc<Upstream>,
+    /// Rules sending names under their domain elsewhere, the longest matching domain wins
+    pub forwards: Vec<ForwardRule>,
+    /// Responses kept from earlier lookups
+    pub cache: Cache,
+    /// Cookies sent to plain DNS servers, if cookies are enabled
+    pub cookies: Option<ClientCookies>,
+    /// Validated denials of existence answering for other names, if aggressive NSEC is enabled
+    pub nsec: Option<NsecCache>,
+    /// Times in a row each server let a randomized query time out with only wrong-case responses,
+    /// and when it last did
+    pub case_mismatches: Mutex<HashMap<SocketAddr, (usize, Instant)>>,
+}
+
+impl LookupOptions {
+    /// If queries to the server are still worth randomizing, it has not kept failing to echo
+    /// the case lately
+    fn echoes_case(&self, server: SocketAddr) -> bool {
+        let case_mismatches = self
+            .case_mismatches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        case_mismatches.get(&server).is_none_or(|(count, last)| {
+            *count < MAX_CASE_MISMATCHES || last.elapsed() >= CASE_RETRY_AFTER
+        })
+    }
+
+    fn note_case_mismatch(&self, server: SocketAddr) {
+        let mut case_mismatches = self
+            .case_mismatches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (count, last) = case_mismatches.entry(server).or_insert((0, Instant::now()));
+        *count += 1;
+        *last = Instant::now();
+    }
+
+    /// Forget the failures of a server that echoed the case, a timeout during an outage is no
+    /// reason to give up on it
+    fn note_case_echoed(&self, server: SocketAddr) {
+        self.case_mismatches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&server);
+    }
+}
+
+/// EDNS options the queries of a lookup carry upstream
+#[derive(Debug, Clone, Copy)]
+struct QueryEdns<'a> {
+    subnet: Option<&'a ClientSubnet>,
+    cookies: Option<&'a ClientCookies>,
+    /// Ask for DNSSEC records along with the answer
+    dnssec_ok: bool,
+}
+
+/// Zones, local records and forwarding rules answered to one set of clients
+pub struct View {
+    /// Address blocks of the clients the view is chosen for
+    pub clients: Vec<Cidr>,
+    /// How lookups are sent upstream, shared with lookups refreshing the cache in the background
+    pub options: Arc<LookupOptions>,
+    /// Zones answered authoritatively instead of recursing
+    zones: RwLock<ZoneStore>,
+    /// Static records answered before recursing
+    pub local: LocalRecords,
+}
+
+impl View {
+    /// Set up the view, loading the zones kept in master files
+    ///
+    /// Zones transferred from a primary are left out, they are filled in by their secondary.
+    fn from_config(
+        view_config: &ViewConfig,
+        config: &Config,
+        upstream: &Arc<Upstream>,
+        keyring: &Keyring,
+    ) -> Result<Self> {
+        let mut zones = ZoneStore::default();
+        for zone_config in &view_config.zones {
+            if let ZoneData::File(file) = &zone_config.data {
+                let mut zone = Zone::load(&zone_config.origin, file)?;
+                zone.allow_transfer = zone_config.allow_transfer.clone();
+                zone.allow_update = zone_config.allow_update.clone();
+                zones.insert(zone);
+            }
+        }
+
+        let ca_file = config.upstream_ca.as_deref();
+        let forwards = view_config
+            .forwards
+            .iter()
+            .map(|forward| {
+                Ok(ForwardRule {
+                    domain: forward.domain.clone(),
+                    upstream: Upstream::from_config(&forward.upstream, ca_file, keyring)?,
+                    recursive: forward.recursive,
+                    name_server_port: UDP_PORT,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(View {
+            clients: view_config.clients.clone(),
+            options: Arc::new(LookupOptions {
+                randomize_case: config.randomize_case,
+                upstream: Arc::clone(upstream),
+                forwards,
+                cache: Cache::new(
+                    config.cache_size.unwrap_or(CACHE_SIZE),
+                    config.serve_stale.clone(),
+                    config.prefetch.clone(),
+                ),
+                cookies: config.cookies.as_ref().map(|_| ClientCookies::default()),
+                nsec: config.aggressive_nsec.then(NsecCache::default),
+                case_mismatches: Mutex::default(),
+            }),
+            zones: RwLock::new(zones),
+            local: LocalRecords::new(
+                &view_config.local_records,
+                &view_config.local_subtrees,
+                config.local_ptr,
+            ),
+        })
+    }
+
+    /// Read access to the zones
+    pub fn zones(&self) -> RwLockReadGuard<'_, ZoneStore> {
+        self.zones.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write access to the zones
+    pub fn zones_mut(&self) -> RwLockWriteGuard<'_, ZoneStore> {
+        self.zones.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Everything the server needs to answer queries
+pub struct ServerState {
+    /// What clients outside of every other view are answered from
+    pub default_view: View,
+    /// Views chosen by client address, the first matching one wins
+    pub views: Vec<View>,
+    /// Zones transferred from a primary server
+    pub secondaries: Vec<Secondary>,
+    /// Keys messages are signed and verified with
+    pub keyring: Keyring,
+    /// Clients that may query at all, everyone if not given
+    pub allow_query: Option<Acl>,
+    /// Clients that may have names outside of our zones resolved, everyone if not given
+    pub allow_recursion: Option<Acl>,
+    /// Blocklists checked before recursing
+    pub filter: Filter,
+    /// Synthesis of AAAA records for IPv6-only clients, if it is enabled
+    pub dns64: Option<Dns64>,
+    /// How much of the client address goes upstream, if EDNS Client Subnet is enabled
+    pub client_subnet: Option<ClientSubnetConfig>,
+    /// Limits on UDP responses, which could be aimed at a spoofed source
+    pub rate_limiter: RateLimiter,
+    /// Server cookies handed out to UDP clients, if cookies are enabled
+    pub cookies: Option<ServerCookies>,
+    /// Certificate and key of the DNS over TLS listener, if it is enabled
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Certificate and key of the DNS over HTTPS listener, if it is enabled
+    pub https: Option<Arc<rustls::ServerConfig>>,
+    /// Certificate and key of the DNS over QUIC listener, if it is enabled
+    pub quic: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl ServerState {
+    /// Build the server state, loading every configured zone
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let keyring = match &config.keyring {
+            Some(path) => Keyring::load(path)?,
+            None => Keyring::default(),
+        };
+
+        // secondary zones are filled in by their first transfer
+        let mut secondaries = Vec::new();
+        for zone_config in &config.default_view.zones {
+            if let ZoneData::Transfer(primary, key) = &zone_config.data {
+                let key =
+                    match key {
+                        Some(name) => Some(keyring.get(name).cloned().ok_or_else(|| {
+                            ConfigErr(format!("key {} is not in the keyring", name))
+                        })?),
+                        None => None,
+                    };
+                secondaries.push(Secondary::new(
+                    &zone_config.origin,
+                    *primary,
+                    key,
+                    zone_config.allow_transfer.clone(),
+                ));
+            }
+        }
+
+        let upstream = Arc::new(match &config.upstream {
+            Some(upstream) => {
+                Upstream::from_config(upstream, config.upstream_ca.as_deref(), &keyring)?
+            }
+            None => Upstream::default(),
+        });
+        let default_view = View::from_config(&config.default_view, config, &upstream, &keyring)?;
+        let views = config
+            .views
+            .iter()
+            .map(|view_config| View::from_config(view_config, config, &upstream, &keyring))
+            .collect::<Result<Vec<_>>>()?;
+
+        let (tls, https, quic) = match (&config.tls_certificate, &config.tls_key) {
+            (Some(cert_file), Some(key_file)) => {
+                // each listener offers its own ALPN protocols
+                let listener = |enabled: bool, alpn: &[&[u8]]| match enabled {
+                    true => server_config(cert_file, key_file, alpn).map(Some),
+                    false => Ok(None),
+                };
+                (
+                    Some(server_config(cert_file, key_file, &[])?),
+                    listener(config.doh, &ALPN)?,
+                    listener(config.doq, &[DOQ_ALPN])?,
+                )
+            }
+            (None, None) if config.doh || config.doq => {
+                return Err(ConfigErr(String::from(
+                    "doh and doq need tls-certificate and tls-key",
+                )))
+            }
+            (None, None) => (None, None, None),
+            _ => {
+                return Err(ConfigErr(String::from(
+                    "tls-certificate and tls-key must be given together",
+                )))
+            }
+        };
+
+        Ok(ServerState {
+            default_view,
+            views,
+            secondaries,
+            keyring,
+            allow_query: config.allow_query.clone(),
+            allow_recursion: config.allow_recursion.clone(),
+            filter: Filter::load(&config.blocklists, &config.allowlists)?,
+            dns64: config.dns64.clone(),
+            client_subnet: config.client_subnet.clone(),
+            rate_limiter: RateLimiter::new(config.rate_limit.clone()),
+            cookies: config.cookies.as_ref().map(ServerCookies::new),
+            tls,
+            https,
+            quic,
+        })
+    }
+
+    /// The view a client is answered from
+    pub fn view(&self, client: IpAddr) -> &View {
+        self.views
+            .iter()
+            .find(|view| view.clients.iter().any(|block| block.contains(client)))
+            .unwrap_or(&self.default_view)
+    }
+
+    /// Secondary zones of the view, they only ever fill the default view
+    pub fn secondaries_of(&self, view: &View) -> &[Secondary] {
+        match ptr::eq(view, &self.default_view) {
+            true => &self.secondaries,
+            false => &[],
+        }
+    }
+
+    /// Origin of the secondary zone holding the name if the view has no copy of it yet
+    fn untransferred(&self, view: &View, zones: &ZoneStore, name: &str) -> Option<&str> {
+        let depth = zones
+            .find(name)
+            .map_or(0, |zone| labels(&zone.origin).len());
+        self.secondaries_of(view)
+            .iter()
+            .map(|secondary| secondary.origin.as_str())
+            .find(|origin| is_in_zone(name, origin) && labels(origin).len() > depth)
+    }
+
+    /// If the client may query at all, key is the name of the TSIG key the query was verified with
+    pub fn allows_query(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        self.allow_query
+            .as_ref()
+            .is_none_or(|acl| acl.allows(addr, key))
+    }
+
+    /// If the client may have names outside of our zones resolved for it
+    pub fn allows_recursion(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        self.allow_recursion
+            .as_ref()
+            .is_none_or(|acl| acl.allows(addr, key))
+    }
+
+    /// Read access to the zones of the default view, where secondary zones are kept up to date
+    pub fn zones(&self) -> RwLockReadGuard<'_, ZoneStore> {
+        self.default_view.zones()
+    }
+
+    /// Write access to the zones of the default view, for the secondary zones living there
+    pub fn zones_mut(&self) -> RwLockWriteGuard<'_, ZoneStore> {
+        self.default_view.zones_mut()
+    }
+}
+
+/// Bind a UDP socket to a random ephemeral port so responses are harder to spoof
+fn bind_ephemeral(server: SocketAddr) -> Result<UdpSocket> {
+    // use the same address family as the server being queried
+    let local: IpAddr = match server {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+
+    let mut rng = thread_rng();
+    for _ in 0..BIND_ATTEMPTS {
+        let port = rng.gen_range(EPHEMERAL_PORTS.0..=EPHEMERAL_PORTS.1);
+        match UdpSocket::bind((local, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(IOErr(e)),
+        }
+    }
+
+    // fall back to whichever port the OS hands out
+    UdpSocket::bind((local, 0)).map_err(IOErr)
+}
+
+/// Check that a response really answers the outstanding query
+pub fn is_valid_response(
+    query: &DNSPacket,
+    server: SocketAddr,
+    res: &DNSPacket,
+    res_src: SocketAddr,
+) -> bool {
+    res_src == server
+        && res.header.qr
+        && res.header.id == query.header.id
+        && res.question_sec.len() == query.question_sec.len()
+        && res
+            .question_sec
+            .iter()
+            .zip(&query.question_sec)
+            .all(|(res_que, que)| res_que.matches(que))
+        && is_cookie_echoed(query, res)
+}
+
+/// If the response echoes the query names with exactly the same case
+fn is_case_echoed(query: &DNSPacket, res: &DNSPacket) -> bool {
+    res.question_sec
+        .iter()
+        .zip(&query.question_sec)
+        .all(|(res_que, que)| res_que.name == que.name)
+}
+
+/// Flip the case of each letter in the domain at random
+fn randomize_case(domain: &str) -> String {
+    let mut rng = thread_rng();
+    domain
+        .chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+/// Perform a lookup for the given domain and requested record type, answering from the cache
+/// where possible
+///
+/// The client subnet, if given, goes upstream with the query and comes back in an OPT record of
+/// the response carrying the scope the answer is valid for, along with any Extended DNS Errors.
+/// With serve-stale enabled an expired answer is given instead of a failure, or of a fresh
+/// answer that takes too long, RFC 8767.
+pub fn lookup(
+    query: &str,
+    query_type: QueryType,
+    subnet: Option<&ClientSubnet>,
+    options: &Arc<LookupOptions>,
+) -> Result<DNSPacket> {
+    if let Some((mut res_packet, scope)) = options.cache.get(query, &query_type, subnet) {
+        add_client_subnet(&mut res_packet, subnet, scope);
+        return Ok(res_packet);
+    }
+    // a cached denial covering the name spares asking upstream, RFC 8198
+    if let Some(nsec) = &options.nsec {
+        if let Some(res_packet) = nsec.synthesize(query, &query_type) {
+            return Ok(res_packet);
+        }
+    }
+
+    let (Some(serve_stale), Some((mut res_packet, scope, refresh))) = (
+        &options.cache.serve_stale,
+        options.cache.get_stale(query, &query_type, subnet),
+    ) else {
+        return lookup_fresh(query, query_type, subnet, options);
+    };
+
+    let text = match refresh {
+        Refresh::InFlight => String::from("fresh answer under way"),
+        Refresh::Recheck => String::from("upstream failed recently"),
+        Refresh::Start => {
+            // the lookup goes on in the background after the client timer, the cache is refreshed
+            // anyway
+            let (sender, receiver) = mpsc::channel();
+            let (fresh_query, fresh_type, fresh_subnet) =
+                (String::from(query), query_type.clone(), subnet.copied());
+            let fresh_options = Arc::clone(options);
+            thread::spawn(move || {
+                let result = lookup_fresh(
+                    &fresh_query,
+                    fresh_type.clone(),
+                    fresh_subnet.as_ref(),
+                    &fresh_options,
+                );
+                let failed = result.as_ref().map_or(true, |fresh| {
+                    matches!(fresh.header.rcode, Servfail | Refused)
+                });
+                fresh_options.cache.finish_refresh(
+                    &fresh_query,
+                    &fresh_type,
+                    fresh_subnet.as_ref(),
+                    failed,
+                );
+                let _ = sender.send(result);
+            });
+
+            match receiver.recv_timeout(serve_stale.client_timeout) {
+                Ok(Ok(fresh)) if !matches!(fresh.header.rcode, Servfail | Refused) => {
+                    return Ok(fresh)
+                }
+                Ok(Ok(_)) => String::from("upstream failed to answer"),
+                Ok(Err(e)) => ExtendedError::from(&e).text,
+                Err(_) => format!(
+                    "no fresh answer within {} ms",
+                    serve_stale.client_timeout.as_millis()
+                ),
+            }
+        }
+    };
+    let code = match res_packet.header.rcode {
+        Nxdomain => InfoCode::StaleNxdomainAnswer,
+        _ => InfoCode::StaleAnswer,
+    };
+
+    add_client_subnet(&mut res_packet, subnet, scope);
+    add_option(&mut res_packet, ExtendedError::new(code, &text).to_option());
+    Ok(res_packet)
+}
+
+/// Look the name up upstream and keep the response in the cache
+fn lookup_fresh(
+    query: &str,
+    query_type: QueryType,
+    subnet: Option<&ClientSubnet>,
+    options: &LookupOptions,
+) -> Result<DNSPacket> {
+    let edns = QueryEdns {
+        subnet,
+        cookies: options.cookies.as_ref(),
+        dnssec_ok: options.nsec.is_some(),
+    };
+    let uses_edns = subnet.is_some() || edns.cookies.is_some() || edns.dnssec_ok;
+    let mut res_packet = match lookup_upstream(
+        query,
+        query_type.clone(),
+        uses_edns.then_some(edns),
+        options,
+    ) {
+        // an upstream without EDNS rejects the options, RFC 6891 section 7
+        Ok(res) if res.header.rcode == Formerr && uses_edns => {
+            lookup_upstream(query, query_type.clone(), None, options)?
+        }
+        result => result?,
+    };
+
+    // the answer is shared with every client in the scope, never beyond the subnet that was sent,
+    // RFC 7871 section 7.3.1
+    let scope = subnet.and_then(|sent| {
+        let echoed = ClientSubnet::from_packet(&res_packet).filter(|res| sent.is_echoed_by(res))?;
+        let prefix = echoed.scope_prefix.min(sent.source.prefix());
+        (prefix > 0).then(|| Cidr::around(sent.source.addr(), prefix))
+    });
+    let errors = extended_errors(&res_packet);
+    strip_opt(&mut res_packet);
+    if let Some(nsec) = &options.nsec {
+        nsec.learn(&res_packet);
+    }
+    options.cache.insert(query, &query_type, &res_packet, scope);
+
+    add_client_subnet(&mut res_packet, subnet, scope);
+    // e.g. a validating upstream explains its SERVFAIL for a bogus answer
+    for option in errors {
+        add_option(&mut res_packet, option);
+    }
+    Ok(res_packet)
+}
 
-    // build query packet
+/// Note in an OPT record which clients the response was tailored to, so it can be passed on
+fn add_client_subnet(
+    res_packet: &mut DNSPacket,
+    subnet: Option<&ClientSubnet>,
+    scope: Option<Cidr>,
+) {
+    if let Some(subnet) = subnet {
+        let echoed = ClientSubnet {
+            source: subnet.source,
+            scope_prefix: scope.map_or(0, |scope| scope.prefix()),
+        };
+        res_packet
+            .additional_sec
+            .push(opt_record(vec![echoed.to_option()]));
+    }
+}
+
+/// Send the lookup upstream
+///
+/// Names under a forwarding rule go to the upstream of the rule with the longest matching domain,
+/// everything else to the default upstream.
+fn lookup_upstream(
+    query: &str,
+    query_type: QueryType,
+    edns: Option<QueryEdns>,
+    options: &LookupOptions,
+) -> Result<DNSPacket> {
+    route(query, query_type, edns, options, 0)
+}
+
+/// Send the lookup upstream, glueless counting the name server lookups it is nested in
+fn route(
+    query: &str,
+    query_type: QueryType,
+    edns: Option<QueryEdns>,
+    options: &LookupOptions,
+    glueless: usize,
+) -> Result<DNSPacket> {
+    let rule = options
+        .forwards
+        .iter()
+        .filter(|rule| is_in_zone(query, &rule.domain))
+        .max_by_key(|rule| labels(&rule.domain).len());
+
+    match rule {
+        Some(rule) if rule.recursive => forward(&rule.upstream, query, query_type, edns, options),
+        Some(rule) => iterate(query, query_type, edns, rule, options, glueless),
+        None => forward(&options.upstream, query, query_type, edns, options),
+    }
+}
+
+/// Build a query for a lookup, with an OPT record carrying the client subnet and DO bit if it
+/// uses EDNS
+///
+/// Cookies are added as the query is sent, they differ from server to server.
+fn lookup_query(query: &str, query_type: QueryType, edns: Option<QueryEdns>) -> DNSPacket {
+    let mut query_packet = new_query(query, query_type);
+    if let Some(edns) = edns {
+        let options = edns.subnet.map(|subnet| subnet.to_option());
+        query_packet
+            .additional_sec
+            .push(opt_record(options.into_iter().collect()));
+        if edns.dnssec_ok {
+            set_dnssec_ok(&mut query_packet);
+        }
+    }
+
+    query_packet
+}
+
+/// Ask the upstream to recurse for the name
+fn forward(
+    upstream: &Upstream,
+    query: &str,
+    query_type: QueryType,
+    edns: Option<QueryEdns>,
+    options: &LookupOptions,
+) -> Result<DNSPacket> {
+    let cookies = edns.and_then(|edns| edns.cookies);
+    // the name is already hidden inside TLS, 0x20 only helps over plain UDP
+    if let (Upstream::Udp(server, key), true) = (upstream, options.randomize_case) {
+        if options.echoes_case(*server) {
+            let query_packet = lookup_query(&randomize_case(query), query_type.clone(), edns);
+            match exchange_plain(*server, false, query_packet, true, cookies, key.as_ref()) {
+                // upstream does not echo the case, retry with the name as given
+                Err(CaseMismatch) => options.note_case_mismatch(*server),
+                Ok(res_packet) => {
+                    options.note_case_echoed(*server);
+                    return Ok(res_packet);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    send_query(upstream, lookup_query(query, query_type, edns), cookies)
+}
+
+/// Send a query over the transport of the upstream, with our cookie if it is plain DNS
+fn send_query(
+    upstream: &Upstream,
+    query_packet: DNSPacket,
+    cookies: Option<&ClientCookies>,
+) -> Result<DNSPacket> {
+    match upstream {
+        Upstream::Udp(server, key) => {
+            exchange_plain(*server, false, query_packet, false, cookies, key.as_ref())
+        }
+        Upstream::Tcp(server, key) => {
+            exchange_plain(*server, true, query_packet, false, cookies, key.as_ref())
+        }
+        Upstream::Tls(upstream) => upstream.send(&query_packet),
+        Upstream::Https(upstream) => upstream.send(query_packet),
+        Upstream::Quic(upstream) => upstream.send(query_packet),
+    }
+}
+
+/// Zone cut a response refers the query to, None when it is an answer
+fn referral(res_packet: &DNSPacket, query: &str) -> Option<String> {
+    // an authoritative response is an answer, even one listing the name servers of its zone
+    if res_packet.header.rcode != Noerror
+        || !res_packet.answer_sec.is_empty()
+        || res_packet.header.aa
+    {
+        return None;
+    }
+
+    res_packet.authority_sec.iter().find_map(|rec| match rec {
+        Record::NS { preamble, .. } if is_in_zone(query, &preamble.name) => {
+            Some(preamble.name.clone())
+        }
+        _ => None,
+    })
+}
+
+/// Address of one of the name servers from the glue of a referral, IPv4 preferred as a server may
+/// still only have an IPv6 address
+fn glue_address(res_packet: &DNSPacket, names: &[&str]) -> Option<IpAddr> {
+    let is_server = |owner: &str| names.iter().any(|name| labels(name) == labels(owner));
+    let v4 = res_packet
+        .additional_sec
+        .iter()
+        .filter_map(|rec| match rec {
+            Record::A { preamble, ip } if is_server(&preamble.name) => Some(IpAddr::V4(*ip)),
+            _ => None,
+        });
+    let v6 = res_packet
+        .additional_sec
+        .iter()
+        .filter_map(|rec| match rec {
+            Record::Aaaa { preamble, ip } if is_server(&preamble.name) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        });
+
+    v4.chain(v6).next()
+}
+
+/// Address of a name server of the zone cut, from glue or else looked up the way any other name
+/// would be, iteratively if a rule says so
+fn name_server(
+    res_packet: &DNSPacket,
+    cut: &str,
+    port: u16,
+    options: &LookupOptions,
+    glueless: usize,
+) -> Result<SocketAddr> {
+    let names: Vec<&str> = res_packet
+        .authority_sec
+        .iter()
+        .filter_map(|rec| match rec {
+            Record::NS { preamble, name } if labels(&preamble.name) == labels(cut) => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+
+    if let Some(ip) = glue_address(res_packet, &names) {
+        return Ok(SocketAddr::from((ip, port)));
+    }
+
+    // servers without glue that keep needing others without glue could go round forever
+    if glueless < MAX_GLUELESS {
+        for name in names {
+            for query_type in [QueryType::A, QueryType::Aaaa] {
+                let Ok(res) = route(name, query_type, None, options, glueless + 1) else {
+                    continue;
+                };
+                let ip = res.answer_sec.iter().find_map(|rec| match rec {
+                    Record::A { ip, .. } => Some(IpAddr::V4(*ip)),
+                    Record::Aaaa { ip, .. } => Some(IpAddr::V6(*ip)),
+                    _ => None,
+                });
+                if let Some(ip) = ip {
+                    return Ok(SocketAddr::from((ip, port)));
+                }
+            }
+        }
+    }
+
+    Err(Resolve(format!(
+        "no address for the name servers of {}",
+        cut
+    )))
+}
+
+/// Resolve the name starting at the server of the rule, following referrals instead of asking
+/// for recursion
+///
+/// Servers are only asked for one label more than the zone cut they were found for, until the
+/// full name is reached, RFC 9156. One answering NXDOMAIN for a shortened name may be wrong about
+/// an empty non-terminal, so from then on the full name is asked for instead.
+fn iterate(
+    query: &str,
+    query_type: QueryType,
+    edns: Option<QueryEdns>,
+    rule: &ForwardRule,
+    options: &LookupOptions,
+    glueless: usize,
+) -> Result<DNSPacket> {
+    let cookies = edns.and_then(|edns| edns.cookies);
+    let query_labels: Vec<&str> = query.split('.').filter(|label| !label.is_empty()).collect();
+    let mut server = None; // the rule's own upstream until the first referral
+    let mut depth = 0;
+    // labels of the name asked for so far, the server of the rule already knows its domain
+    let mut asked = labels(&rule.domain).len().min(query_labels.len());
+    let mut minimised = 0;
+    let mut minimise = true;
+
+    for _ in 0..MAX_REFERRALS + MAX_MINIMISE_COUNT {
+        let remaining = query_labels.len() - asked;
+        if minimise && remaining > 0 && minimised < MAX_MINIMISE_COUNT {
+            asked += match minimised < MINIMISE_ONE_LAB {
+                true => 1,
+                false => remaining.div_ceil(MAX_MINIMISE_COUNT - minimised),
+            };
+            minimised += 1;
+        } else {
+            asked = query_labels.len();
+        }
+
+        // a shortened name is asked for its A records, the type most servers handle well
+        let full = asked == query_labels.len();
+        let (name, name_type) = match full {
+            true => (String::from(query), query_type.clone()),
+            false => (
+                query_labels[query_labels.len() - asked..].join("."),
+                QueryType::A,
+            ),
+        };
+        let mut query_packet = lookup_query(&name, name_type, edns);
+        query_packet.header.rd = false;
+        let res_packet = match server {
+            None => send_query(&rule.upstream, query_packet, cookies)?,
+            Some(server) => exchange_plain(server, false, query_packet, false, cookies, None)?,
+        };
+
+        let Some(cut) = referral(&res_packet, &name) else {
+            if full {
+                return Ok(res_packet);
+            }
+            // the shortened name exists or is an empty non-terminal, the next label is asked of
+            // the same server, anything else gets the full name
+            let aliased = res_packet
+                .answer_sec
+                .iter()
+                .any(|rec| matches!(rec, Record::Cname { .. } | Record::Dname { .. }));
+            if res_packet.header.rcode != Noerror || aliased {
+                minimise = false;
+            }
+            continue;
+        };
+        // every referral has to lead closer to the name, or it could go round forever
+        if labels(&cut).len() <= depth {
+            return Err(Resolve(format!(
+                "referral to {} leads away from {}",
+                cut, query
+            )));
+        }
+        depth = labels(&cut).len();
+        server = Some(name_server(
+            &res_packet,
+            &cut,
+            rule.name_server_port,
+            options,
+            glueless,
+        )?);
+    }
+
+    Err(Resolve(format!("too many referrals for {}", query)))
+}
+
+/// Ask the given server directly, e.g. the primary of a secondary zone, signing with the key
+pub fn query_server(
+    server: SocketAddr,
+    query: &str,
+    query_type: QueryType,
+    key: Option<&TsigKey>,
+) -> Result<DNSPacket> {
+    exchange(server, new_query(query, query_type), false, key)
+}
+
+/// Build a recursive query for the given domain and record type
+pub fn new_query(query: &str, query_type: QueryType) -> DNSPacket {
     let mut query_packet = DNSPacket::new();
     query_packet.header.id = thread_rng().gen(); // generate random transaction ID
     query_packet.header.qd_count = 1; // always one query
@@ -34,29 +963,312 @@ pub fn lookup(query: &str, query_type: QueryType) -> Result<DNSPacket> {
     que.class = 1; // always 1 in practice
     query_packet.question_sec.push(que);
 
+    query_packet
+}
+
+/// Send a query to the server over plain UDP or TCP, with our cookie for the server if cookies are
+/// enabled
+///
+/// A server that wants its own cookie back first is asked once more with it, RFC 7873 section 5.3.
+/// A truncated UDP response is retried over TCP, RFC 7766 section 5. With a key the query is
+/// signed and only a response signed with the same key accepted.
+fn exchange_plain(
+    server: SocketAddr,
+    tcp: bool,
+    query_packet: DNSPacket,
+    exact_case: bool,
+    cookies: Option<&ClientCookies>,
+    key: Option<&TsigKey>,
+) -> Result<DNSPacket> {
+    let send = |mut query_packet: DNSPacket| -> Result<DNSPacket> {
+        if let Some(cookies) = cookies {
+            add_option(&mut query_packet, cookies.option_for(server.ip()));
+        }
+        let res_packet = match tcp {
+            true => exchange_tcp(server, query_packet, key)?,
+            // whatever did not fit is asked for again over TCP, DNSSEC records rarely fit
+            false => match exchange(server, query_packet.clone(), exact_case, key)? {
+                res_packet if res_packet.header.tc => exchange_tcp(server, query_packet, key)?,
+                res_packet => res_packet,
+            },
+        };
+        if let Some(cookies) = cookies {
+            cookies.learn(server.ip(), &res_packet);
+        }
+        Ok(res_packet)
+    };
+
+    if cookies.is_none() {
+        return send(query_packet);
+    }
+    let res_packet = send(query_packet.clone())?;
+    if extended_rcode(&res_packet) != RCODE_BADCOOKIE {
+        return Ok(res_packet);
+    }
+
+    let res_packet = send(query_packet)?;
+    match extended_rcode(&res_packet) {
+        RCODE_BADCOOKIE => Err(Resolve(format!("{} keeps rejecting our cookie", server))),
+        _ => Ok(res_packet),
+    }
+}
+
+/// Remove the TSIG of a verified response, it is not passed on to whoever asked us
+fn strip_tsig(res_packet: &mut DNSPacket) {
+    res_packet
+        .additional_sec
+        .retain(|rec| !matches!(rec, Record::Tsig { .. }));
+}
+
+/// Send a single query to the server and wait for the response that answers it
+fn exchange(
+    server: SocketAddr,
+    mut query_packet: DNSPacket,
+    exact_case: bool,
+    key: Option<&TsigKey>,
+) -> Result<DNSPacket> {
+    // bind a UDP socket to a random ephemeral port, the kernel drops datagrams from anyone else
+    let socket = bind_ephemeral(server)?;
+    socket.connect(server).map_err(IOErr)?;
+
+    let request_mac = match key {
+        Some(key) => sign(&mut query_packet, Signer::new(key.clone()))?,
+        None => Vec::new(),
+    };
+
     // write query_packet to buffer
     let mut query_buf = RawPacket::new();
     query_packet.write(&mut query_buf)?;
 
     // send query packet to DNS resolver
     socket
-        .send_to(&query_buf.buf[0..query_buf.cursor()], LOOKUP_SERVER)
+        .send(&query_buf.buf[0..query_buf.cursor()])
         .map_err(IOErr)?;
 
-    // buffer to store response packet
-    let mut res_buf = RawPacket::new();
-    // write received data into buffer
-    socket.recv_from(&mut res_buf.buf).map_err(IOErr)?;
+    // a response with the case of the name changed may be spoofed, the real one can still come
+    let mut mismatched = false;
+    // datagrams that answer nothing must not keep the lookup waiting past its timeout
+    let deadline = Instant::now() + LOOKUP_TIMEOUT;
 
-    // parse response packet into DNS Packet
-    let mut res_packet = DNSPacket::new();
-    res_packet.parse(&mut res_buf)?;
+    // drop anything that does not answer our query and keep waiting
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(match mismatched {
+                true => CaseMismatch,
+                false => IOErr(ErrorKind::TimedOut.into()),
+            });
+        }
+        socket.set_read_timeout(Some(left)).map_err(IOErr)?;
 
-    Ok(res_packet)
+        // buffer to store response packet
+        let mut res_buf = RawPacket::new();
+        // write received data into buffer
+        let res_src = match socket.recv_from(&mut res_buf.buf) {
+            Ok((_, res_src)) => res_src,
+            // only a server that never echoes the case lets the lookup go on without it
+            Err(e)
+                if mismatched
+                    && matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+            {
+                return Err(CaseMismatch);
+            }
+            Err(e) => return Err(IOErr(e)),
+        };
+
+        // parse response packet into DNS Packet
+        let mut res_packet = DNSPacket::new();
+        if res_packet.parse(&mut res_buf).is_err() {
+            continue;
+        }
+
+        if !is_valid_response(&query_packet, server, &res_packet, res_src) {
+            continue;
+        }
+
+        // a response to a signed query must be signed with the same key
+        if let Some(key) = key {
+            let mut verifier = ResponseVerifier::new(key, request_mac.clone());
+            if let Err(e) = verifier.check(&res_buf, &res_packet) {
+                eprintln!("dropping response from {}: {}", res_src, e);
+                continue;
+            }
+            strip_tsig(&mut res_packet);
+        }
+
+        if exact_case && !is_case_echoed(&query_packet, &res_packet) {
+            mismatched = true;
+            continue;
+        }
+
+        return Ok(res_packet);
+    }
+}
+
+/// Send a single query over a new TCP connection and wait for the response that answers it
+fn exchange_tcp(
+    server: SocketAddr,
+    mut query_packet: DNSPacket,
+    key: Option<&TsigKey>,
+) -> Result<DNSPacket> {
+    let mut stream = TcpStream::connect_timeout(&server, LOOKUP_TIMEOUT).map_err(IOErr)?;
+    stream
+        .set_read_timeout(Some(LOOKUP_TIMEOUT))
+        .map_err(IOErr)?;
+
+    let mut verifier = match key {
+        Some(key) => {
+            let request_mac = sign(&mut query_packet, Signer::new(key.clone()))?;
+            Some(ResponseVerifier::new(key, request_mac))
+        }
+        None => None,
+    };
+    write_message(&mut stream, &query_packet)?;
+
+    loop {
+        let mut res_buf = read_message(&mut stream)?
+            .ok_or_else(|| Resolve(String::from("upstream closed the connection")))?;
+        let mut res_packet = DNSPacket::new();
+        res_packet.parse(&mut res_buf)?;
+
+        if !is_valid_response(&query_packet, server, &res_packet, server) {
+            continue;
+        }
+        // a response to a signed query must be signed with the same key
+        if let Some(verifier) = &mut verifier {
+            verifier.check(&res_buf, &res_packet)?;
+            strip_tsig(&mut res_packet);
+        }
+
+        return Ok(res_packet);
+    }
+}
+
+/// Serve DNS queries over UDP and TCP on the default port, and TLS, HTTPS and QUIC if configured,
+/// forever
+pub fn serve(state: ServerState) -> Result<()> {
+    let state = Arc::new(state);
+    let socket = UdpSocket::bind(SERVER).map_err(IOErr)?;
+    let listener = TcpListener::bind(SERVER).map_err(IOErr)?;
+
+    let tcp_state = Arc::clone(&state);
+    thread::spawn(move || serve_tcp(listener, tcp_state));
+
+    if let Some(tls) = &state.tls {
+        let tls_listener = TcpListener::bind((DNS_SERVER_IP, TLS_PORT)).map_err(IOErr)?;
+        let (tls, tls_state) = (Arc::clone(tls), Arc::clone(&state));
+        thread::spawn(move || serve_tls(tls_listener, tls, tls_state));
+    }
+
+    if let Some(https) = &state.https {
+        let https_listener = TcpListener::bind((DNS_SERVER_IP, HTTPS_PORT)).map_err(IOErr)?;
+        let (https, https_state) = (Arc::clone(https), Arc::clone(&state));
+        thread::spawn(move || {
+            if let Err(e) = serve_https(https_listener, https, https_state) {
+                eprintln!("failed to serve DNS over HTTPS: {}", e);
+            }
+        });
+    }
+
+    if let Some(quic) = &state.quic {
+        let quic_socket = UdpSocket::bind((DNS_SERVER_IP, QUIC_PORT)).map_err(IOErr)?;
+        let (quic, quic_state) = (Arc::clone(quic), Arc::clone(&state));
+        thread::spawn(move || {
+            if let Err(e) = serve_quic(quic_socket, quic, quic_state) {
+                eprintln!("failed to serve DNS over QUIC: {}", e);
+            }
+        });
+    }
+
+    let reload_state = Arc::clone(&state);
+    thread::spawn(move || reload_zones(&reload_state));
+
+    for view in iter::once(&state.default_view).chain(&state.views) {
+        if let Some(prefetch) = &view.options.cache.prefetch {
+            let (options, rate) = (Arc::clone(&view.options), prefetch.rate);
+            thread::spawn(move || run_prefetch(&options, rate));
+        }
+    }
+
+    if !state.filter.is_empty() {
+        let filter_state = Arc::clone(&state);
+        thread::spawn(move || reload_blocklists(&filter_state.filter));
+    }
+
+    for pos in 0..state.secondaries.len() {
+        let secondary_state = Arc::clone(&state);
+        thread::spawn(move || run_secondary(&secondary_state, &secondary_state.secondaries[pos]));
+    }
+
+    loop {
+        if let Err(e) = handle_query(&socket, &state) {
+            eprintln!("failed to handle query: {}", e);
+        }
+    }
 }
 
-/// Handle recursive lookups if required
-fn handle_query(socket: &UdpSocket) -> Result<()> {
+/// Reload zones whose master file changed, so edits reach clients and secondaries
+fn reload_zones(state: &ServerState) {
+    loop {
+        thread::sleep(RELOAD_INTERVAL);
+
+        for view in iter::once(&state.default_view).chain(&state.views) {
+            // parse outside of the lock so queries are not held up
+            let stale = view.zones().stale_sources();
+            for (origin, path) in stale {
+                let reloaded =
+                    Zone::load(&origin, &path).and_then(|zone| view.zones_mut().replace(zone));
+                if let Err(e) = reloaded {
+                    eprintln!("failed to reload zone {}: {}", origin, e);
+                }
+            }
+        }
+    }
+}
+
+/// Refresh the popular answers the cache queues before they expire, no faster than the rate
+fn run_prefetch(options: &LookupOptions, rate: u32) {
+    let interval = Duration::from_secs(1) / rate.max(1);
+    while let Some(job) = options.cache.next_prefetch() {
+        let result = lookup_fresh(
+            &job.name,
+            job.query_type.clone(),
+            job.subnet.as_ref(),
+            options,
+        );
+        if let Err(e) = result {
+            eprintln!("failed to prefetch {}: {}", job.name, e);
+        }
+        // a failed refresh leaves the old response in place, a later hit may queue it again
+        options
+            .cache
+            .finish_prefetch(&job.name, &job.query_type, job.subnet.as_ref());
+        thread::sleep(interval);
+    }
+}
+
+/// Reload blocklists whose file changed and report how many queries each list blocked
+fn reload_blocklists(filter: &Filter) {
+    let mut reported = vec![0; filter.lists.len()];
+    loop {
+        thread::sleep(RELOAD_INTERVAL);
+
+        if let Err(e) = filter.reload() {
+            eprintln!("failed to reload blocklists: {}", e);
+        }
+
+        for (list, reported) in filter.lists.iter().zip(&mut reported) {
+            let blocked = list.blocked.load(Ordering::Relaxed);
+            if blocked != *reported {
+                eprintln!("{}: {} queries blocked", list.path.display(), blocked);
+                *reported = blocked;
+            }
+        }
+    }
+}
+
+/// Receive one query over UDP and send back its response
+fn handle_query(socket: &UdpSocket, state: &ServerState) -> Result<()> {
     // create buffer to receive query packet
     let mut query_buf = RawPacket::new();
     let (_, query_src) = socket.recv_from(&mut query_buf.buf).map_err(IOErr)?;
@@ -65,32 +1277,265 @@ fn handle_query(socket: &UdpSocket) -> Result<()> {
     let mut query_packet = DNSPacket::new();
     query_packet.parse(&mut query_buf)?;
 
+    let payload_size = udp_payload_size(&query_packet);
+    let cookie = match &state.cookies {
+        Some(cookies) => cookies.check(&query_packet, query_src.ip()),
+        None => CookieCheck::Absent,
+    };
+
+    let mut res_packet = match cookie {
+        // RFC 7873 section 5.2.2
+        CookieCheck::Malformed => {
+            let mut res_packet = DNSPacket::new();
+            res_packet.header.id = query_packet.header.id;
+            res_packet.header.qr = true;
+            res_packet.header.op_code = query_packet.header.op_code;
+            res_packet.header.rcode = Formerr;
+            res_packet.question_sec = query_packet.question_sec;
+            res_packet.additional_sec.push(opt_record(Vec::new()));
+            res_packet
+        }
+        _ => respond(&query_buf, query_packet, query_src, state, false)?.remove(0),
+    };
+    let cookies = state.cookies.as_ref();
+    if let Some(option) = cookies.and_then(|cookies| cookies.option_for(&cookie, query_src.ip())) {
+        add_option(&mut res_packet, option);
+    }
+
+    // encode response packet into bytes
+    let encode = |res_packet: &DNSPacket| -> Result<RawPacket> {
+        let mut res_buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        res_packet.write(&mut res_buf)?;
+        Ok(res_buf)
+    };
+    let mut res_buf = encode(&res_packet)?;
+
+    // a large response only goes to a client that showed it can receive at its address
+    let held_back = cookies.is_some_and(|cookies| cookies.holds_back(&cookie, res_buf.cursor()));
+    if held_back || res_buf.cursor() > payload_size {
+        match cookie {
+            // the client asks again with the server cookie it is handed, RFC 7873 section 5.2.3
+            CookieCheck::Unverified(_) if held_back => bad_cookie(&mut res_packet),
+            _ => res_packet.truncate(),
+        }
+        res_buf = encode(&res_packet)?;
+    }
+
+    // only UDP sources can be spoofed, the other transports are not limited and neither is a
+    // client that returned its server cookie
+    if !matches!(cookie, CookieCheck::Verified { .. }) {
+        match state.rate_limiter.check(query_src.ip(), &res_packet) {
+            Verdict::Send => {}
+            Verdict::Drop => return Ok(()),
+            Verdict::Slip => {
+                res_packet.truncate();
+                res_buf = encode(&res_packet)?;
+            }
+        }
+    }
+
+    socket
+        .send_to(&res_buf.buf[..res_buf.cursor()], query_src)
+        .map_err(IOErr)?;
+
+    Ok(())
+}
+
+/// Answer a query received over any transport, checking its TSIG and signing the response to match
+pub fn respond(
+    query_buf: &RawPacket,
+    query_packet: DNSPacket,
+    query_src: SocketAddr,
+    state: &ServerState,
+    tcp: bool,
+) -> Result<Vec<DNSPacket>> {
+    let rejection = |rcode| {
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.id = query_packet.header.id;
+        res_packet.header.qr = true;
+        res_packet.header.op_code = query_packet.header.op_code;
+        res_packet.header.rcode = rcode;
+        res_packet.question_sec = query_packet.question_sec.clone();
+        res_packet
+    };
+
+    // a TSIG anywhere but at the very end of the message is malformed, RFC 8945 section 5.1
+    let misplaced_tsig = query_packet
+        .answer_sec
+        .iter()
+        .chain(&query_packet.authority_sec)
+        .chain(query_packet.additional_sec.iter().rev().skip(1))
+        .any(|rec| matches!(rec, Record::Tsig { .. }));
+    if misplaced_tsig {
+        return Ok(vec![rejection(Formerr)]);
+    }
+
+    let signer = match verify_request(query_buf, &query_packet, &state.keyring) {
+        Ok(signer) => signer,
+        // the client learns what was wrong from the TSIG of the response
+        Err(signer) => {
+            let mut res_packet = rejection(Notauth);
+            res_packet.signer = Some(signer);
+            return Ok(vec![res_packet]);
+        }
+    };
+    let key = signer.as_ref().map(|signer| signer.key.name.clone());
+
+    let is_transfer = query_packet
+        .question_sec
+        .first()
+        .is_some_and(|que| matches!(que.query_type, QueryType::Axfr | QueryType::Ixfr));
+
+    let mut res_packets = if is_transfer {
+        // the zone is transferred as the client's view has it
+        let zones = state.view(query_src.ip()).zones();
+        answer_transfer(&query_packet, query_src, key.as_deref(), &zones, tcp)?
+    } else {
+        vec![resolve(query_packet, query_src, key.as_deref(), state)]
+    };
+
+    if let Some(signer) = signer {
+        sign_all(&mut res_packets, signer)?;
+    }
+
+    Ok(res_packets)
+}
+
+/// Answer from local zones, or handle recursive lookups if required
+///
+/// Key names the TSIG key the query was verified with.
+pub fn resolve(
+    mut query_packet: DNSPacket,
+    query_src: SocketAddr,
+    key: Option<&str>,
+    state: &ServerState,
+) -> DNSPacket {
+    match query_packet.header.op_code {
+        Opcode::Query => {}
+        Opcode::Notify => return handle_notify(&query_packet, query_src, key, state),
+        Opcode::Update => return handle_update(&query_packet, query_src, key, state),
+        Opcode::Unknown(_) => {
+            let mut res_packet = DNSPacket::new();
+            res_packet.header.id = query_packet.header.id;
+            res_packet.header.qr = true;
+            res_packet.header.op_code = query_packet.header.op_code;
+            res_packet.header.rcode = Notimp;
+            return res_packet;
+        }
+    }
+
     // Create and initialize the response packet
     let mut res_packet = DNSPacket::new();
     res_packet.header.id = query_packet.header.id; // same ID as query
     res_packet.header.rd = true;
-    res_packet.header.ra = true;
     res_packet.header.qr = true;
 
-    // expect 1 question only
-    if let Some(que) = query_packet.question_sec.pop() {
-        if let Ok(result) = lookup(&que.name, que.query_type.clone()) {
-            res_packet.question_sec.push(que); // add question to response packet also
-            res_packet.header.rcode = result.header.rcode; // same response code as query
+    // RA tells the client whether it may recurse through us, even on authoritative answers
+    let recursion = state.allows_recursion(query_src.ip(), key);
+    res_packet.header.ra = recursion;
 
-            for rec in result.answer_sec {
-                res_packet.answer_sec.push(rec);
-            }
+    // the client subnet only goes upstream when enabled, a client may ask for less of it to be sent
+    let uses_edns = edns_options(&query_packet).is_some();
+    let wants_dnssec = dnssec_ok(&query_packet);
+    let requested = ClientSubnet::from_packet(&query_packet);
+    let subnet = state
+        .client_subnet
+        .as_ref()
+        .map(|config| config.subnet_for(query_src.ip(), requested.as_ref()));
+    let mut scope_prefix = 0;
+    // why the client got the response it did, passed on if it uses EDNS, RFC 8914
+    let mut errors = Vec::new();
 
-            for rec in result.authority_sec {
-                res_packet.authority_sec.push(rec);
+    if !state.allows_query(query_src.ip(), key) {
+        res_packet.header.rcode = Refused;
+        res_packet.question_sec = query_packet.question_sec;
+        errors.push(ExtendedError::new(InfoCode::Prohibited, "query not allowed").to_option());
+    }
+    // expect 1 question only
+    else if let Some(que) = query_packet.question_sec.pop() {
+        let view = state.view(query_src.ip());
+        // only recurse for names outside of our own zones, which stay locked just while answering
+        // from them so a slow upstream does not hold up zone writers
+        let zones = view.zones();
+        let authoritative = if let Some(origin) = state.untransferred(view, &zones, &que.name) {
+            // a secondary has nothing to answer with before its first transfer
+            res_packet.header.rcode = Servfail;
+            let text = format!("zone {} has not been transferred yet", origin);
+            errors.push(ExtendedError::new(InfoCode::NotReady, &text).to_option());
+            true
+        } else if let Some(zone) = zones.find(&que.name) {
+            if zone.expired {
+                res_packet.header.rcode = Servfail;
+                let text = format!("zone {} expired without reaching its primary", zone.origin);
+                errors.push(ExtendedError::new(InfoCode::NotReady, &text).to_option());
+            } else {
+                zone.answer(&que, &mut res_packet);
             }
+            true
+        } else {
+            false
+        };
+        drop(zones);
 
-            for rec in result.additional_sec {
-                res_packet.additional_sec.push(rec);
-            }
+        if authoritative {
+            res_packet.question_sec.push(que);
+        } else if !recursion {
+            res_packet.header.rcode = Refused;
+            res_packet.question_sec.push(que);
+            let error = ExtendedError::new(InfoCode::NotAuthoritative, "recursion not allowed");
+            errors.push(error.to_option());
+        } else if view
+            .local
+            .answer(&que, &mut res_packet, subnet.as_ref(), &view.options)
+        {
+            res_packet.question_sec.push(que);
+        } else if let Some(action) = state.filter.check(&que.name) {
+            // only recursive answers are filtered, our own zones are trusted
+            answer_blocked(action, &que, &mut res_packet);
+            let text = format!("{} is on a blocklist", que.name);
+            errors.push(ExtendedError::new(InfoCode::Blocked, &text).to_option());
+            res_packet.question_sec.push(que);
         } else {
-            res_packet.header.rcode = Servfail;
+            let result = match &state.dns64 {
+                Some(dns64) => dns64.lookup(&que, subnet.as_ref(), &view.options),
+                None => lookup(
+                    &que.name,
+                    que.query_type.clone(),
+                    subnet.as_ref(),
+                    &view.options,
+                ),
+            };
+            match result {
+                Ok(mut result) => {
+                    if let Some(echoed) = ClientSubnet::from_packet(&result) {
+                        scope_prefix = echoed.scope_prefix;
+                    }
+                    errors.extend(extended_errors(&result));
+                    strip_opt(&mut result);
+                    if !wants_dnssec {
+                        strip_dnssec(&mut result, &que.query_type);
+                    }
+
+                    res_packet.question_sec.push(que); // add question to response packet also
+                    res_packet.header.rcode = result.header.rcode; // same response code as query
+
+                    for rec in result.answer_sec {
+                        res_packet.answer_sec.push(rec);
+                    }
+
+                    for rec in result.authority_sec {
+                        res_packet.authority_sec.push(rec);
+                    }
+
+                    for rec in result.additional_sec {
+                        res_packet.additional_sec.push(rec);
+                    }
+                }
+                Err(e) => {
+                    res_packet.header.rcode = Servfail;
+                    errors.push(ExtendedError::from(&e).to_option());
+                }
+            }
         }
     }
     // no question found
@@ -98,13 +1543,662 @@ fn handle_query(socket: &UdpSocket) -> Result<()> {
         res_packet.header.rcode = Formerr;
     }
 
-    // encode response packet into bytes
-    let mut res_buf = RawPacket::new();
-    res_packet.write(&mut res_buf)?;
-    let len = res_buf.cursor();
-    let data = res_buf.get_bytes_from(0, len - 1)?;
+    // a client using EDNS gets an OPT back, with its own subnet echoed, RFC 7871 section 7.2.1
+    if uses_edns {
+        let mut options = match (requested, &subnet) {
+            (Some(requested), Some(_)) => vec![ClientSubnet {
+                source: requested.source,
+                scope_prefix,
+            }
+            .to_option()],
+            _ => Vec::new(),
+        };
+        options.extend(errors);
+        res_packet.additional_sec.push(opt_record(options));
+        // the DO bit is copied back, RFC 3225 section 3
+        if wants_dnssec {
+            set_dnssec_ok(&mut res_packet);
+        }
+    }
 
-    socket.send_to(data, query_src).map_err(IOErr)?;
+    res_packet
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::{
+        edns::{EdnsOption, OPTION_COOKIE},
+        header::ResponseCode,
+        zone_file::parse_record,
+    };
+
+    fn rec(text: &str) -> Record {
+        parse_record(text, 3600).unwrap()
+    }
+
+    /// A response referring sub.example.com to its name server, with the given glue
+    fn referral_to_sub(glue: &[&str]) -> DNSPacket {
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.qr = true;
+        res_packet
+            .authority_sec
+            .push(rec("sub.example.com. 3600 IN NS ns.sub.example.com."));
+        res_packet.additional_sec = glue.iter().map(|text| rec(text)).collect();
+        res_packet
+    }
+
+    #[test]
+    fn referral_is_followed() {
+        let res_packet = referral_to_sub(&[]);
+
+        assert_eq!(
+            referral(&res_packet, "www.sub.example.com").as_deref(),
+            Some("sub.example.com")
+        );
+        assert_eq!(referral(&res_packet, "www.example.org"), None);
+    }
+
+    #[test]
+    fn authoritative_nodata_is_no_referral() {
+        let mut res_packet = referral_to_sub(&[]);
+        res_packet.header.aa = true;
+
+        assert_eq!(referral(&res_packet, "www.sub.example.com"), None);
+    }
+
+    #[test]
+    fn ipv4_glue_is_preferred() {
+        let res_packet = referral_to_sub(&[
+            "ns.sub.example.com. 3600 IN AAAA 2001:db8::53",
+            "ns.sub.example.com. 3600 IN A 192.0.2.53",
+        ]);
+
+        assert_eq!(
+            glue_address(&res_packet, &["ns.sub.example.com"]),
+            Some(IpAddr::from([192, 0, 2, 53]))
+        );
+    }
+
+    #[test]
+    fn ipv6_glue_is_used() {
+        let res_packet = referral_to_sub(&[
+            "other.example.net. 3600 IN A 192.0.2.1",
+            "NS.sub.example.com. 3600 IN AAAA 2001:db8::53",
+        ]);
+
+        assert_eq!(
+            glue_address(&res_packet, &["ns.sub.example.com"]),
+            Some("2001:db8::53".parse().unwrap())
+        );
+    }
+
+    /// A response to the query as the server would send it
+    fn response_to(query_packet: &DNSPacket) -> DNSPacket {
+        let mut res_packet = query_packet.clone();
+        res_packet.header.qr = true;
+        res_packet
+    }
+
+    #[test]
+    fn only_the_server_answering_the_query_is_accepted() {
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        let query_packet = new_query("www.example.com", QueryType::A);
+        let res_packet = response_to(&query_packet);
+        assert!(is_valid_response(
+            &query_packet,
+            server,
+            &res_packet,
+            server
+        ));
+
+        let elsewhere = SocketAddr::from(([192, 0, 2, 54], 53));
+        assert!(!is_valid_response(
+            &query_packet,
+            server,
+            &res_packet,
+            elsewhere
+        ));
+
+        let mut wrong_id = response_to(&query_packet);
+        wrong_id.header.id = query_packet.header.id.wrapping_add(1);
+        assert!(!is_valid_response(&query_packet, server, &wrong_id, server));
+
+        let mut not_response = response_to(&query_packet);
+        not_response.header.qr = false;
+        assert!(!is_valid_response(
+            &query_packet,
+            server,
+            &not_response,
+            server
+        ));
+
+        let mut other_question = response_to(&query_packet);
+        other_question.question_sec[0].name = String::from("mail.example.com");
+        assert!(!is_valid_response(
+            &query_packet,
+            server,
+            &other_question,
+            server
+        ));
+        let mut other_type = response_to(&query_packet);
+        other_type.question_sec[0].query_type = QueryType::Aaaa;
+        assert!(!is_valid_response(
+            &query_packet,
+            server,
+            &other_type,
+            server
+        ));
+    }
+
+    #[test]
+    fn junk_does_not_hold_the_lookup_past_its_timeout() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, client) = server.recv_from(&mut buf).unwrap();
+            // answers to some other query, sent faster than any read timeout runs out
+            buf[0] ^= 0xff;
+            buf[2] |= 0x80;
+            for _ in 0..100 {
+                let _ = server.send_to(&buf[..len], client);
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let started = Instant::now();
+        let query_packet = new_query("www.example.com", QueryType::A);
+        let result = exchange(server_addr, query_packet, false, None);
+
+        assert!(matches!(
+            result,
+            Err(IOErr(e)) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock)
+        ));
+        assert!(started.elapsed() < LOOKUP_TIMEOUT + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn randomized_case_keeps_the_name() {
+        let name = "www.Example-1.com";
+        let randomized = randomize_case(name);
+        assert!(randomized.eq_ignore_ascii_case(name));
+
+        let query_packet = new_query(&randomized, QueryType::A);
+        let mut res_packet = response_to(&query_packet);
+        assert!(is_case_echoed(&query_packet, &res_packet));
+        res_packet.question_sec[0].name = name.to_ascii_uppercase();
+        assert!(!is_case_echoed(&query_packet, &res_packet));
+    }
+
+    /// A stand-in server answering one query with a response for each of the names given, in
+    /// that case, and the address it listens on
+    fn answer_in_case(names: Vec<String>) -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut query_buf = RawPacket::new();
+            let (_, client) = server.recv_from(&mut query_buf.buf).unwrap();
+            let mut query_packet = DNSPacket::new();
+            query_packet.parse(&mut query_buf).unwrap();
+
+            for name in names {
+                let mut res_packet = response_to(&query_packet);
+                res_packet.question_sec[0].name = name;
+                let mut res_buf = RawPacket::new();
+                res_packet.write(&mut res_buf).unwrap();
+                server
+                    .send_to(&res_buf.buf[..res_buf.cursor()], client)
+                    .unwrap();
+            }
+        });
+        server_addr
+    }
+
+    #[test]
+    fn wrong_case_response_is_dropped_for_the_right_one() {
+        let name = String::from("wWw.ExAmPlE.cOm");
+        let server = answer_in_case(vec![name.to_ascii_lowercase(), name.clone()]);
+
+        let query_packet = new_query(&name, QueryType::A);
+        let res_packet = exchange(server, query_packet, true, None).unwrap();
+        assert_eq!(res_packet.question_sec[0].name, name);
+    }
+
+    #[test]
+    fn only_wrong_case_responses_are_a_case_mismatch() {
+        let name = String::from("wWw.ExAmPlE.cOm");
+        let server = answer_in_case(vec![name.to_ascii_lowercase()]);
+
+        let query_packet = new_query(&name, QueryType::A);
+        assert!(matches!(
+            exchange(server, query_packet, true, None),
+            Err(CaseMismatch)
+        ));
+    }
+
+    #[test]
+    fn case_randomization_is_retried() {
+        let options = LookupOptions::default();
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        for _ in 0..MAX_CASE_MISMATCHES {
+            assert!(options.echoes_case(server));
+            options.note_case_mismatch(server);
+        }
+        assert!(!options.echoes_case(server));
+
+        // after a while
+        let long_ago = Instant::now() - CASE_RETRY_AFTER;
+        options
+            .case_mismatches
+            .lock()
+            .unwrap()
+            .get_mut(&server)
+            .unwrap()
+            .1 = long_ago;
+        assert!(options.echoes_case(server));
+
+        // or once the server echoes the case again
+        options.note_case_mismatch(server);
+        assert!(!options.echoes_case(server));
+        options.note_case_echoed(server);
+        assert!(options.echoes_case(server));
+    }
+
+    /// A server loaded from the configuration, with the zone file example.com.zone beside it
+    fn state_from(config: &str) -> ServerState {
+        let dir = env::temp_dir().join(format!(
+            "server-test-{}-{}",
+            process::id(),
+            thread_rng().gen::<u32>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("dns.conf"), config).unwrap();
+        fs::write(
+            dir.join("example.com.zone"),
+            "$ORIGIN example.com.\n\
+             @ 3600 IN SOA ns admin 1 7200 900 86400 300\n\
+             @ 3600 IN NS ns\n\
+             ns 3600 IN A 192.0.2.1\n\
+             www 3600 IN A 192.0.2.80\n",
+        )
+        .unwrap();
+
+        let state = ServerState::from_config(&Config::load(&dir.join("dns.conf")).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        state.unwrap()
+    }
+
+    fn ask(state: &ServerState, client: [u8; 4], key: Option<&str>, name: &str) -> DNSPacket {
+        let query_src = SocketAddr::from((client, 40000));
+        resolve(new_query(name, QueryType::A), query_src, key, state)
+    }
+
+    const ACL_CONFIG: &str = "\
+acl lab 10.1.0.0/16 key lab-key
+acl trusted lab 192.0.2.0/24
+allow-query trusted localhost
+allow-recursion lab
+zone example.com example.com.zone
+local api.lab.example A 10.0.0.5
+";
+
+    #[test]
+    fn client_outside_allow_query_is_refused() {
+        let state = state_from(ACL_CONFIG);
+
+        let res_packet = ask(&state, [198, 51, 100, 1], None, "www.example.com");
+        assert_eq!(res_packet.header.rcode, Refused);
+        assert!(res_packet.answer_sec.is_empty());
+    }
+
+    #[test]
+    fn zone_data_is_answered_without_recursion() {
+        let state = state_from(ACL_CONFIG);
+
+        let res_packet = ask(&state, [192, 0, 2, 7], None, "www.example.com");
+        assert_eq!(res_packet.header.rcode, Noerror);
+        assert!(res_packet.header.aa);
+        assert!(!res_packet.header.ra);
+        assert_eq!(res_packet.answer_sec.len(), 1);
+
+        let res_packet = ask(&state, [192, 0, 2, 7], None, "api.lab.example");
+        assert_eq!(res_packet.header.rcode, Refused);
+        assert!(!res_packet.header.ra);
+    }
+
+    #[test]
+    fn named_groups_expand() {
+        let state = state_from(ACL_CONFIG);
+
+        // lab is allowed to query through trusted, and to recurse
+        for (client, key) in [([10, 1, 2, 3], None), ([198, 51, 100, 1], Some("lab-key"))] {
+            let res_packet = ask(&state, client, key, "api.lab.example");
+            assert_eq!(res_packet.header.rcode, Noerror);
+            assert!(res_packet.header.ra);
+            assert_eq!(res_packet.answer_sec.len(), 1);
+        }
+
+        let res_packet = ask(&state, [127, 0, 0, 1], None, "www.example.com");
+        assert_eq!(res_packet.answer_sec.len(), 1);
+    }
+
+    /// Send the query with the COOKIE option data to a UDP server of the state and read the
+    /// response
+    fn ask_with_cookie(state: &ServerState, cookie: &[u8]) -> DNSPacket {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+
+        let mut query_packet = new_query("www.example.com", QueryType::A);
+        add_option(
+            &mut query_packet,
+            EdnsOption {
+                code: OPTION_COOKIE,
+                data: cookie.to_vec(),
+            },
+        );
+        let mut query_buf = RawPacket::new();
+        query_packet.write(&mut query_buf).unwrap();
+        client.send(&query_buf.buf[..query_buf.cursor()]).unwrap();
+        handle_query(&server, state).unwrap();
+
+        let mut res_buf = RawPacket::new();
+        client.recv(&mut res_buf.buf).unwrap();
+        let mut res_packet = DNSPacket::new();
+        res_packet.parse(&mut res_buf).unwrap();
+        res_packet
+    }
+
+    /// Server cookie handed back in the response
+    fn server_cookie(res_packet: &DNSPacket) -> Vec<u8> {
+        let option = edns_options(res_packet)
+            .unwrap()
+            .iter()
+            .find(|option| option.code == OPTION_COOKIE)
+            .unwrap();
+        option.data[8..].to_vec()
+    }
+
+    const COOKIE_CONFIG: &str = "\
+cookies yes
+require-cookies-above 60
+zone example.com example.com.zone
+";
+
+    #[test]
+    fn malformed_cookie_is_formerr() {
+        let state = state_from(COOKIE_CONFIG);
+
+        let res_packet = ask_with_cookie(&state, &[1; 5]);
+        assert_eq!(res_packet.header.rcode, Formerr);
+        assert!(res_packet.answer_sec.is_empty());
+        assert!(edns_options(&res_packet).is_some());
+    }
+
+    #[test]
+    fn large_response_needs_a_server_cookie() {
+        let state = state_from(COOKIE_CONFIG);
+        let client_cookie = [7; 8];
+
+        let res_packet = ask_with_cookie(&state, &client_cookie);
+        assert_eq!(extended_rcode(&res_packet), RCODE_BADCOOKIE);
+        assert!(res_packet.answer_sec.is_empty());
+        assert!(!res_packet.header.tc);
+
+        // asking again with the server cookie it was handed gets the answer
+        let cookie = [&client_cookie[..], &server_cookie(&res_packet)].concat();
+        let res_packet = ask_with_cookie(&state, &cookie);
+        assert_eq!(extended_rcode(&res_packet), Noerror.to_num() as u16);
+        assert_eq!(res_packet.answer_sec.len(), 1);
+        assert_eq!(server_cookie(&res_packet), cookie[8..]);
+    }
+
+    /// The info code of the Extended DNS Error of the response, if it carries one
+    fn info_code(res_packet: &DNSPacket) -> Option<u16> {
+        let errors = extended_errors(res_packet);
+        let [error] = errors.as_slice() else {
+            return None;
+        };
+        Some(u16::from_be_bytes([error.data[0], error.data[1]]))
+    }
+
+    #[test]
+    fn refused_transfer_and_update_say_why() {
+        let state = state_from(ACL_CONFIG);
+        let client = SocketAddr::from(([192, 0, 2, 7], 40000));
+        let with_opt = |mut query_packet: DNSPacket| {
+            query_packet.additional_sec.push(opt_record(Vec::new()));
+            query_packet
+        };
+
+        let query_packet = with_opt(new_query("example.com", QueryType::Axfr));
+        let res_packets = answer_transfer(&query_packet, client, None, &state.zones(), true);
+        let res_packet = &res_packets.unwrap()[0];
+        assert_eq!(res_packet.header.rcode, Refused);
+        assert_eq!(info_code(res_packet), Some(18));
+
+        let mut query_packet = with_opt(new_query("example.com", QueryType::Soa));
+        query_packet.header.op_code = Opcode::Update;
+        let res_packet = handle_update(&query_packet, client, None, &state);
+        assert_eq!(res_packet.header.rcode, Refused);
+        assert_eq!(info_code(&res_packet), Some(18));
+
+        // a client without EDNS gets no OPT record
+        query_packet.additional_sec.clear();
+        let res_packet = handle_update(&query_packet, client, None, &state);
+        assert_eq!(res_packet.header.rcode, Refused);
+        assert!(edns_options(&res_packet).is_none());
+    }
+
+    type Asked = Arc<Mutex<Vec<(String, QueryType)>>>;
+
+    /// A name server at the address, port 0 for any, answering each question with what the
+    /// function gives and noting the names and types asked
+    fn name_server_at(
+        addr: SocketAddr,
+        mut answer: impl FnMut(&Question) -> DNSPacket + Send + 'static,
+    ) -> (SocketAddr, Asked) {
+        let socket = UdpSocket::bind(addr).unwrap();
+        let addr = socket.local_addr().unwrap();
+        let asked = Asked::default();
+        let noted = asked.clone();
+
+        thread::spawn(move || loop {
+            let mut query_buf = RawPacket::new();
+            let Ok((_, client)) = socket.recv_from(&mut query_buf.buf) else {
+                return;
+            };
+            let mut query_packet = DNSPacket::new();
+            query_packet.parse(&mut query_buf).unwrap();
+            let que = &query_packet.question_sec[0];
+            noted
+                .lock()
+                .unwrap()
+                .push((que.name.to_ascii_lowercase(), que.query_type.clone()));
+
+            let mut res_packet = answer(que);
+            res_packet.header.id = query_packet.header.id;
+            res_packet.header.qr = true;
+            res_packet.question_sec = query_packet.question_sec.clone();
+            let mut res_buf = RawPacket::new();
+            res_packet.write(&mut res_buf).unwrap();
+            let _ = socket.send_to(&res_buf.buf[..res_buf.cursor()], client);
+        });
+
+        (addr, asked)
+    }
+
+    /// A referral to the name server of the cut, with its address as glue
+    fn referral_to(cut: &str, ip: IpAddr) -> DNSPacket {
+        let mut res_packet = DNSPacket::new();
+        res_packet
+            .authority_sec
+            .push(rec(&format!("{}. 3600 IN NS ns.{}.", cut, cut)));
+        res_packet
+            .additional_sec
+            .push(rec(&format!("ns.{}. 3600 IN A {}", cut, ip)));
+        res_packet
+    }
+
+    /// An authoritative response with the records
+    fn authoritative(rcode: ResponseCode, records: &[String]) -> DNSPacket {
+        let mut res_packet = DNSPacket::new();
+        res_packet.header.aa = true;
+        res_packet.header.rcode = rcode;
+        res_packet.answer_sec = records.iter().map(|text| rec(text)).collect();
+        res_packet
+    }
+
+    /// The address of the name for a full query, an empty non-terminal for a shortened one
+    fn answer_or_empty(que: &Question) -> DNSPacket {
+        match que.query_type {
+            QueryType::Aaaa => authoritative(
+                Noerror,
+                &[format!("{}. 3600 IN AAAA 2001:db8::1", que.name)],
+            ),
+            _ => authoritative(Noerror, &[]),
+        }
+    }
+
+    /// Resolve iteratively from the root server at the address
+    fn iterate_from(root: SocketAddr, query: &str) -> Result<DNSPacket> {
+        let rule = ForwardRule {
+            domain: String::from("."),
+            upstream: Upstream::Udp(root, None),
+            recursive: false,
+            name_server_port: root.port(),
+        };
+        iterate(
+            query,
+            QueryType::Aaaa,
+            None,
+            &rule,
+            &LookupOptions::default(),
+            0,
+        )
+    }
+
+    /// The last labels of the name
+    fn suffix(name: &str, count: usize) -> String {
+        let labels: Vec<&str> = name.split('.').collect();
+        labels[labels.len() - count..].join(".")
+    }
+
+    fn asked(asked: &Asked) -> Vec<(String, QueryType)> {
+        asked.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn minimised_names_grow_a_label_then_catch_up() {
+        let (root, root_asked) =
+            name_server_at(SocketAddr::from(([127, 0, 0, 2], 0)), answer_or_empty);
+        let query = "a.b.c.d.e.f.g.h.i.j.k.l.example";
+
+        let res_packet = iterate_from(root, query).unwrap();
+        assert_eq!(res_packet.answer_sec.len(), 1);
+
+        // one label each for the first four, the rest spread over the queries left
+        let mut expected: Vec<(String, QueryType)> = [1, 2, 3, 4, 6, 8, 10, 11, 12]
+            .into_iter()
+            .map(|count| (suffix(query, count), QueryType::A))
+            .collect();
+        expected.push((String::from(query), QueryType::Aaaa));
+        assert_eq!(expected.len(), MAX_MINIMISE_COUNT);
+        assert_eq!(asked(&root_asked), expected);
+    }
+
+    #[test]
+    fn referrals_are_asked_one_label_below_the_cut() {
+        let (server, server_asked) =
+            name_server_at(SocketAddr::from(([127, 0, 0, 3], 0)), answer_or_empty);
+        let (root, root_asked) = name_server_at(
+            SocketAddr::from(([127, 0, 0, 2], server.port())),
+            move |_: &Question| referral_to("example", server.ip()),
+        );
+
+        let res_packet = iterate_from(root, "www.sub.example").unwrap();
+        assert_eq!(res_packet.answer_sec.len(), 1);
+
+        assert_eq!(
+            asked(&root_asked),
+            [(String::from("example"), QueryType::A)]
+        );
+        assert_eq!(
+            asked(&server_asked),
+            [
+                (String::from("sub.example"), QueryType::A),
+                (String::from("www.sub.example"), QueryType::Aaaa),
+            ]
+        );
+    }
+
+    #[test]
+    fn nxdomain_or_alias_for_a_shortened_name_asks_the_full_name() {
+        let (root, root_asked) = name_server_at(
+            SocketAddr::from(([127, 0, 0, 2], 0)),
+            |que: &Question| match (que.name.as_str(), &que.query_type) {
+                // an empty non-terminal the server wrongly denies
+                ("ent.example", QueryType::A) => authoritative(Nxdomain, &[]),
+                ("alias.example", QueryType::A) => authoritative(
+                    Noerror,
+                    &[String::from("alias.example. 3600 IN CNAME target.example.")],
+                ),
+                _ => answer_or_empty(que),
+            },
+        );
+
+        for query in ["x.y.ent.example", "x.y.alias.example"] {
+            root_asked.lock().unwrap().clear();
+            let res_packet = iterate_from(root, query).unwrap();
+            assert_eq!(res_packet.answer_sec.len(), 1);
+            assert_eq!(
+                asked(&root_asked),
+                [
+                    (String::from("example"), QueryType::A),
+                    (suffix(query, 2), QueryType::A),
+                    (String::from(query), QueryType::Aaaa),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn referral_leading_away_is_an_error() {
+        // the server refers back to the cut it was found for
+        let (server, server_asked) =
+            name_server_at(SocketAddr::from(([127, 0, 0, 3], 0)), |_: &Question| {
+                referral_to("example", IpAddr::from([127, 0, 0, 3]))
+            });
+        let (root, _) = name_server_at(
+            SocketAddr::from(([127, 0, 0, 2], server.port())),
+            move |_: &Question| referral_to("example", server.ip()),
+        );
+
+        let result = iterate_from(root, "www.sub.example");
+        assert!(matches!(result, Err(Resolve(msg)) if msg.contains("leads away")));
+        assert_eq!(asked(&server_asked).len(), 1);
+    }
+
+    #[test]
+    fn endless_referrals_stop_at_the_cap() {
+        // each referral is one label deeper than the last, to the same server
+        let mut referrals = 0;
+        let (root, root_asked) = name_server_at(
+            SocketAddr::from(([127, 0, 0, 2], 0)),
+            move |que: &Question| {
+                referrals += 1;
+                referral_to(&suffix(&que.name, referrals), IpAddr::from([127, 0, 0, 2]))
+            },
+        );
+        let query: Vec<String> = (0..40)
+            .map(|label| String::from((b'a' + label % 26) as char))
+            .collect();
+
+        let result = iterate_from(root, &query.join("."));
+        assert!(matches!(result, Err(Resolve(msg)) if msg.contains("too many referrals")));
+        assert_eq!(asked(&root_asked).len(), MAX_REFERRALS + MAX_MINIMISE_COUNT);
+    }
 }
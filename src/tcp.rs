@@ -0,0 +1,94 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{Errors::IOErr, Result},
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    server::{respond, ServerState},
+};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30); // close connections that stay quiet this long
+
+/// Read one length prefixed message, or None once the peer has closed the connection
+pub fn read_message<R: Read>(stream: &mut R) -> Result<Option<RawPacket>> {
+    let mut len = [0; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        // an idle client is closed the same way
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(IOErr(e)),
+    }
+
+    let mut buf = RawPacket::with_size(u16::from_be_bytes(len) as usize);
+    stream.read_exact(&mut buf.buf).map_err(IOErr)?;
+
+    Ok(Some(buf))
+}
+
+/// Write one message with its two byte length prefix
+pub fn write_message<W: Write>(stream: &mut W, packet: &DNSPacket) -> Result<()> {
+    let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+    packet.write(&mut buf)?;
+
+    let mut framed = Vec::with_capacity(buf.cursor() + 2);
+    framed.extend((buf.cursor() as u16).to_be_bytes());
+    framed.extend(buf.written());
+    stream.write_all(&framed).map_err(IOErr)?;
+    // TLS streams hold data back until flushed
+    stream.flush().map_err(IOErr)?;
+
+    Ok(())
+}
+
+/// Answer every query sent over a stream connection until the client closes it
+pub fn handle_connection<S: Read + Write>(
+    stream: &mut S,
+    query_src: SocketAddr,
+    state: &ServerState,
+) -> Result<()> {
+    while let Some(mut query_buf) = read_message(stream)? {
+        let mut query_packet = DNSPacket::new();
+        query_packet.parse(&mut query_buf)?;
+
+        for res_packet in respond(&query_buf, query_packet, query_src, state, true)? {
+            write_message(stream, &res_packet)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept TCP connections forever, each one served on its own thread
+pub fn serve_tcp(listener: TcpListener, state: Arc<ServerState>) {
+    for stream in listener.incoming() {
+        let mut stream: TcpStream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept TCP connection: {}", e);
+                continue;
+            }
+        };
+
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let result = stream
+                .set_read_timeout(Some(IDLE_TIMEOUT))
+                .map_err(IOErr)
+                .and_then(|()| stream.peer_addr().map_err(IOErr))
+                .and_then(|query_src| handle_connection(&mut stream, query_src, &state));
+
+            if let Err(e) = result {
+                eprintln!("failed to handle TCP connection: {}", e);
+            }
+        });
+    }
+}
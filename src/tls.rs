@@ -0,0 +1,391 @@
+use std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::Path,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+use webpki::EndEntityCert;
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{
+        Errors::{self, IOErr, Tls},
+        Result,
+    },
+    server::{is_valid_response, ServerState},
+    tcp::{handle_connection, read_message, write_message},
+};
+
+pub const TLS_PORT: u16 = 853; // port DNS over TLS is served on, RFC 7858
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30); // close connections that stay quiet this long
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5); // give up on the upstream after this long
+const MAX_IDLE_CONNECTIONS: usize = 4; // open connections kept per upstream between lookups
+
+/// Turn a TLS library error into ours
+fn tls_err(e: rustls::Error) -> Errors {
+    Tls(e.to_string())
+}
+
+/// Load the certificate chain and private key a listener presents, offering the ALPN protocols
+pub fn server_config(
+    cert_file: &Path,
+    key_file: &Path,
+    alpn: &[&[u8]],
+) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| Tls(format!("{}: {}", cert_file.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|e| Tls(format!("{}: {}", key_file.display(), e)))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_err)?;
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+/// Accept DNS over TLS connections forever, each one served on its own thread
+pub fn serve_tls(listener: TcpListener, config: Arc<ServerConfig>, state: Arc<ServerState>) {
+    for stream in listener.incoming() {
+        let stream: TcpStream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept TLS connection: {}", e);
+                continue;
+            }
+        };
+
+        let config = Arc::clone(&config);
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            // messages are framed exactly as over TCP once the handshake is done
+            let result = stream
+                .set_read_timeout(Some(IDLE_TIMEOUT))
+                .map_err(IOErr)
+                .and_then(|()| stream.peer_addr().map_err(IOErr))
+                .and_then(|query_src| {
+                    let conn = ServerConnection::new(config).map_err(tls_err)?;
+                    let mut tls = StreamOwned::new(conn, stream);
+                    handle_connection(&mut tls, query_src, &state)
+                });
+
+            if let Err(e) = result {
+                eprintln!("failed to handle TLS connection: {}", e);
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+/// Accepts a server whose public key matches a pin, whatever signed its certificate
+///
+/// This is the out-of-band key-pinned profile of RFC 7858.
+struct SpkiPins {
+    /// SHA-256 digests of the accepted SubjectPublicKeyInfo structures
+    pins: Vec<Vec<u8>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for SpkiPins {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let cert = EndEntityCert::try_from(end_entity)
+            .map_err(|_| rustls::Error::General(String::from("malformed certificate")))?;
+        let digest = Sha256::digest(cert.subject_public_key_info().as_ref());
+
+        if self.pins.iter().any(|pin| pin[..] == digest[..]) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from(
+                "public key matches no pin",
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// How the certificate of an upstream server is checked, and the ALPN protocols asked for
+pub fn client_config(
+    ca_file: Option<&Path>,
+    pins: &[Vec<u8>],
+    alpn: &[&[u8]],
+) -> Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder();
+
+    let mut config = if !pins.is_empty() {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .unwrap_or_else(|| Arc::new(crypto::ring::default_provider()));
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(SpkiPins {
+                pins: pins.to_vec(),
+                provider,
+            }))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(ca_file) => {
+                let certs = CertificateDer::pem_file_iter(ca_file)
+                    .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+                    .map_err(|e| Tls(format!("{}: {}", ca_file.display(), e)))?;
+                roots.add_parsable_certificates(certs);
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+
+    Ok(Arc::new(config))
+}
+
+#[derive(Debug)]
+/// A resolver reached over DNS over TLS, connections are kept open and reused between lookups
+///
+/// Each lookup has a connection to itself while it waits, so a slow response holds up no other.
+pub struct TlsUpstream {
+    pub server: SocketAddr,
+    /// Name the certificate must be valid for
+    name: ServerName<'static>,
+    config: Arc<ClientConfig>,
+    /// Connections left open by earlier lookups, none of them in use
+    idle: Mutex<Vec<StreamOwned<ClientConnection, TcpStream>>>,
+}
+
+impl TlsUpstream {
+    pub fn new(server: SocketAddr, name: &str, config: Arc<ClientConfig>) -> Result<Self> {
+        let name = ServerName::try_from(name)
+            .map_err(|_| Tls(format!("invalid server name {}", name)))?
+            .to_owned();
+
+        Ok(TlsUpstream {
+            server,
+            name,
+            config,
+            idle: Mutex::default(),
+        })
+    }
+
+    /// Open a new connection to the server
+    fn connect(&self) -> Result<StreamOwned<ClientConnection, TcpStream>> {
+        let stream = TcpStream::connect_timeout(&self.server, UPSTREAM_TIMEOUT).map_err(IOErr)?;
+        stream
+            .set_read_timeout(Some(UPSTREAM_TIMEOUT))
+            .map_err(IOErr)?;
+        let conn =
+            ClientConnection::new(Arc::clone(&self.config), self.name.clone()).map_err(tls_err)?;
+
+        Ok(StreamOwned::new(conn, stream))
+    }
+
+    /// Send the query and wait for the response that answers it
+    pub fn send(&self, query_packet: &DNSPacket) -> Result<DNSPacket> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pop();
+        let (res_packet, stream) = match idle {
+            // a connection the server closed while idle only shows once it is used again
+            Some(mut stream) => match self.exchange(&mut stream, query_packet) {
+                Ok(res_packet) => (res_packet, stream),
+                Err(_) => self.exchange_new(query_packet)?,
+            },
+            None => self.exchange_new(query_packet)?,
+        };
+
+        let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+
+        Ok(res_packet)
+    }
+
+    /// Send the query over a new connection, which is handed back for reuse
+    fn exchange_new(
+        &self,
+        query_packet: &DNSPacket,
+    ) -> Result<(DNSPacket, StreamOwned<ClientConnection, TcpStream>)> {
+        let mut stream = self.connect()?;
+        let res_packet = self.exchange(&mut stream, query_packet)?;
+
+        Ok((res_packet, stream))
+    }
+
+    /// Send the query over the connection and wait for the response that answers it
+    fn exchange(
+        &self,
+        stream: &mut StreamOwned<ClientConnection, TcpStream>,
+        query_packet: &DNSPacket,
+    ) -> Result<DNSPacket> {
+        write_message(stream, query_packet)?;
+
+        loop {
+            let mut res_buf = read_message(stream)?
+                .ok_or_else(|| Tls(String::from("upstream closed the connection")))?;
+            let mut res_packet = DNSPacket::new();
+            res_packet.parse(&mut res_buf)?;
+
+            if is_valid_response(query_packet, self.server, &res_packet, self.server) {
+                return Ok(res_packet);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    use super::*;
+    use crate::{
+        question::QueryType,
+        record::{Record, RecordPreamble},
+        server::new_query,
+    };
+
+    const SLOW: Duration = Duration::from_secs(2); // the stand-in holds back answers to slow.test
+
+    /// Answer A queries over TLS on a loopback port, standing in for the upstream, and the pin of
+    /// its key
+    fn stand_in_upstream() -> (SocketAddr, Vec<u8>) {
+        let certified = rcgen::generate_simple_self_signed(vec![String::from("dns.test")]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+        let config = Arc::new(
+            ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![certified.cert.der().clone()], key.into())
+                .unwrap(),
+        );
+        let cert = EndEntityCert::try_from(certified.cert.der()).unwrap();
+        let pin = Sha256::digest(cert.subject_public_key_info().as_ref()).to_vec();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let conn = ServerConnection::new(Arc::clone(&config)).unwrap();
+                let mut tls = StreamOwned::new(conn, stream.unwrap());
+                thread::spawn(move || {
+                    while let Ok(Some(mut buf)) = read_message(&mut tls) {
+                        let mut query = DNSPacket::new();
+                        query.parse(&mut buf).unwrap();
+                        let que = query.question_sec[0].clone();
+                        if que.name == "slow.test" {
+                            thread::sleep(SLOW);
+                        }
+
+                        let mut res = DNSPacket::new();
+                        res.header.id = query.header.id;
+                        res.header.qr = true;
+                        res.answer_sec.push(Record::A {
+                            preamble: RecordPreamble::new(&que.name, QueryType::A, 60),
+                            ip: "192.0.2.1".parse().unwrap(),
+                        });
+                        res.question_sec.push(que);
+                        write_message(&mut tls, &res).unwrap();
+                    }
+                });
+            }
+        });
+
+        (addr, pin)
+    }
+
+    fn upstream() -> Arc<TlsUpstream> {
+        let (addr, pin) = stand_in_upstream();
+        let config = client_config(None, &[pin], &[]).unwrap();
+        Arc::new(TlsUpstream::new(addr, "dns.test", config).unwrap())
+    }
+
+    #[test]
+    fn connections_are_reused() {
+        let upstream = upstream();
+        for _ in 0..3 {
+            let res = upstream.send(&new_query("www.test", QueryType::A)).unwrap();
+            assert_eq!(res.answer_sec.len(), 1);
+        }
+
+        assert_eq!(upstream.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn slow_response_holds_up_no_other() {
+        let upstream = upstream();
+        upstream.send(&new_query("www.test", QueryType::A)).unwrap();
+
+        let slow_upstream = Arc::clone(&upstream);
+        let slow = thread::spawn(move || {
+            slow_upstream
+                .send(&new_query("slow.test", QueryType::A))
+                .unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        let res = upstream.send(&new_query("www.test", QueryType::A)).unwrap();
+        assert!(start.elapsed() < SLOW / 2);
+        assert_eq!(res.question_sec[0].name, "www.test");
+
+        let res = slow.join().unwrap();
+        assert_eq!(res.question_sec[0].name, "slow.test");
+        assert_eq!(upstream.idle.lock().unwrap().len(), 2);
+    }
+}
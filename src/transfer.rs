@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+
+use super::{
+    dns_packet::DNSPacket,
+    edns::{edns_options, opt_record, ExtendedError, InfoCode},
+    errors::Result,
+    header::ResponseCode::{Formerr, Notauth, Refused, Servfail},
+    question::QueryType,
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    record::Record,
+    zone::{serial_gt, Zone, ZoneStore},
+};
+
+/// Records of a full transfer: the SOA, every other record and the SOA again
+fn axfr_records(zone: &Zone) -> Vec<Record> {
+    let mut records = zone.records();
+    records.extend(zone.soa().cloned());
+
+    records
+}
+
+/// Records of an incremental transfer to a client holding the given serial, RFC 1995
+///
+/// Falls back to the records of a full transfer when the journal cannot bridge the gap.
+fn ixfr_records(zone: &Zone, client_serial: u32) -> Vec<Record> {
+    let Some(soa) = zone.soa().cloned() else {
+        return Vec::new();
+    };
+
+    // a client that is up to date only gets the current SOA
+    if !serial_gt(zone.serial(), client_serial) {
+        return vec![soa];
+    }
+
+    let start = zone.journal.iter().position(|diff| match diff.from {
+        Record::Soa { serial, .. } => serial == client_serial,
+        _ => false,
+    });
+    let Some(start) = start else {
+        return axfr_records(zone);
+    };
+
+    let mut records = vec![soa.clone()];
+    for diff in &zone.journal[start..] {
+        records.push(diff.from.clone());
+        records.extend(diff.deleted.iter().cloned());
+        records.push(diff.to.clone());
+        records.extend(diff.added.iter().cloned());
+    }
+    records.push(soa);
+
+    records
+}
+
+/// Number of bytes the record takes up on the wire
+fn wire_len(rec: &Record) -> Result<usize> {
+    let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+    rec.write(&mut buf)?;
+
+    Ok(buf.cursor())
+}
+
+const TSIG_SPACE: usize = 512; // room left in each message for the TSIG that may close it
+
+/// Pack the records into as few response messages as fit, the question goes in the first one only
+fn pack_messages(query: &DNSPacket, records: Vec<Record>) -> Result<Vec<DNSPacket>> {
+    let mut messages = vec![response_to(query)];
+    let mut size = {
+        let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        messages[0].write(&mut buf)?;
+        buf.cursor()
+    };
+
+    for rec in records {
+        let len = wire_len(&rec)?;
+        let last = messages.len() - 1;
+        if size + len > MAX_PACKET_SIZE - TSIG_SPACE && !messages[last].answer_sec.is_empty() {
+            let mut next = response_to(query);
+            next.question_sec.clear();
+            messages.push(next);
+            size = 12; // header only
+        }
+
+        size += len;
+        let last = messages.len() - 1;
+        messages[last].answer_sec.push(rec);
+    }
+
+    Ok(messages)
+}
+
+/// An authoritative response carrying the question of the query
+fn response_to(query: &DNSPacket) -> DNSPacket {
+    let mut res = DNSPacket::new();
+    res.header.id = query.header.id;
+    res.header.qr = true;
+    res.header.aa = true;
+    res.question_sec = query.question_sec.clone();
+
+    res
+}
+
+/// Answer an AXFR or IXFR query with the messages of the transfer
+///
+/// Over UDP only an IXFR for an up to date client can be answered, anything larger gets the
+/// current SOA so the client retries over TCP. Key names the TSIG key the query was verified with.
+pub fn answer_transfer(
+    query: &DNSPacket,
+    query_src: SocketAddr,
+    key: Option<&str>,
+    zones: &ZoneStore,
+    tcp: bool,
+) -> Result<Vec<DNSPacket>> {
+    let Some(que) = query.question_sec.first() else {
+        let mut res = response_to(query);
+        res.header.rcode = Formerr;
+        return Ok(vec![res]);
+    };
+
+    let mut res = response_to(query);
+    let Some(zone) = zones.get(&que.name) else {
+        res.header.aa = false;
+        res.header.rcode = Notauth;
+        return Ok(vec![res]);
+    };
+
+    if !zone.allows_transfer(query_src.ip(), key) {
+        res.header.rcode = Refused;
+        if edns_options(query).is_some() {
+            let error = ExtendedError::new(InfoCode::Prohibited, "transfer not allowed");
+            res.additional_sec.push(opt_record(vec![error.to_option()]));
+        }
+        return Ok(vec![res]);
+    }
+
+    // an expired secondary must not pass on data its primary may have long replaced
+    if zone.expired {
+        res.header.rcode = Servfail;
+        return Ok(vec![res]);
+    }
+
+    let records = match que.query_type {
+        QueryType::Axfr if !tcp => {
+            res.header.rcode = Formerr;
+            return Ok(vec![res]);
+        }
+
+        QueryType::Axfr => axfr_records(zone),
+
+        _ => {
+            // the client tells us its version with an SOA in the authority section
+            let client_serial = query.authority_sec.iter().find_map(|rec| match rec {
+                Record::Soa { serial, .. } => Some(*serial),
+                _ => None,
+            });
+
+            match client_serial {
+                Some(_) if !tcp => zone.soa().cloned().into_iter().collect(),
+                Some(serial) => ixfr_records(zone, serial),
+                None => {
+                    res.header.rcode = Formerr;
+                    return Ok(vec![res]);
+                }
+            }
+        }
+    };
+
+    pack_messages(query, records)
+}
@@ -0,0 +1,694 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::{Sha256, Sha512};
+
+use super::{
+    dns_packet::DNSPacket,
+    errors::{
+        Errors::{Config as ConfigErr, IOErr, Tsig},
+        Result,
+    },
+    question::QueryType,
+    raw_packet::{RawPacket, MAX_PACKET_SIZE},
+    record::{Record, RecordPreamble},
+    zone::labels,
+};
+
+const FUDGE: u16 = 300; // seconds of clock skew allowed either way, as RFC 8945 recommends
+const CLASS_ANY: u16 = 255; // TSIG records always use class ANY
+const MAX_UNSIGNED: usize = 99; // unsigned messages allowed in a row within a transfer
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// Extended errors carried in the TSIG of a NOTAUTH response
+pub enum TsigError {
+    Badsig,
+    Badkey,
+    Badtime,
+}
+
+impl TsigError {
+    pub fn to_num(self) -> u16 {
+        match self {
+            Self::Badsig => 16,
+            Self::Badkey => 17,
+            Self::Badtime => 18,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// MAC algorithms keys can use
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    /// Look up an algorithm by the name used on the wire and in the keyring
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name
+            .strip_suffix('.')
+            .unwrap_or(name)
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "hmac-sha256" => Some(Self::HmacSha256),
+            "hmac-sha512" => Some(Self::HmacSha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    /// MAC of the data under the secret
+    fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::HmacSha256 => {
+                let mut mac = <Hmac<Sha256>>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Self::HmacSha512 => {
+                let mut mac = <Hmac<Sha512>>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    /// If the MAC of the data matches, compared in constant time
+    fn verify(self, secret: &[u8], data: &[u8], expected: &[u8]) -> bool {
+        match self {
+            Self::HmacSha256 => {
+                let mut mac = <Hmac<Sha256>>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            }
+            Self::HmacSha512 => {
+                let mut mac = <Hmac<Sha512>>::new_from_slice(secret).expect("HMAC takes any key");
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A secret shared with another server
+pub struct TsigKey {
+    /// Name both sides know the key by
+    pub name: String,
+    pub algorithm: Algorithm,
+    pub secret: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+/// Keys loaded from the keyring file
+///
+/// The file holds one key per line, `#` starts a comment:
+///
+/// ```text
+/// transfer-key hmac-sha256 c2VjcmV0IHNoYXJlZCB3aXRoIHRoZSBzZWNvbmRhcnk=
+/// ```
+pub struct Keyring {
+    /// Keys by lowercase name
+    keys: BTreeMap<String, TsigKey>,
+}
+
+impl Keyring {
+    /// Read the keys from the keyring file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).map_err(IOErr)?;
+
+        let mut keyring = Keyring::default();
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let key = match words.as_slice() {
+                [] => continue,
+                [name, algorithm, secret] => parse_key(name, algorithm, secret),
+                _ => Err(String::from("expected a name, algorithm and secret")),
+            }
+            .map_err(|msg| ConfigErr(format!("{}:{}: {}", path.display(), num + 1, msg)))?;
+
+            keyring.keys.insert(key_name(&key.name), key);
+        }
+
+        Ok(keyring)
+    }
+
+    /// The key with the given name
+    pub fn get(&self, name: &str) -> Option<&TsigKey> {
+        self.keys.get(&key_name(name))
+    }
+}
+
+/// Parse one line of the keyring
+fn parse_key(name: &str, algorithm: &str, secret: &str) -> std::result::Result<TsigKey, String> {
+    Ok(TsigKey {
+        name: key_name(name),
+        algorithm: Algorithm::from_name(algorithm)
+            .ok_or_else(|| format!("unsupported algorithm {}", algorithm))?,
+        secret: STANDARD
+            .decode(secret)
+            .map_err(|_| format!("invalid base64 secret for key {}", name))?,
+    })
+}
+
+/// Key names compare without case or a trailing dot
+fn key_name(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// Name in the uncompressed lowercase wire form the MAC is computed over
+fn canonical_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in labels(name).iter().rev() {
+        wire.push(label.len() as u8);
+        wire.extend(label.as_bytes());
+    }
+    wire.push(0);
+
+    wire
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Debug, Clone)]
+/// Signs a message with a TSIG record as it is written, RFC 8945
+pub struct Signer {
+    pub key: TsigKey,
+    /// Seconds since the epoch the signature claims, for BADTIME the time of the request
+    pub time_signed: u64,
+    /// Seconds since the epoch by our clock, told to a client whose clock is off
+    pub server_time: u64,
+    pub fudge: u16,
+    /// MAC of the request being answered or of the previous message of a transfer
+    pub prior_mac: Vec<u8>,
+    /// Later messages of a transfer only cover the timers instead of every TSIG variable
+    pub timers_only: bool,
+    /// Error reported back to the client, BADSIG and BADKEY responses carry no MAC
+    pub error: Option<TsigError>,
+}
+
+impl Signer {
+    /// Sign a request with the key
+    pub fn new(key: TsigKey) -> Self {
+        let now = now();
+        Signer {
+            key,
+            time_signed: now,
+            server_time: now,
+            fudge: FUDGE,
+            prior_mac: Vec::new(),
+            timers_only: false,
+            error: None,
+        }
+    }
+
+    /// Sign the response to a request that carried the given MAC
+    pub fn response(key: TsigKey, request_mac: Vec<u8>) -> Self {
+        Signer {
+            prior_mac: request_mac,
+            ..Self::new(key)
+        }
+    }
+
+    /// The TSIG record closing the message
+    fn record(&self, id: u16, mac: Vec<u8>) -> Record {
+        // a client with a wrong clock is told the time of the server
+        let other = match self.error {
+            Some(TsigError::Badtime) => self.server_time.to_be_bytes()[2..].to_vec(),
+            _ => Vec::new(),
+        };
+
+        Record::Tsig {
+            preamble: RecordPreamble {
+                class: CLASS_ANY,
+                ..RecordPreamble::new(&self.key.name, QueryType::Tsig, 0)
+            },
+            algorithm: String::from(self.key.algorithm.name()),
+            time_signed: self.time_signed,
+            fudge: self.fudge,
+            mac,
+            original_id: id,
+            error: self.error.map_or(0, TsigError::to_num),
+            other,
+        }
+    }
+
+    /// MAC of the message written without its TSIG
+    pub fn mac(&self, message: &[u8]) -> Vec<u8> {
+        match self.error {
+            Some(TsigError::Badsig | TsigError::Badkey) => Vec::new(),
+            _ => {
+                let tsig = self.record(0, Vec::new());
+                let digest = digest(&self.prior_mac, message, &tsig, self.timers_only);
+                self.key.algorithm.mac(&self.key.secret, &digest)
+            }
+        }
+    }
+
+    /// Append the TSIG to a message already written into the buffer
+    pub fn sign(&self, buf: &mut RawPacket, id: u16, ar_count: u16) -> Result<()> {
+        let mac = self.mac(buf.written());
+        self.record(id, mac).write(buf)?;
+        buf.set_u16(10, ar_count + 1)?;
+
+        Ok(())
+    }
+}
+
+/// Everything the MAC of a message covers
+///
+/// Later messages of a transfer only cover the timers of their TSIG instead of every field.
+fn digest(prior_mac: &[u8], message: &[u8], tsig: &Record, timers_only: bool) -> Vec<u8> {
+    let mut digest = Vec::new();
+    if !prior_mac.is_empty() {
+        digest.extend((prior_mac.len() as u16).to_be_bytes());
+        digest.extend(prior_mac);
+    }
+    digest.extend(message);
+
+    let Record::Tsig {
+        preamble,
+        algorithm,
+        time_signed,
+        fudge,
+        error,
+        other,
+        ..
+    } = tsig
+    else {
+        return digest;
+    };
+
+    if !timers_only {
+        digest.extend(canonical_name(&preamble.name));
+        digest.extend(preamble.class.to_be_bytes());
+        digest.extend(preamble.ttl.to_be_bytes());
+        digest.extend(canonical_name(algorithm));
+    }
+    digest.extend(&time_signed.to_be_bytes()[2..]);
+    digest.extend(fudge.to_be_bytes());
+    if !timers_only {
+        digest.extend(error.to_be_bytes());
+        digest.extend((other.len() as u16).to_be_bytes());
+        digest.extend(other);
+    }
+
+    digest
+}
+
+/// Set the signer of the message, returning the MAC it will carry
+pub fn sign(packet: &mut DNSPacket, signer: Signer) -> Result<Vec<u8>> {
+    packet.signer = None;
+    let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+    packet.write(&mut buf)?;
+
+    let mac = signer.mac(buf.written());
+    packet.signer = Some(signer);
+
+    Ok(mac)
+}
+
+/// Sign every message of a response, each one chained to the MAC of the one before
+pub fn sign_all(messages: &mut [DNSPacket], mut signer: Signer) -> Result<()> {
+    for packet in messages {
+        signer.prior_mac = sign(packet, signer.clone())?;
+        signer.timers_only = true;
+    }
+
+    Ok(())
+}
+
+/// The bytes the sender computed the MAC over: the message as it was before the TSIG was added
+fn unsigned_message(buf: &RawPacket, packet: &DNSPacket, original_id: u16) -> Option<Vec<u8>> {
+    let mut message = buf.buf.get(..packet.tsig_start?)?.to_vec();
+    message[..2].copy_from_slice(&original_id.to_be_bytes());
+    message[10..12].copy_from_slice(&(packet.header.ar_count - 1).to_be_bytes());
+
+    Some(message)
+}
+
+/// Check the MAC and clock of a TSIG, returning its MAC
+fn check(
+    key: &TsigKey,
+    tsig: &Record,
+    message: &[u8],
+    prior_mac: &[u8],
+    timers_only: bool,
+) -> std::result::Result<Vec<u8>, TsigError> {
+    let Record::Tsig {
+        time_signed,
+        fudge,
+        mac,
+        ..
+    } = tsig
+    else {
+        return Err(TsigError::Badsig);
+    };
+
+    // truncated MACs are not accepted
+    let digest = digest(prior_mac, message, tsig, timers_only);
+    if !key.algorithm.verify(&key.secret, &digest, mac) {
+        return Err(TsigError::Badsig);
+    }
+    if now().abs_diff(*time_signed) > *fudge as u64 {
+        return Err(TsigError::Badtime);
+    }
+
+    Ok(mac.clone())
+}
+
+/// Check the TSIG of a request, giving the signer for the response
+///
+/// An unsigned request gives None, one that fails the check gives the signer of the NOTAUTH
+/// response reporting why.
+pub fn verify_request(
+    buf: &RawPacket,
+    packet: &DNSPacket,
+    keyring: &Keyring,
+) -> std::result::Result<Option<Signer>, Signer> {
+    let Some(
+        tsig @ Record::Tsig {
+            preamble,
+            algorithm,
+            time_signed,
+            mac,
+            original_id,
+            ..
+        },
+    ) = packet.additional_sec.last()
+    else {
+        return Ok(None);
+    };
+
+    let key = keyring
+        .get(&preamble.name)
+        .filter(|key| Algorithm::from_name(algorithm) == Some(key.algorithm));
+    let Some(key) = key else {
+        // the client is told which key was not recognised
+        let mut signer = Signer::new(TsigKey {
+            name: preamble.name.clone(),
+            algorithm: Algorithm::from_name(algorithm).unwrap_or(Algorithm::HmacSha256),
+            secret: Vec::new(),
+        });
+        signer.error = Some(TsigError::Badkey);
+        return Err(signer);
+    };
+
+    let result = match unsigned_message(buf, packet, *original_id) {
+        Some(message) => check(key, tsig, &message, &[], false),
+        None => Err(TsigError::Badsig),
+    };
+
+    match result {
+        Ok(mac) => Ok(Some(Signer::response(key.clone(), mac))),
+        Err(TsigError::Badtime) => {
+            // the response carries the time of the request so the client can check it, ours
+            // goes in the other data, RFC 8945 section 5.2.3
+            let mut signer = Signer::response(key.clone(), mac.clone());
+            signer.time_signed = *time_signed;
+            signer.error = Some(TsigError::Badtime);
+            Err(signer)
+        }
+        Err(error) => {
+            let mut signer = Signer::new(key.clone());
+            signer.error = Some(error);
+            Err(signer)
+        }
+    }
+}
+
+/// Checks the TSIG of each message answering a signed request, which for a transfer may be many
+pub struct ResponseVerifier<'a> {
+    key: &'a TsigKey,
+    /// MAC of the request, then of the last signed message
+    prior_mac: Vec<u8>,
+    /// Messages received since the last signed one, covered by the next MAC
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+    /// Set once the first message, which covers every TSIG variable, has been checked
+    started: bool,
+}
+
+impl<'a> ResponseVerifier<'a> {
+    /// Check responses to a request that carried the given MAC
+    pub fn new(key: &'a TsigKey, request_mac: Vec<u8>) -> Self {
+        ResponseVerifier {
+            key,
+            prior_mac: request_mac,
+            unsigned: Vec::new(),
+            unsigned_count: 0,
+            started: false,
+        }
+    }
+
+    /// Check the next message, errors when its MAC is wrong or too many went unsigned
+    pub fn check(&mut self, buf: &RawPacket, packet: &DNSPacket) -> Result<()> {
+        let (tsig, key, original_id, error) = match packet.additional_sec.last() {
+            Some(
+                tsig @ Record::Tsig {
+                    preamble,
+                    original_id,
+                    error,
+                    ..
+                },
+            ) if packet.tsig_start.is_some() => (tsig, &preamble.name, *original_id, *error),
+            // only messages after the first of a transfer may go unsigned, the buffer holds
+            // exactly the message read from the stream
+            _ if self.started && self.unsigned_count < MAX_UNSIGNED => {
+                self.unsigned.extend(&buf.buf);
+                self.unsigned_count += 1;
+                return Ok(());
+            }
+            _ => return Err(Tsig(String::from("response is not signed"))),
+        };
+
+        if key_name(key) != self.key.name {
+            return Err(Tsig(format!("response signed with key {}", key)));
+        }
+        if error != 0 {
+            return Err(Tsig(format!("server reported TSIG error {}", error)));
+        }
+
+        let mut message = std::mem::take(&mut self.unsigned);
+        message.extend(
+            unsigned_message(buf, packet, original_id)
+                .ok_or_else(|| Tsig(String::from("malformed TSIG")))?,
+        );
+        self.prior_mac = check(self.key, tsig, &message, &self.prior_mac, self.started)
+            .map_err(|error| Tsig(format!("{:?}", error)))?;
+        self.unsigned_count = 0;
+        self.started = true;
+
+        Ok(())
+    }
+
+    /// If the last message checked was signed, a transfer must end on one
+    pub fn is_signed(&self) -> bool {
+        self.started && self.unsigned_count == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::question::Question;
+
+    fn key(algorithm: Algorithm) -> TsigKey {
+        TsigKey {
+            name: String::from("xfr-key"),
+            algorithm,
+            secret: b"0123456789abcdef0123456789abcdef".to_vec(),
+        }
+    }
+
+    fn keyring(key: &TsigKey) -> Keyring {
+        Keyring {
+            keys: BTreeMap::from([(key.name.clone(), key.clone())]),
+        }
+    }
+
+    fn query() -> DNSPacket {
+        let mut que = Question::new();
+        que.name = String::from("example.com");
+        que.query_type = QueryType::Axfr;
+        que.class = 1;
+
+        let mut packet = DNSPacket::new();
+        packet.header.id = 4321;
+        packet.question_sec.push(que);
+        packet
+    }
+
+    /// The message as the other side reads it, the bytes changed on the way by the function
+    fn received(packet: &DNSPacket, tamper: impl Fn(&mut [u8])) -> (RawPacket, DNSPacket) {
+        let mut buf = RawPacket::with_size(MAX_PACKET_SIZE);
+        packet.write(&mut buf).unwrap();
+
+        let mut wire = RawPacket::with_size(buf.cursor());
+        wire.buf.copy_from_slice(buf.written());
+        tamper(&mut wire.buf);
+        let mut parsed = DNSPacket::new();
+        parsed.parse(&mut wire).unwrap();
+        (wire, parsed)
+    }
+
+    /// The error the response to the signed request reports
+    fn request_error(signer: Signer, keyring: &Keyring, tamper: impl Fn(&mut [u8])) -> Signer {
+        let mut packet = query();
+        sign(&mut packet, signer).unwrap();
+        let (buf, packet) = received(&packet, tamper);
+        verify_request(&buf, &packet, keyring).unwrap_err()
+    }
+
+    #[test]
+    fn signed_request_and_response_verify() {
+        for algorithm in [Algorithm::HmacSha256, Algorithm::HmacSha512] {
+            let key = key(algorithm);
+            let mut request = query();
+            let request_mac = sign(&mut request, Signer::new(key.clone())).unwrap();
+            let (buf, request) = received(&request, |_| {});
+            let signer = verify_request(&buf, &request, &keyring(&key))
+                .unwrap()
+                .unwrap();
+
+            let mut response = request.clone();
+            response.header.qr = true;
+            response.additional_sec.clear();
+            sign(&mut response, signer).unwrap();
+            let (buf, response) = received(&response, |_| {});
+            let mut verifier = ResponseVerifier::new(&key, request_mac);
+            verifier.check(&buf, &response).unwrap();
+            assert!(verifier.is_signed());
+        }
+    }
+
+    #[test]
+    fn changed_message_is_badsig() {
+        let key = key(Algorithm::HmacSha256);
+        let signer = request_error(Signer::new(key.clone()), &keyring(&key), |bytes| {
+            bytes[3] ^= 0x10
+        });
+        assert_eq!(signer.error, Some(TsigError::Badsig));
+    }
+
+    #[test]
+    fn unknown_key_is_badkey() {
+        let key = key(Algorithm::HmacSha256);
+        let mut other = key.clone();
+        other.name = String::from("other-key");
+        let signer = request_error(Signer::new(key), &keyring(&other), |_| {});
+
+        assert_eq!(signer.error, Some(TsigError::Badkey));
+        assert_eq!(signer.key.name, "xfr-key");
+    }
+
+    #[test]
+    fn skewed_clock_is_badtime() {
+        let key = key(Algorithm::HmacSha256);
+        let mut skewed = Signer::new(key.clone());
+        skewed.time_signed -= FUDGE as u64 + 60;
+        let signer = request_error(skewed.clone(), &keyring(&key), |_| {});
+
+        assert_eq!(signer.error, Some(TsigError::Badtime));
+        // the request time is echoed, ours is only in the other data
+        assert_eq!(signer.time_signed, skewed.time_signed);
+        assert!(signer.server_time >= skewed.server_time);
+
+        let mut in_time = Signer::new(key.clone());
+        in_time.time_signed -= FUDGE as u64 - 60;
+        let mut packet = query();
+        sign(&mut packet, in_time).unwrap();
+        let (buf, packet) = received(&packet, |_| {});
+        assert!(verify_request(&buf, &packet, &keyring(&key)).is_ok());
+    }
+
+    #[test]
+    fn transfer_may_leave_messages_unsigned_but_not_the_last() {
+        let key = key(Algorithm::HmacSha512);
+        let request_mac = vec![7; 64];
+        let messages: Vec<DNSPacket> = (0..4)
+            .map(|_| {
+                let mut packet = query();
+                packet.header.qr = true;
+                packet
+            })
+            .collect();
+
+        // every message signed
+        let mut signed = messages.clone();
+        sign_all(
+            &mut signed,
+            Signer::response(key.clone(), request_mac.clone()),
+        )
+        .unwrap();
+        let mut verifier = ResponseVerifier::new(&key, request_mac.clone());
+        for packet in &signed {
+            let (buf, packet) = received(packet, |_| {});
+            verifier.check(&buf, &packet).unwrap();
+        }
+        assert!(verifier.is_signed());
+
+        // the first and last signed, the last MAC covering the two in between as well
+        let mut signer = Signer::response(key.clone(), request_mac.clone());
+        let mut first = messages[0].clone();
+        signer.prior_mac = sign(&mut first, signer.clone()).unwrap();
+        signer.timers_only = true;
+        let unsigned: Vec<(RawPacket, DNSPacket)> = messages[1..]
+            .iter()
+            .map(|packet| received(packet, |_| {}))
+            .collect();
+        // the MAC covers the unsigned messages along with the last one
+        let covered: Vec<u8> = unsigned
+            .iter()
+            .flat_map(|(buf, _)| buf.buf.clone())
+            .collect();
+        let mut last = RawPacket::with_size(MAX_PACKET_SIZE);
+        messages[3].write(&mut last).unwrap();
+        let mac = signer.mac(&covered);
+        signer
+            .record(messages[3].header.id, mac)
+            .write(&mut last)
+            .unwrap();
+        last.set_u16(10, 1).unwrap();
+        let mut wire = RawPacket::with_size(last.cursor());
+        wire.buf.copy_from_slice(last.written());
+        let mut last_packet = DNSPacket::new();
+        last_packet.parse(&mut wire).unwrap();
+
+        let mut verifier = ResponseVerifier::new(&key, request_mac);
+        let (buf, packet) = received(&first, |_| {});
+        verifier.check(&buf, &packet).unwrap();
+        for (buf, packet) in &unsigned[..2] {
+            verifier.check(buf, packet).unwrap();
+            assert!(!verifier.is_signed());
+        }
+        verifier.check(&wire, &last_packet).unwrap();
+        assert!(verifier.is_signed());
+
+        // a transfer ending on an unsigned message is not accepted
+        let (buf, packet) = &unsigned[0];
+        verifier.check(buf, packet).unwrap();
+        assert!(!verifier.is_signed());
+    }
+}
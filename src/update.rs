@@ -0,0 +1,324 @@
+use std::net::SocketAddr;
+
+use super::{
+    dns_packet::DNSPacket,
+    edns::{edns_options, opt_record, ExtendedError, InfoCode},
+    header::{
+        Opcode,
+        ResponseCode::{
+            self, Formerr, Notauth, Notzone, Nxdomain, Nxrrset, Refused, Servfail, Yxdomain,
+            Yxrrset,
+        },
+    },
+    question::QueryType,
+    record::{Record, CLASS_ANY, CLASS_NONE},
+    server::ServerState,
+    zone::{is_in_zone, labels, serial_gt, Zone},
+};
+
+const CLASS_IN: u16 = 1;
+const TYPE_ANY: u16 = 255;
+
+/// The sections of an UPDATE message, which reuse the sections of a query, RFC 2136
+struct UpdateMessage<'a> {
+    /// Name of the zone to update, carried as the question
+    zone: &'a str,
+    /// Conditions the zone must meet, carried in the answer section
+    prerequisites: &'a [Record],
+    /// Records to add or delete, carried in the authority section
+    updates: &'a [Record],
+}
+
+impl<'a> UpdateMessage<'a> {
+    /// Split an UPDATE into its sections, None when the zone section is malformed
+    fn parse(packet: &'a DNSPacket) -> Option<Self> {
+        let [zone] = packet.question_sec.as_slice() else {
+            return None;
+        };
+        if zone.query_type != QueryType::Soa {
+            return None;
+        }
+
+        Some(UpdateMessage {
+            zone: &zone.name,
+            prerequisites: &packet.answer_sec,
+            updates: &packet.authority_sec,
+        })
+    }
+}
+
+/// If both names are the same, ignoring case
+fn same_name(a: &str, b: &str) -> bool {
+    labels(a) == labels(b)
+}
+
+/// If both records have the same name and type
+fn same_rrset(a: &Record, b: &Record) -> bool {
+    let (a, b) = (a.preamble(), b.preamble());
+    a.query_type.to_num() == b.query_type.to_num() && same_name(&a.name, &b.name)
+}
+
+/// If both records carry the same data, whatever their class and TTL
+fn same_data(a: &Record, b: &Record) -> bool {
+    let mut a = a.clone();
+    a.preamble_mut().class = b.preamble().class;
+    a.preamble_mut().ttl = b.preamble().ttl;
+
+    a == *b
+}
+
+/// Check that the zone meets every prerequisite, RFC 2136 section 3.2
+fn check_prerequisites(
+    origin: &str,
+    prerequisites: &[Record],
+    records: &[Record],
+) -> Result<(), ResponseCode> {
+    for (pos, pre) in prerequisites.iter().enumerate() {
+        let preamble = pre.preamble();
+        if preamble.ttl != 0 {
+            return Err(Formerr);
+        }
+        if !is_in_zone(&preamble.name, origin) {
+            return Err(Notzone);
+        }
+
+        let any_type = preamble.query_type.to_num() == TYPE_ANY;
+        let name_in_use = records
+            .iter()
+            .any(|rec| same_name(&rec.preamble().name, &preamble.name));
+        let rrset_exists = records.iter().any(|rec| same_rrset(rec, pre));
+
+        match preamble.class {
+            CLASS_ANY if any_type && !name_in_use => return Err(Nxdomain),
+            CLASS_ANY if !any_type && !rrset_exists => return Err(Nxrrset),
+            CLASS_NONE if any_type && name_in_use => return Err(Yxdomain),
+            CLASS_NONE if !any_type && rrset_exists => return Err(Yxrrset),
+            CLASS_ANY | CLASS_NONE => {}
+
+            CLASS_IN if any_type => return Err(Formerr),
+            CLASS_IN => {
+                // the RRset must hold exactly the records listed for it, checked once per RRset
+                let same_check =
+                    |other: &&Record| other.preamble().class == CLASS_IN && same_rrset(other, pre);
+                if prerequisites[..pos].iter().any(|prev| same_check(&prev)) {
+                    continue;
+                }
+                let wanted: Vec<&Record> = prerequisites.iter().filter(same_check).collect();
+                let existing: Vec<&Record> =
+                    records.iter().filter(|rec| same_rrset(rec, pre)).collect();
+
+                let covered = wanted
+                    .iter()
+                    .all(|want| existing.iter().any(|rec| same_data(want, rec)));
+                let complete = existing
+                    .iter()
+                    .all(|rec| wanted.iter().any(|want| same_data(want, rec)));
+                if !covered || !complete {
+                    return Err(Nxrrset);
+                }
+            }
+
+            _ => return Err(Formerr),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reject malformed updates before anything is changed, RFC 2136 section 3.4.1
+fn prescan(origin: &str, updates: &[Record]) -> Result<(), ResponseCode> {
+    for upd in updates {
+        let preamble = upd.preamble();
+        if !is_in_zone(&preamble.name, origin) {
+            return Err(Notzone);
+        }
+
+        let query_type = preamble.query_type.to_num();
+        let meta_type = matches!(query_type, TYPE_ANY) || (251..=254).contains(&query_type);
+        let empty = matches!(upd, Record::Unknown { data, .. } if data.is_empty());
+
+        let valid = match preamble.class {
+            CLASS_IN => !meta_type,
+            CLASS_ANY => preamble.ttl == 0 && empty && (query_type == TYPE_ANY || !meta_type),
+            CLASS_NONE => preamble.ttl == 0 && !meta_type,
+            _ => false,
+        };
+        if !valid {
+            return Err(Formerr);
+        }
+    }
+
+    Ok(())
+}
+
+/// Add a record unless it clashes with a CNAME, returns if the zone changed
+fn add_record(records: &mut Vec<Record>, mut rec: Record, at_apex: bool) -> bool {
+    rec.preamble_mut().class = CLASS_IN;
+
+    match rec {
+        Record::Soa { serial, .. } => {
+            // only a newer SOA at the apex replaces the current one
+            let current = records.iter().position(|r| matches!(r, Record::Soa { .. }));
+            match current {
+                Some(pos) if at_apex && serial_gt(serial, soa_serial(&records[pos])) => {
+                    records[pos] = rec;
+                    return true;
+                }
+                _ => return false,
+            }
+        }
+
+        // a CNAME cannot share its name with other data
+        Record::Cname { .. } => {
+            let other_data = records.iter().any(|r| {
+                same_name(&r.preamble().name, &rec.preamble().name)
+                    && !matches!(r, Record::Cname { .. })
+            });
+            if other_data {
+                return false;
+            }
+        }
+        _ => {
+            let has_cname = records.iter().any(|r| {
+                same_name(&r.preamble().name, &rec.preamble().name)
+                    && matches!(r, Record::Cname { .. })
+            });
+            if has_cname {
+                return false;
+            }
+        }
+    }
+
+    // a CNAME replaces the one already there, a duplicate record only updates the TTL
+    let existing = records.iter().position(|r| {
+        same_rrset(r, &rec) && (matches!(rec, Record::Cname { .. }) || same_data(&rec, r))
+    });
+    match existing {
+        Some(pos) if records[pos] == rec && records[pos].preamble().ttl == rec.preamble().ttl => {
+            false
+        }
+        Some(pos) => {
+            records[pos] = rec;
+            true
+        }
+        None => {
+            records.push(rec);
+            true
+        }
+    }
+}
+
+/// Serial number of an SOA record
+fn soa_serial(rec: &Record) -> u32 {
+    match rec {
+        Record::Soa { serial, .. } => *serial,
+        _ => 0,
+    }
+}
+
+/// Apply every update to the records, returns if anything changed, RFC 2136 section 3.4.2
+fn apply_updates(origin: &str, updates: &[Record], records: &mut Vec<Record>) -> bool {
+    let mut changed = false;
+
+    for upd in updates {
+        let preamble = upd.preamble();
+        let at_apex = same_name(&preamble.name, origin);
+        // the apex always keeps its SOA and NS records
+        let protected =
+            |rec: &Record| at_apex && matches!(rec, Record::Soa { .. } | Record::NS { .. });
+        let before = records.len();
+
+        match preamble.class {
+            CLASS_IN => changed |= add_record(records, upd.clone(), at_apex),
+
+            CLASS_ANY if preamble.query_type.to_num() == TYPE_ANY => records
+                .retain(|rec| !same_name(&rec.preamble().name, &preamble.name) || protected(rec)),
+
+            CLASS_ANY => records.retain(|rec| !same_rrset(rec, upd) || protected(rec)),
+
+            _ => {
+                let ns_count = records
+                    .iter()
+                    .filter(|rec| same_rrset(rec, upd) && matches!(rec, Record::NS { .. }))
+                    .count();
+                let keep = |rec: &Record| {
+                    matches!(rec, Record::Soa { .. })
+                        || (at_apex && matches!(rec, Record::NS { .. }) && ns_count <= 1)
+                };
+                records.retain(|rec| !same_rrset(rec, upd) || !same_data(upd, rec) || keep(rec));
+            }
+        }
+
+        changed |= records.len() != before;
+    }
+
+    changed
+}
+
+/// Check the prerequisites of an UPDATE and apply its changes to the zone as one
+fn update_zone(
+    message: &UpdateMessage,
+    query_src: SocketAddr,
+    key: Option<&str>,
+    state: &ServerState,
+) -> Result<(), ResponseCode> {
+    // hold the write lock throughout so no other change slips in between, the zone is the one
+    // in the client's view
+    let mut zones = state.view(query_src.ip()).zones_mut();
+    let zone = zones.get(message.zone).ok_or(Notauth)?;
+    if !zone.allows_update(query_src.ip(), key) {
+        return Err(Refused);
+    }
+
+    let mut records = zone.records();
+    check_prerequisites(&zone.origin, message.prerequisites, &records)?;
+    prescan(&zone.origin, message.updates)?;
+    if !apply_updates(&zone.origin, message.updates, &mut records) {
+        return Ok(());
+    }
+
+    // secondaries only pick up the change once the serial moves on
+    if !serial_gt(soa_serial(&records[0]), zone.serial()) {
+        if let Record::Soa { serial, .. } = &mut records[0] {
+            *serial = zone.serial().wrapping_add(1);
+        }
+    }
+
+    Zone::new(&zone.origin, records)
+        .and_then(|updated| zones.replace(updated))
+        .map_err(|e| {
+            eprintln!("failed to update zone {}: {}", message.zone, e);
+            Servfail
+        })
+}
+
+/// Answer a dynamic UPDATE, RFC 2136
+///
+/// The updated zone is kept in memory only, its master file is no longer watched for changes so
+/// a reload cannot undo the update. Key names the TSIG key the UPDATE was verified with.
+pub fn handle_update(
+    query: &DNSPacket,
+    query_src: SocketAddr,
+    key: Option<&str>,
+    state: &ServerState,
+) -> DNSPacket {
+    let mut res = DNSPacket::new();
+    res.header.id = query.header.id;
+    res.header.qr = true;
+    res.header.op_code = Opcode::Update;
+    res.question_sec = query.question_sec.clone();
+
+    res.header.rcode = match UpdateMessage::parse(query) {
+        None => Formerr,
+        Some(message) => match update_zone(&message, query_src, key, state) {
+            Ok(()) => ResponseCode::Noerror,
+            Err(rcode) => rcode,
+        },
+    };
+    if res.header.rcode == Refused && edns_options(query).is_some() {
+        let error = ExtendedError::new(InfoCode::Prohibited, "update not allowed");
+        res.additional_sec.push(opt_record(vec![error.to_option()]));
+    }
+
+    res
+}
@@ -0,0 +1,750 @@
+use std::{
+    collections::BTreeMap,
+    fs, mem,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use super::{
+    acl::Acl,
+    dns_packet::DNSPacket,
+    errors::{Errors::ZoneFile, Result},
+    header::ResponseCode::{Nxdomain, Yxdomain},
+    question::{QueryType, Question},
+    record::{Record, RecordPreamble},
+    zone_file::load_zone_file,
+};
+
+const MAX_CHAIN: usize = 16; // longest CNAME chain followed inside a zone
+const MAX_NAME_LEN: usize = 253; // longest domain name in text form
+const MAX_JOURNAL: usize = 64; // changes kept per zone for incremental transfers
+
+/// Lowercase labels of a domain name, starting from the one closest to the root
+pub fn labels(name: &str) -> Vec<String> {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .rev()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+/// Compare SOA serials using RFC 1982 sequence space arithmetic
+pub fn serial_gt(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+/// If the name is the given zone origin or lies below it
+pub fn is_in_zone(name: &str, origin: &str) -> bool {
+    labels(name).starts_with(&labels(origin))
+}
+
+#[derive(Debug, Default)]
+/// A node of the zone tree holding the records owned by one domain name
+struct ZoneNode {
+    /// Nodes one label further from the root, keyed by lowercase label
+    children: BTreeMap<String, ZoneNode>,
+    /// Records owned by this name
+    records: Vec<Record>,
+}
+
+impl ZoneNode {
+    /// Records of the given type owned by this node
+    fn records_of(&self, query_type: &QueryType) -> Vec<Record> {
+        self.records
+            .iter()
+            .filter(|rec| rec.preamble().query_type == *query_type)
+            .cloned()
+            .collect()
+    }
+
+    /// If this node owns records of the given type
+    fn has(&self, query_type: &QueryType) -> bool {
+        self.records
+            .iter()
+            .any(|rec| rec.preamble().query_type == *query_type)
+    }
+}
+
+/// Outcome of looking a name up in a zone
+pub enum ZoneAnswer {
+    /// Records answering the question, or the CNAME the name is an alias for
+    Answer(Vec<Record>),
+    /// The name lies at or below a zone cut, with the delegation NS records and their glue
+    Referral { ns: Vec<Record>, glue: Vec<Record> },
+    /// The name exists but has no records of the requested type
+    NoData,
+    /// The name does not exist in the zone
+    NxDomain,
+    /// Substituting a DNAME made the name too long
+    YxDomain,
+}
+
+#[derive(Debug, Clone)]
+/// One change to a zone between two SOA serials, as sent in an IXFR
+pub struct ZoneDiff {
+    /// SOA of the zone before the change
+    pub from: Record,
+    /// SOA of the zone after the change
+    pub to: Record,
+    pub deleted: Vec<Record>,
+    pub added: Vec<Record>,
+}
+
+#[derive(Debug)]
+/// Master file a zone was loaded from
+struct ZoneSource {
+    path: PathBuf,
+    /// Modification time of the file when it was loaded
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+/// Authoritative data for a single zone kept as a tree of labels
+pub struct Zone {
+    /// Domain name at the apex of the zone
+    pub origin: String,
+    /// Node of the apex, every other name hangs below it
+    root: ZoneNode,
+    /// Recent changes, oldest first, used to answer IXFR queries
+    pub journal: Vec<ZoneDiff>,
+    /// Clients allowed to transfer the zone
+    pub allow_transfer: Acl,
+    /// Clients allowed to change the zone with dynamic updates
+    pub allow_update: Acl,
+    /// Master file to reload the zone from when it changes
+    source: Option<ZoneSource>,
+    /// Set on a secondary zone that could not be refreshed within its expire interval
+    pub expired: bool,
+}
+
+impl Zone {
+    /// Build a zone from its records, which must include an SOA at the apex
+    pub fn new(origin: &str, records: Vec<Record>) -> Result<Self> {
+        let mut zone = Zone {
+            origin: String::from(origin.strip_suffix('.').unwrap_or(origin)),
+            root: ZoneNode::default(),
+            journal: Vec::new(),
+            allow_transfer: Acl::default(),
+            allow_update: Acl::default(),
+            source: None,
+            expired: false,
+        };
+
+        for rec in records {
+            zone.insert(rec)?;
+        }
+
+        if zone.soa().is_none() {
+            return Err(ZoneFile(format!("zone {} has no SOA record", zone.origin)));
+        }
+
+        Ok(zone)
+    }
+
+    /// Load a zone from an RFC 1035 master file
+    pub fn load(origin: &str, path: &Path) -> Result<Self> {
+        let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        let mut zone = Self::new(origin, load_zone_file(path, origin)?)?;
+        zone.source = Some(ZoneSource {
+            path: path.to_path_buf(),
+            modified,
+        });
+
+        Ok(zone)
+    }
+
+    /// Add a record to the zone, creating the nodes on the way to its name
+    pub fn insert(&mut self, rec: Record) -> Result<()> {
+        let rel = self.relative_labels(&rec.preamble().name).ok_or_else(|| {
+            ZoneFile(format!(
+                "{} is outside of zone {}",
+                rec.preamble().name,
+                self.origin
+            ))
+        })?;
+
+        let mut node = &mut self.root;
+        for label in rel {
+            node = node.children.entry(label).or_default();
+        }
+        node.records.push(rec);
+
+        Ok(())
+    }
+
+    /// Labels of the name below the apex, starting next to the apex
+    fn relative_labels(&self, name: &str) -> Option<Vec<String>> {
+        let name_labels = labels(name);
+        let origin_labels = labels(&self.origin);
+
+        name_labels
+            .strip_prefix(origin_labels.as_slice())
+            .map(|rel| rel.to_vec())
+    }
+
+    /// Node owning exactly the given name, ignoring zone cuts
+    fn find_node(&self, name: &str) -> Option<&ZoneNode> {
+        let mut node = &self.root;
+        for label in self.relative_labels(name)? {
+            node = node.children.get(&label)?;
+        }
+
+        Some(node)
+    }
+
+    /// The SOA record at the apex of the zone
+    pub fn soa(&self) -> Option<&Record> {
+        self.root
+            .records
+            .iter()
+            .find(|rec| matches!(rec, Record::Soa { .. }))
+    }
+
+    /// Serial number of the current version of the zone
+    pub fn serial(&self) -> u32 {
+        match self.soa() {
+            Some(Record::Soa { serial, .. }) => *serial,
+            _ => 0,
+        }
+    }
+
+    /// Every record in the zone, starting with the SOA
+    pub fn records(&self) -> Vec<Record> {
+        let mut records: Vec<Record> = self.soa().into_iter().cloned().collect();
+
+        let mut nodes = vec![&self.root];
+        while let Some(node) = nodes.pop() {
+            records.extend(
+                node.records
+                    .iter()
+                    .filter(|rec| !matches!(rec, Record::Soa { .. }))
+                    .cloned(),
+            );
+            nodes.extend(node.children.values().rev());
+        }
+
+        records
+    }
+
+    /// If the client may transfer this zone, key names the TSIG key it signed with
+    pub fn allows_transfer(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        self.allow_transfer.allows(addr, key)
+    }
+
+    /// If the client may send dynamic updates for this zone, key names the TSIG key it signed with
+    pub fn allows_update(&self, addr: IpAddr, key: Option<&str>) -> bool {
+        self.allow_update.allows(addr, key)
+    }
+
+    /// Take over the journal of the previous version of the zone and record what changed since
+    fn follow(&mut self, old: &mut Zone) -> Result<()> {
+        let old_records = old.records();
+        let new_records = self.records();
+
+        // without a newer serial secondaries cannot tell the zone changed, so the change is refused
+        if !serial_gt(self.serial(), old.serial()) {
+            let unchanged = new_records.len() == old_records.len()
+                && new_records.iter().all(|rec| old_records.contains(rec));
+            if !unchanged {
+                return Err(ZoneFile(format!(
+                    "zone {} changed without increasing its serial {}",
+                    self.origin,
+                    self.serial()
+                )));
+            }
+        } else if let (Some(from), Some(to)) = (old.soa().cloned(), self.soa().cloned()) {
+            old.journal.push(ZoneDiff {
+                from,
+                to,
+                deleted: old_records[1..]
+                    .iter()
+                    .filter(|rec| !new_records.contains(rec))
+                    .cloned()
+                    .collect(),
+                added: new_records[1..]
+                    .iter()
+                    .filter(|rec| !old_records.contains(rec))
+                    .cloned()
+                    .collect(),
+            });
+
+            if old.journal.len() > MAX_JOURNAL {
+                old.journal.remove(0);
+            }
+        }
+
+        self.journal = mem::take(&mut old.journal);
+        self.allow_transfer = old.allow_transfer.clone();
+        self.allow_update = old.allow_update.clone();
+
+        Ok(())
+    }
+
+    /// SOA to put in the authority section of negative answers, its TTL capped by the minimum field
+    fn negative_soa(&self) -> Vec<Record> {
+        self.soa()
+            .map(|soa| {
+                let mut soa = soa.clone();
+                if let Record::Soa {
+                    preamble, minimum, ..
+                } = &mut soa
+                {
+                    preamble.ttl = preamble.ttl.min(*minimum);
+                }
+                soa
+            })
+            .into_iter()
+            .collect()
+    }
+
+    /// Look the name up in the zone following RFC 1034 section 4.3.2
+    pub fn lookup(&self, name: &str, query_type: &QueryType) -> ZoneAnswer {
+        let Some(rel) = self.relative_labels(name) else {
+            return ZoneAnswer::NxDomain;
+        };
+
+        let mut node = &self.root;
+        for label in &rel {
+            // a DNAME redirects every name below its owner, but not the owner itself
+            if let Some(dname) = node.records_of(&QueryType::Dname).into_iter().next() {
+                return self.synthesize_cname(dname, name);
+            }
+
+            match node.children.get(label) {
+                Some(child) => {
+                    node = child;
+
+                    // NS records below the apex mark a zone cut
+                    if node.has(&QueryType::NS) {
+                        return self.referral(node);
+                    }
+                }
+
+                None => {
+                    // the deepest existing node is the closest encloser, which may hold a wildcard
+                    if let Some(wildcard) = node.children.get("*") {
+                        return self.answer_node(wildcard, query_type, Some(name));
+                    }
+
+                    return ZoneAnswer::NxDomain;
+                }
+            }
+        }
+
+        self.answer_node(node, query_type, None)
+    }
+
+    /// Answer with the DNAME and a CNAME to the name it maps the query name to, RFC 6672
+    fn synthesize_cname(&self, dname: Record, name: &str) -> ZoneAnswer {
+        let Record::Dname {
+            preamble,
+            name: target,
+        } = &dname
+        else {
+            return ZoneAnswer::NoData;
+        };
+
+        // keep the labels of the query name that lie below the DNAME owner
+        let name_labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+        let prefix = name_labels[..name_labels.len() - labels(&preamble.name).len()].join(".");
+        let alias = if target.is_empty() {
+            prefix
+        } else {
+            format!("{}.{}", prefix, target)
+        };
+
+        if alias.len() > MAX_NAME_LEN {
+            return ZoneAnswer::YxDomain;
+        }
+
+        let cname = Record::Cname {
+            preamble: RecordPreamble::new(name, QueryType::Cname, preamble.ttl),
+            name: alias,
+        };
+
+        ZoneAnswer::Answer(vec![dname, cname])
+    }
+
+    /// Answer from the records of a matched node, renaming them when synthesized from a wildcard
+    fn answer_node(
+        &self,
+        node: &ZoneNode,
+        query_type: &QueryType,
+        owner: Option<&str>,
+    ) -> ZoneAnswer {
+        let mut records = node.records_of(query_type);
+        // a name with a CNAME has no other data, so the alias answers every type
+        if records.is_empty() {
+            records = node.records_of(&QueryType::Cname);
+        }
+
+        if records.is_empty() {
+            return ZoneAnswer::NoData;
+        }
+
+        if let Some(owner) = owner {
+            for rec in &mut records {
+                rec.preamble_mut().name = String::from(owner);
+            }
+        }
+
+        ZoneAnswer::Answer(records)
+    }
+
+    /// Refer the client to the name servers of a delegated child zone
+    fn referral(&self, cut: &ZoneNode) -> ZoneAnswer {
+        let ns = cut.records_of(&QueryType::NS);
+
+        // addresses for name servers inside the zone are needed to reach them
+        let glue = ns
+            .iter()
+            .filter_map(|rec| match rec {
+                Record::NS { name, .. } => self.find_node(name),
+                _ => None,
+            })
+            .flat_map(|node| {
+                let mut addrs = node.records_of(&QueryType::A);
+                addrs.extend(node.records_of(&QueryType::Aaaa));
+                addrs
+            })
+            .collect();
+
+        ZoneAnswer::Referral { ns, glue }
+    }
+
+    /// Fill the response with the authoritative answer to the question
+    ///
+    /// CNAMEs, including those synthesized from wildcards and DNAMEs, are followed while they
+    /// stay inside the zone, and the response code reflects the last name in the chain.
+    pub fn answer(&self, que: &Question, res: &mut DNSPacket) {
+        res.header.aa = true;
+
+        let mut name = que.name.clone();
+        let mut seen = vec![labels(&name)];
+        for _ in 0..MAX_CHAIN {
+            match self.lookup(&name, &que.query_type) {
+                ZoneAnswer::Answer(records) => {
+                    let alias = match records.last() {
+                        Some(Record::Cname { name: alias, .. })
+                            if que.query_type != QueryType::Cname =>
+                        {
+                            Some(alias.clone())
+                        }
+                        _ => None,
+                    };
+                    res.answer_sec.extend(records);
+
+                    // stop at the edge of the zone or when the chain loops back on itself
+                    match alias {
+                        Some(alias)
+                            if is_in_zone(&alias, &self.origin)
+                                && !seen.contains(&labels(&alias)) =>
+                        {
+                            seen.push(labels(&alias));
+                            name = alias;
+                        }
+                        _ => return,
+                    }
+                }
+
+                // referrals are not authoritative unless part of the answer is
+                ZoneAnswer::Referral { ns, glue } => {
+                    res.header.aa = !res.answer_sec.is_empty();
+                    res.authority_sec.extend(ns);
+                    res.additional_sec.extend(glue);
+                    return;
+                }
+
+                ZoneAnswer::NoData => {
+                    res.authority_sec.extend(self.negative_soa());
+                    return;
+                }
+
+                ZoneAnswer::NxDomain => {
+                    res.header.rcode = Nxdomain;
+                    res.authority_sec.extend(self.negative_soa());
+                    return;
+                }
+
+                ZoneAnswer::YxDomain => {
+                    res.header.rcode = Yxdomain;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Key a zone is stored under, its origin in lowercase without a trailing dot
+fn zone_key(origin: &str) -> String {
+    origin
+        .strip_suffix('.')
+        .unwrap_or(origin)
+        .to_ascii_lowercase()
+}
+
+#[derive(Debug, Default)]
+/// Every zone this server is authoritative for
+pub struct ZoneStore {
+    /// Zones keyed by their lowercase origin
+    zones: BTreeMap<String, Zone>,
+}
+
+impl ZoneStore {
+    /// Add a zone, replacing any previous zone with the same origin
+    pub fn insert(&mut self, zone: Zone) {
+        self.zones.insert(zone_key(&zone.origin), zone);
+    }
+
+    /// Zone with exactly the given origin
+    pub fn get(&self, origin: &str) -> Option<&Zone> {
+        self.zones.get(&zone_key(origin))
+    }
+
+    /// Mutable access to the zone with exactly the given origin
+    pub fn get_mut(&mut self, origin: &str) -> Option<&mut Zone> {
+        self.zones.get_mut(&zone_key(origin))
+    }
+
+    /// Replace a zone with a newer version of itself, journaling the changes
+    ///
+    /// A version that changes records without increasing the serial is refused and the previous
+    /// one kept, as secondaries could never pick it up.
+    pub fn replace(&mut self, mut zone: Zone) -> Result<()> {
+        let key = zone_key(&zone.origin);
+        if let Some(old) = self.zones.get_mut(&key) {
+            if let Err(e) = zone.follow(old) {
+                // the refused file is not reloaded again until it changes once more
+                if zone.source.is_some() {
+                    old.source = zone.source;
+                }
+                return Err(e);
+            }
+        }
+        self.zones.insert(key, zone);
+
+        Ok(())
+    }
+
+    /// Origins and files of zones whose master file changed since it was loaded
+    pub fn stale_sources(&self) -> Vec<(String, PathBuf)> {
+        self.zones
+            .values()
+            .filter_map(|zone| {
+                let source = zone.source.as_ref()?;
+                let modified = fs::metadata(&source.path).and_then(|meta| meta.modified());
+                match modified {
+                    Ok(modified) if Some(modified) != source.modified => {
+                        Some((zone.origin.clone(), source.path.clone()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// The most specific zone containing the given name
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        self.zones
+            .values()
+            .filter(|zone| is_in_zone(name, &zone.origin))
+            .max_by_key(|zone| labels(&zone.origin).len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{header::ResponseCode::Noerror, zone_file::parse_record};
+
+    fn rec(text: &str) -> Record {
+        parse_record(text, 3600).unwrap()
+    }
+
+    fn zone() -> Zone {
+        let records = [
+            "example.com. 3600 IN SOA ns.example.com. hostmaster.example.com. 1 7200 900 1209600 300",
+            "example.com. 3600 IN NS ns.example.com.",
+            "ns.example.com. 3600 IN A 192.0.2.1",
+            "*.example.com. 3600 IN A 192.0.2.2",
+            "a.b.example.com. 3600 IN A 192.0.2.3",
+            "www.example.com. 3600 IN CNAME web.example.com.",
+            "web.example.com. 3600 IN CNAME host.example.com.",
+            "host.example.com. 3600 IN A 192.0.2.4",
+            "old.example.com. 3600 IN DNAME new.example.com.",
+            "mail.new.example.com. 3600 IN A 192.0.2.5",
+        ];
+
+        Zone::new("example.com", records.into_iter().map(rec).collect()).unwrap()
+    }
+
+    fn answer(name: &str, query_type: QueryType) -> DNSPacket {
+        let mut que = Question::new();
+        que.name = String::from(name);
+        que.query_type = query_type;
+
+        let mut res = DNSPacket::new();
+        zone().answer(&que, &mut res);
+        res
+    }
+
+    fn negative_soa() -> Vec<Record> {
+        vec![rec(
+            "example.com. 300 IN SOA ns.example.com. hostmaster.example.com. 1 7200 900 1209600 300",
+        )]
+    }
+
+    #[test]
+    fn wildcard_answers_with_query_name() {
+        let res = answer("nothere.example.com", QueryType::A);
+
+        assert!(res.header.aa);
+        assert_eq!(res.header.rcode, Noerror);
+        assert_eq!(
+            res.answer_sec,
+            vec![rec("nothere.example.com. 3600 IN A 192.0.2.2")]
+        );
+        assert!(res.authority_sec.is_empty());
+    }
+
+    #[test]
+    fn wildcard_nodata_has_soa() {
+        let res = answer("nothere.example.com", QueryType::Aaaa);
+
+        assert_eq!(res.header.rcode, Noerror);
+        assert!(res.answer_sec.is_empty());
+        assert_eq!(res.authority_sec, negative_soa());
+    }
+
+    #[test]
+    fn wildcard_only_below_closest_encloser() {
+        // b.example.com exists, so *.example.com does not cover names below it
+        let res = answer("x.b.example.com", QueryType::A);
+
+        assert!(res.header.aa);
+        assert_eq!(res.header.rcode, Nxdomain);
+        assert!(res.answer_sec.is_empty());
+        assert_eq!(res.authority_sec, negative_soa());
+    }
+
+    #[test]
+    fn empty_non_terminal_is_nodata() {
+        let res = answer("b.example.com", QueryType::A);
+
+        assert_eq!(res.header.rcode, Noerror);
+        assert!(res.answer_sec.is_empty());
+        assert_eq!(res.authority_sec, negative_soa());
+        assert!(res.additional_sec.is_empty());
+    }
+
+    #[test]
+    fn cname_chain_is_followed() {
+        let res = answer("WWW.example.com", QueryType::A);
+
+        assert!(res.header.aa);
+        assert_eq!(res.header.rcode, Noerror);
+        assert_eq!(
+            res.answer_sec,
+            vec![
+                rec("www.example.com. 3600 IN CNAME web.example.com."),
+                rec("web.example.com. 3600 IN CNAME host.example.com."),
+                rec("host.example.com. 3600 IN A 192.0.2.4"),
+            ]
+        );
+        assert!(res.authority_sec.is_empty());
+    }
+
+    #[test]
+    fn cname_query_is_not_followed() {
+        let res = answer("www.example.com", QueryType::Cname);
+
+        assert_eq!(
+            res.answer_sec,
+            vec![rec("www.example.com. 3600 IN CNAME web.example.com.")]
+        );
+    }
+
+    #[test]
+    fn dname_synthesizes_cname() {
+        let res = answer("mail.old.example.com", QueryType::A);
+
+        assert!(res.header.aa);
+        assert_eq!(res.header.rcode, Noerror);
+        assert_eq!(
+            res.answer_sec,
+            vec![
+                rec("old.example.com. 3600 IN DNAME new.example.com."),
+                rec("mail.old.example.com. 3600 IN CNAME mail.new.example.com."),
+                rec("mail.new.example.com. 3600 IN A 192.0.2.5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dname_owner_is_not_redirected() {
+        let res = answer("old.example.com", QueryType::A);
+
+        assert_eq!(res.header.rcode, Noerror);
+        assert!(res.answer_sec.is_empty());
+        assert_eq!(res.authority_sec, negative_soa());
+    }
+
+    #[test]
+    fn dname_target_missing_is_nxdomain() {
+        let res = answer("www.old.example.com", QueryType::A);
+
+        assert_eq!(res.header.rcode, Nxdomain);
+        assert_eq!(
+            res.answer_sec,
+            vec![
+                rec("old.example.com. 3600 IN DNAME new.example.com."),
+                rec("www.old.example.com. 3600 IN CNAME www.new.example.com."),
+            ]
+        );
+        assert_eq!(res.authority_sec, negative_soa());
+    }
+
+    #[test]
+    fn replace_journals_changes() {
+        let mut zones = ZoneStore::default();
+        zones.insert(zone());
+
+        let mut records = zone().records();
+        records[0] = rec(
+            "example.com. 3600 IN SOA ns.example.com. hostmaster.example.com. 2 7200 900 1209600 300",
+        );
+        records.push(rec("fresh.example.com. 3600 IN A 192.0.2.6"));
+        zones
+            .replace(Zone::new("example.com", records).unwrap())
+            .unwrap();
+
+        let zone = zones.get("example.com").unwrap();
+        assert_eq!(zone.serial(), 2);
+        assert_eq!(zone.journal.len(), 1);
+        assert!(zone.journal[0].deleted.is_empty());
+        assert_eq!(
+            zone.journal[0].added,
+            vec![rec("fresh.example.com. 3600 IN A 192.0.2.6")]
+        );
+    }
+
+    #[test]
+    fn replace_refuses_change_without_new_serial() {
+        let mut zones = ZoneStore::default();
+        zones.insert(zone());
+
+        let mut records = zone().records();
+        records.push(rec("fresh.example.com. 3600 IN A 192.0.2.6"));
+        assert!(zones
+            .replace(Zone::new("example.com", records).unwrap())
+            .is_err());
+
+        let zone = zones.get("example.com").unwrap();
+        assert!(zone.find_node("fresh.example.com").is_none());
+        assert!(zone.journal.is_empty());
+
+        // the same records again are not a change
+        assert!(zones.replace(self::zone()).is_ok());
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use super::{
+    errors::{
+        Errors::{IOErr, ZoneFile},
+        Result,
+    },
+    question::QueryType,
+    record::{Record, RecordPreamble},
+};
+
+const MAX_INCLUDE_DEPTH: usize = 8; // prevent $INCLUDE cycles
+
+/// A single word of a master file entry
+struct Token {
+    text: String,
+    /// The word as it goes on the wire, escapes of values over 127 stay single bytes
+    bytes: Vec<u8>,
+    /// If the word was written as a quoted string
+    quoted: bool,
+}
+
+/// One logical entry of a master file, which may span lines inside parentheses
+struct Entry {
+    /// Line the entry starts on
+    line: usize,
+    /// If the entry starts with blank space, meaning it reuses the previous owner
+    blank_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Parser state carried from one entry to the next
+struct ZoneFileParser {
+    /// Origin appended to relative names
+    origin: String,
+    /// TTL set by the $TTL directive
+    default_ttl: Option<u32>,
+    /// Owner of the previous record, used when the owner field is left blank
+    last_owner: Option<String>,
+    /// TTL of the previous record, used when no $TTL has been seen
+    last_ttl: Option<u32>,
+    records: Vec<Record>,
+}
+
+/// Parse an RFC 1035 master file into the records it contains
+pub fn load_zone_file(path: &Path, origin: &str) -> Result<Vec<Record>> {
+    let mut parser = ZoneFileParser {
+        origin: normalize_origin(origin),
+        default_ttl: None,
+        last_owner: None,
+        last_ttl: None,
+        records: Vec::new(),
+    };
+    parser.parse_file(path, 0)?;
+
+    Ok(parser.records)
+}
+
+/// Parse one record written as a master file entry, every name taken as absolute
+pub fn parse_record(text: &str, default_ttl: u32) -> std::result::Result<Record, String> {
+    let mut parser = ZoneFileParser {
+        origin: String::new(),
+        default_ttl: Some(default_ttl),
+        last_owner: None,
+        last_ttl: None,
+        records: Vec::new(),
+    };
+
+    let entries = tokenize(text).map_err(|(_, msg)| msg)?;
+    let [entry] = entries.as_slice() else {
+        return Err(String::from("expected a single record"));
+    };
+    parser.parse_entry(entry)?;
+
+    parser
+        .records
+        .pop()
+        .ok_or_else(|| String::from("expected a record"))
+}
+
+/// Strip the trailing dot of an absolute name, the root becomes the empty string
+fn normalize_origin(origin: &str) -> String {
+    String::from(origin.strip_suffix('.').unwrap_or(origin))
+}
+
+/// Parse a TTL written either as seconds or with units, e.g. 1h30m or 1W
+pub fn parse_ttl(text: &str) -> Option<u32> {
+    if let Ok(secs) = text.parse() {
+        return Some(secs);
+    }
+
+    let mut total: u32 = 0;
+    let mut num: Option<u32> = None;
+    for c in text.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            num = Some(num.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(num.take()?.checked_mul(unit)?)?;
+    }
+
+    // a trailing number without a unit is seconds
+    total
+        .checked_add(num.unwrap_or(0))
+        .filter(|_| !text.is_empty())
+}
+
+/// Append the UTF-8 encoding of the character
+fn push_char(bytes: &mut Vec<u8>, c: char) {
+    bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+}
+
+/// Split the text of a master file into entries
+fn tokenize(text: &str) -> std::result::Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut line = 1;
+    let mut depth = 0; // open parentheses
+    let mut entry: Option<Entry> = None;
+    let mut at_line_start = true;
+
+    while let Some(c) = chars.next() {
+        let starts_blank = at_line_start && depth == 0;
+        at_line_start = false;
+
+        match c {
+            '\n' => {
+                line += 1;
+                at_line_start = true;
+                if depth == 0 {
+                    entries.extend(entry.take().filter(|e| !e.tokens.is_empty()));
+                }
+            }
+
+            ' ' | '\t' | '\r' => {
+                if starts_blank && entry.is_none() {
+                    entry = Some(Entry {
+                        line,
+                        blank_owner: true,
+                        tokens: Vec::new(),
+                    });
+                }
+            }
+
+            ';' => {
+                // comment runs to the end of the line
+                while chars.peek().is_some_and(|&next| next != '\n') {
+                    chars.next();
+                }
+            }
+
+            '(' => depth += 1,
+
+            ')' => {
+                if depth == 0 {
+                    return Err((line, String::from("unbalanced parenthesis")));
+                }
+                depth -= 1;
+            }
+
+            _ => {
+                let entry = entry.get_or_insert(Entry {
+                    line,
+                    blank_owner: false,
+                    tokens: Vec::new(),
+                });
+
+                let quoted = c == '"';
+                let mut word = String::new();
+                let mut bytes = Vec::new();
+                let mut pending = if quoted { None } else { Some(c) };
+                loop {
+                    let c = match pending.take().or_else(|| chars.next()) {
+                        Some(c) => c,
+                        None if quoted => {
+                            return Err((entry.line, String::from("unterminated string")))
+                        }
+                        None => break,
+                    };
+
+                    match c {
+                        '\\' => match chars.next() {
+                            Some(d) if d.is_ascii_digit() => {
+                                // \DDD is a decimal byte value
+                                let mut code = d.to_digit(10).unwrap_or(0);
+                                for _ in 0..2 {
+                                    match chars.next().and_then(|d| d.to_digit(10)) {
+                                        Some(d) => code = code * 10 + d,
+                                        None => return Err((line, String::from("invalid escape"))),
+                                    }
+                                }
+                                let Ok(byte) = u8::try_from(code) else {
+                                    return Err((line, String::from("escaped value over 255")));
+                                };
+                                word.push(char::from(byte));
+                                bytes.push(byte);
+                            }
+                            Some(escaped) => {
+                                word.push(escaped);
+                                push_char(&mut bytes, escaped);
+                            }
+                            None => return Err((line, String::from("invalid escape"))),
+                        },
+                        '"' if quoted => break,
+                        '\n' if quoted => {
+                            line += 1;
+                            word.push(c);
+                            push_char(&mut bytes, c);
+                        }
+                        _ => {
+                            word.push(c);
+                            push_char(&mut bytes, c);
+                        }
+                    }
+
+                    if !quoted
+                        && chars
+                            .peek()
+                            .is_none_or(|&next| " \t\r\n;()\"".contains(next))
+                    {
+                        break;
+                    }
+                }
+
+                entry.tokens.push(Token {
+                    text: word,
+                    bytes,
+                    quoted,
+                });
+            }
+        }
+    }
+
+    if depth != 0 {
+        return Err((line, String::from("unbalanced parenthesis")));
+    }
+    entries.extend(entry.take().filter(|e| !e.tokens.is_empty()));
+
+    Ok(entries)
+}
+
+impl ZoneFileParser {
+    /// Parse every entry of the given file, following $INCLUDE directives
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<()> {
+        if depth > MAX_INCLUDE_DEPTH {
+            return Err(ZoneFile(format!(
+                "{}: too many nested includes",
+                path.display()
+            )));
+        }
+
+        let text = fs::read_to_string(path).map_err(IOErr)?;
+        let entries = tokenize(&text)
+            .map_err(|(line, msg)| ZoneFile(format!("{}:{}: {}", path.display(), line, msg)))?;
+
+        for entry in entries {
+            let located =
+                |msg: String| ZoneFile(format!("{}:{}: {}", path.display(), entry.line, msg));
+
+            if !entry.blank_owner && entry.tokens[0].text.eq_ignore_ascii_case("$INCLUDE") {
+                let file = entry
+                    .tokens
+                    .get(1)
+                    .ok_or_else(|| located(String::from("missing file to include")))?;
+                let include = path.parent().unwrap_or(Path::new("")).join(&file.text);
+
+                // an origin given to $INCLUDE only applies inside the included file
+                let saved_origin = self.origin.clone();
+                if let Some(origin) = entry.tokens.get(2) {
+                    self.origin = self.absolute_name(&origin.text);
+                }
+                let result = self.parse_file(&include, depth + 1);
+                self.origin = saved_origin;
+                result?;
+
+                continue;
+            }
+
+            self.parse_entry(&entry).map_err(located)?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse a directive or resource record entry
+    fn parse_entry(&mut self, entry: &Entry) -> std::result::Result<(), String> {
+        let mut tokens = entry.tokens.iter();
+
+        if !entry.blank_owner && entry.tokens[0].text.starts_with('$') {
+            let directive = tokens.next().map(|t| t.text.to_ascii_uppercase());
+            let arg = tokens.next().ok_or("missing directive argument")?;
+
+            match directive.as_deref() {
+                Some("$ORIGIN") => self.origin = self.absolute_name(&arg.text),
+                Some("$TTL") => {
+                    self.default_ttl = Some(parse_ttl(&arg.text).ok_or("invalid TTL")?);
+                }
+                _ => return Err(format!("unknown directive {}", entry.tokens[0].text)),
+            }
+
+            return Ok(());
+        }
+
+        let owner = if entry.blank_owner {
+            self.last_owner.clone().ok_or("no previous owner name")?
+        } else {
+            self.absolute_name(&tokens.next().ok_or("missing owner")?.text)
+        };
+
+        // TTL and class may come in either order and are both optional
+        let mut ttl = None;
+        let mut query_type = None;
+        for token in tokens.by_ref().take(3) {
+            let text = token.text.to_ascii_uppercase();
+            if ttl.is_none() && text.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&text).ok_or("invalid TTL")?);
+            } else if text == "IN" {
+                continue;
+            } else if matches!(text.as_str(), "CH" | "HS" | "CS") {
+                return Err(format!("unsupported class {}", text));
+            } else {
+                query_type = Some(QueryType::from_name(&text).ok_or("unknown record type")?);
+                break;
+            }
+        }
+        let query_type = query_type.ok_or("missing record type")?;
+        let rdata: Vec<&Token> = tokens.collect();
+
+        let ttl = match ttl {
+            Some(ttl) => {
+                self.last_ttl = Some(ttl);
+                Some(ttl)
+            }
+            None => self.default_ttl.or(self.last_ttl),
+        };
+
+        let record = self.parse_rdata(&owner, query_type, ttl, &rdata)?;
+        self.last_owner = Some(owner);
+        self.records.push(record);
+
+        Ok(())
+    }
+
+    /// Build a record from its type specific fields
+    fn parse_rdata(
+        &self,
+        owner: &str,
+        query_type: QueryType,
+        ttl: Option<u32>,
+        rdata: &[&Token],
+    ) -> std::result::Result<Record, String> {
+        let field = |i: usize| -> std::result::Result<&str, String> {
+            rdata
+                .get(i)
+                .map(|t| t.text.as_str())
+                .ok_or_else(|| String::from("missing record data"))
+        };
+        let num = |i: usize| -> std::result::Result<u32, String> {
+            parse_ttl(field(i)?).ok_or_else(|| format!("invalid number {}", rdata[i].text))
+        };
+
+        // SOA records can fall back on their own minimum field for a TTL
+        let ttl = match (ttl, &query_type) {
+            (Some(ttl), _) => ttl,
+            (None, QueryType::Soa) => num(6)?,
+            (None, _) => return Err(String::from("no TTL and no $TTL directive")),
+        };
+        let preamble = RecordPreamble::new(owner, query_type.clone(), ttl);
+
+        // RFC 3597 generic form works for every type
+        if rdata.first().is_some_and(|t| t.text == "#" && !t.quoted) {
+            let len: usize = field(1)?.parse().map_err(|_| "invalid data length")?;
+            let hex: String = rdata[2..].iter().map(|t| t.text.as_str()).collect();
+            let data = (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or("x"), 16))
+                .collect::<std::result::Result<Vec<u8>, _>>()
+                .map_err(|_| "invalid hex data")?;
+            if data.len() != len {
+                return Err(String::from("data length does not match"));
+            }
+
+            return Ok(Record::Unknown { preamble, data });
+        }
+
+        let record = match query_type {
+            QueryType::A => Record::A {
+                preamble,
+                ip: field(0)?
+                    .parse::<Ipv4Addr>()
+                    .map_err(|_| "invalid IPv4 address")?,
+            },
+            QueryType::Aaaa => Record::Aaaa {
+                preamble,
+                ip: field(0)?
+                    .parse::<Ipv6Addr>()
+                    .map_err(|_| "invalid IPv6 address")?,
+            },
+            QueryType::NS => Record::NS {
+                preamble,
+                name: self.absolute_name(field(0)?),
+            },
+            QueryType::Cname => Record::Cname {
+                preamble,
+                name: self.absolute_name(field(0)?),
+            },
+            QueryType::Dname => Record::Dname {
+                preamble,
+                name: self.absolute_name(field(0)?),
+            },
+            QueryType::Ptr => Record::Ptr {
+                preamble,
+                name: self.absolute_name(field(0)?),
+            },
+            QueryType::MX => Record::MX {
+                preamble,
+                priority: field(0)?.parse().map_err(|_| "invalid preference")?,
+                name: self.absolute_name(field(1)?),
+            },
+            QueryType::Soa => Record::Soa {
+                preamble,
+                mname: self.absolute_name(field(0)?),
+                rname: self.absolute_name(field(1)?),
+                serial: field(2)?.parse().map_err(|_| "invalid serial")?,
+                refresh: num(3)?,
+                retry: num(4)?,
+                expire: num(5)?,
+                minimum: num(6)?,
+            },
+            QueryType::Txt => {
+                if rdata.is_empty() {
+                    return Err(String::from("missing record data"));
+                }
+                if rdata.iter().any(|t| t.bytes.len() > 255) {
+                    return Err(String::from("text exceeds 255 bytes"));
+                }
+
+                Record::Txt {
+                    preamble,
+                    data: rdata.iter().map(|t| t.bytes.clone()).collect(),
+                }
+            }
+            QueryType::Unknown(_)
+            | QueryType::Opt
+            | QueryType::Rrsig
+            | QueryType::Nsec
+            | QueryType::Nsec3
+            | QueryType::Tsig
+            | QueryType::Ixfr
+            | QueryType::Axfr => {
+                return Err(String::from("unknown types need the generic \\# form"))
+            }
+        };
+
+        Ok(record)
+    }
+
+    /// Resolve @ and relative names against the current origin
+    fn absolute_name(&self, name: &str) -> String {
+        if name == "@" {
+            self.origin.clone()
+        } else if let Some(name) = name.strip_suffix('.') {
+            String::from(name)
+        } else if self.origin.is_empty() {
+            String::from(name)
+        } else {
+            format!("{}.{}", name, self.origin)
+        }
+    }
+}